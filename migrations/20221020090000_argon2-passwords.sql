@@ -0,0 +1,5 @@
+ALTER TABLE users ALTER COLUMN password TYPE VARCHAR(255);
+
+-- Mark the unsalted SHA-256 digests so they can be told apart from Argon2 PHC strings. They are
+-- upgraded on the next successful login.
+UPDATE users SET password = 'sha256$' || password WHERE password NOT LIKE '$%';
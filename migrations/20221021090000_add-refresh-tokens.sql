@@ -0,0 +1,9 @@
+CREATE TABLE refresh_tokens (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER REFERENCES users(id) ON DELETE CASCADE NOT NULL,
+    token_hash VARCHAR(64) UNIQUE NOT NULL,
+    expires TIMESTAMP NOT NULL,
+    revoked BOOLEAN NOT NULL DEFAULT false,
+    created TIMESTAMP NOT NULL
+);
+CREATE INDEX refresh_tokens_user_id ON refresh_tokens(user_id);
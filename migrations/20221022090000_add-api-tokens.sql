@@ -0,0 +1,11 @@
+CREATE TABLE api_tokens (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER REFERENCES users(id) ON DELETE CASCADE NOT NULL,
+    name VARCHAR(100) NOT NULL,
+    token_hash VARCHAR(64) UNIQUE NOT NULL,
+    scopes VARCHAR[] NOT NULL,
+    expires TIMESTAMP,
+    last_used TIMESTAMP,
+    created TIMESTAMP NOT NULL
+);
+CREATE INDEX api_tokens_user_id ON api_tokens(user_id);
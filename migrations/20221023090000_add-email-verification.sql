@@ -0,0 +1,15 @@
+ALTER TABLE users ADD COLUMN email_verified BOOLEAN NOT NULL DEFAULT false;
+
+-- Accounts created before the verification flow are trusted
+UPDATE users SET email_verified = true;
+
+CREATE TABLE user_tokens (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER REFERENCES users(id) ON DELETE CASCADE NOT NULL,
+    kind VARCHAR(32) NOT NULL,
+    token_hash VARCHAR(64) UNIQUE NOT NULL,
+    expires TIMESTAMP NOT NULL,
+    used BOOLEAN NOT NULL DEFAULT false,
+    created TIMESTAMP NOT NULL
+);
+CREATE INDEX user_tokens_user_id ON user_tokens(user_id);
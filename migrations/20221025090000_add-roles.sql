@@ -0,0 +1,5 @@
+CREATE TYPE user_role AS ENUM ('user', 'moderator', 'admin');
+
+ALTER TABLE users ADD COLUMN role user_role NOT NULL DEFAULT 'user';
+UPDATE users SET role = 'admin' WHERE is_staff = true;
+ALTER TABLE users DROP COLUMN is_staff;
@@ -0,0 +1,9 @@
+CREATE TABLE login_attempts (
+    id SERIAL PRIMARY KEY,
+    username VARCHAR(100) NOT NULL,
+    ip VARCHAR(64) NOT NULL,
+    success BOOLEAN NOT NULL,
+    created TIMESTAMP NOT NULL
+);
+CREATE INDEX login_attempts_username_created ON login_attempts(username, created);
+CREATE INDEX login_attempts_ip_created ON login_attempts(ip, created);
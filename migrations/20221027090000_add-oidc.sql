@@ -0,0 +1,16 @@
+CREATE TABLE oidc_states (
+    state VARCHAR(64) PRIMARY KEY,
+    code_verifier VARCHAR(128) NOT NULL,
+    nonce VARCHAR(64) NOT NULL,
+    redirect_to VARCHAR,
+    created TIMESTAMP NOT NULL
+);
+
+CREATE TABLE user_identities (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER REFERENCES users(id) ON DELETE CASCADE NOT NULL,
+    issuer VARCHAR NOT NULL,
+    subject VARCHAR NOT NULL,
+    created TIMESTAMP NOT NULL,
+    UNIQUE (issuer, subject)
+);
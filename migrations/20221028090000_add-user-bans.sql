@@ -0,0 +1,14 @@
+CREATE TYPE user_ban_kind AS ENUM ('suspended', 'banned');
+
+CREATE TABLE user_bans (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER REFERENCES users(id) ON DELETE CASCADE NOT NULL,
+    kind user_ban_kind NOT NULL,
+    reason TEXT NOT NULL,
+    expires TIMESTAMP,
+    created_by INTEGER REFERENCES users(id) ON DELETE SET NULL,
+    created TIMESTAMP NOT NULL,
+    lifted_by INTEGER REFERENCES users(id) ON DELETE SET NULL,
+    lifted TIMESTAMP
+);
+CREATE INDEX user_bans_user_id ON user_bans(user_id);
@@ -0,0 +1,9 @@
+CREATE TABLE upload_derivatives (
+    id SERIAL PRIMARY KEY,
+    source VARCHAR NOT NULL,
+    size INTEGER NOT NULL,
+    format VARCHAR(16) NOT NULL,
+    filepath VARCHAR NOT NULL,
+    created TIMESTAMP NOT NULL,
+    UNIQUE (source, size, format)
+);
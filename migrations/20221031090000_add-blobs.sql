@@ -0,0 +1,11 @@
+CREATE TABLE blobs (
+    key VARCHAR PRIMARY KEY,
+    hash VARCHAR(64) NOT NULL,
+    size BIGINT NOT NULL,
+    refcount INTEGER NOT NULL,
+    created TIMESTAMP NOT NULL
+);
+CREATE INDEX blobs_hash ON blobs(hash);
+
+ALTER TABLE uploads ADD COLUMN hash VARCHAR(64);
+CREATE INDEX uploads_hash ON uploads(hash);
@@ -0,0 +1,2 @@
+ALTER TABLE uploads ADD COLUMN filename VARCHAR(255);
+ALTER TABLE pending_uploads ADD COLUMN filename VARCHAR(255);
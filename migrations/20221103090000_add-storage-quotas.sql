@@ -0,0 +1,4 @@
+ALTER TABLE users ADD COLUMN storage_quota BIGINT;
+
+ALTER TABLE uploads ADD COLUMN size BIGINT NOT NULL DEFAULT 0;
+UPDATE uploads SET size = blobs.size FROM blobs WHERE uploads.filepath LIKE '%/' || blobs.key;
@@ -0,0 +1 @@
+ALTER TABLE models ADD COLUMN downloads INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1 @@
+ALTER TABLE uploads ADD COLUMN print_info JSONB;
@@ -0,0 +1,2 @@
+ALTER TABLE pending_uploads ADD COLUMN lock_token VARCHAR(32);
+ALTER TABLE pending_uploads ADD COLUMN locked_until TIMESTAMP;
@@ -0,0 +1,3 @@
+ALTER TABLE blobs ADD COLUMN updated TIMESTAMP;
+UPDATE blobs SET updated = created;
+ALTER TABLE blobs ALTER COLUMN updated SET NOT NULL;
@@ -1,2 +1,6 @@
 pub mod models;
+pub mod oidc;
+pub mod password;
+pub mod permissions;
 pub mod routes;
+pub mod totp;
@@ -1,15 +1,21 @@
-use crate::errors::AppError;
+use crate::{
+    auth::totp,
+    errors::AppError,
+    state::AppState,
+    user::models::{BanKind, UserBan},
+};
 use axum::{
     async_trait,
-    extract::{FromRequest, RequestParts, TypedHeader},
+    extract::{Extension, FromRequest, RequestParts, TypedHeader},
     headers::{authorization::Bearer, Authorization},
 };
-use chrono::{Duration, Local};
+use chrono::{Duration, Local, NaiveDateTime};
 use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
-use once_cell::sync::Lazy;
+use rand::{distributions::Alphanumeric, Rng};
 use serde::{Deserialize, Serialize};
 
-struct Keys {
+/// Keys used to encode and decode tokens, built from the JWT secret
+pub struct Keys {
     encoding: EncodingKey,
     decoding: DecodingKey,
 }
@@ -19,17 +25,176 @@ struct Keys {
 pub struct Claims {
     /// ID from the user model
     pub user_id: i32,
+    /// ID of the refresh token which issued this access token. When it is revoked or rotated the
+    /// access token is no longer valid. It is `None` for personal access tokens
+    pub session_id: Option<i32>,
     /// Expiration timestamp
     exp: usize,
+    /// Scopes granted to a personal access token. JWTs have no restrictions
+    #[serde(skip)]
+    scopes: Option<Vec<Scope>>,
+}
+
+/// Claims of a token issued after a right password for an user who enabled the two-factor
+/// authentication. It can only be exchanged for a session together with a valid code
+#[derive(Serialize, Deserialize)]
+pub struct MfaClaims {
+    pub user_id: i32,
+    /// Always "mfa", so it can't be mistaken for an access token
+    aud: String,
+    exp: usize,
+}
+
+/// Two-factor authentication settings of an user
+#[derive(sqlx::FromRow)]
+pub struct Totp {
+    pub id: i32,
+    pub email: String,
+    pub totp_secret: Option<String>,
+    pub totp_enabled: bool,
+    pub totp_last_step: Option<i64>,
+}
+
+/// One-time codes usable instead of a TOTP code. Only the SHA256 digest of a code is stored
+pub struct RecoveryCode;
+
+/// Model for login attempts, used to lock out brute-force attacks and reviewed by staffers
+#[derive(Serialize, sqlx::FromRow)]
+pub struct LoginAttempt {
+    pub id: i32,
+    pub username: String,
+    pub ip: String,
+    pub success: bool,
+    pub created: NaiveDateTime,
+}
+
+/// Payload used for login attempts filtering
+#[derive(Deserialize)]
+pub struct LoginAttemptFilter {
+    pub username: Option<String>,
+    pub ip: Option<String>,
+    pub success: Option<bool>,
+}
+
+/// Actions a personal access token can be allowed to perform
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Read private resources, such as the users list or the warnings
+    Read,
+    /// Create, edit and delete resources. It includes `Upload`
+    Write,
+    /// Upload files to models
+    Upload,
+}
+
+/// Model for personal access tokens, used by scripts. Only the SHA256 digest of a token is stored
+#[derive(Serialize, sqlx::FromRow)]
+pub struct ApiToken {
+    pub id: i32,
+    pub user_id: i32,
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub expires: Option<NaiveDateTime>,
+    pub last_used: Option<NaiveDateTime>,
+    pub created: NaiveDateTime,
+}
+
+/// Payload used to create a personal access token
+#[derive(Deserialize)]
+pub struct ApiTokenCreate {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    /// Number of days after that the token expires. It never expires if it is `None`
+    pub expires_in_days: Option<i64>,
+}
+
+/// Response for a personal access token creation. It is the only time the raw token is shown
+#[derive(Serialize)]
+pub struct ApiTokenCreated {
+    #[serde(flatten)]
+    pub api_token: ApiToken,
+    pub token: String,
+}
+
+/// Model for refresh tokens. Only the SHA256 digest of a token is stored
+#[derive(sqlx::FromRow)]
+pub struct RefreshToken {
+    pub id: i32,
+    pub user_id: i32,
+    expires: NaiveDateTime,
+    revoked: bool,
+}
+
+/// What a `UserToken` can be used for
+#[derive(Clone, Copy)]
+pub enum UserTokenKind {
+    EmailVerification,
+    PasswordReset,
 }
 
+/// Single-use expiring token sent by email to an user. Only the SHA256 digest is stored
+pub struct UserToken;
+
 /// Body used as response to login
 #[derive(Serialize)]
 pub struct AuthBody {
     /// Access token string
     access_token: String,
+    /// Refresh token string, used to get a new access token
+    refresh_token: String,
     /// "Bearer" string
     token_type: String,
+    /// Lifetime of the access token, in seconds
+    expires_in: i64,
+}
+
+/// Response to a login: a session or, if the user enabled the two-factor authentication, a token
+/// to complete the login with a code
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum LoginResponse {
+    Authenticated(AuthBody),
+    MfaRequired(MfaPending),
+}
+
+/// Body used as response to a login which needs a second factor
+#[derive(Serialize)]
+pub struct MfaPending {
+    /// Always `true`
+    mfa_required: bool,
+    /// Token to pass to `/v1/auth/mfa/verify`
+    mfa_token: String,
+    /// Lifetime of the token, in seconds
+    expires_in: i64,
+}
+
+/// Response to the two-factor authentication setup
+#[derive(Serialize)]
+pub struct MfaSetup {
+    pub secret: String,
+    pub provisioning_uri: String,
+}
+
+/// Response with new recovery codes. They are shown only once
+#[derive(Serialize)]
+pub struct RecoveryCodes {
+    pub recovery_codes: Vec<String>,
+}
+
+/// Payload used to complete a login with a second factor
+#[derive(Deserialize)]
+pub struct MfaVerifyForm {
+    pub mfa_token: String,
+    /// A TOTP code or a recovery code
+    pub code: String,
+}
+
+/// Payload used to confirm an action on the two-factor authentication settings
+#[derive(Deserialize)]
+pub struct MfaCodeForm {
+    /// A TOTP code or a recovery code
+    pub code: String,
 }
 
 /// Payload used for login
@@ -49,13 +214,34 @@ pub struct SignUpForm {
     pub password2: String,
 }
 
-static KEYS: Lazy<Keys> = Lazy::new(|| {
-    let secret = &crate::config::CONFIG.jwt_secret;
-    Keys::new(secret.as_bytes())
-});
+/// Payload used to verify an email address
+#[derive(Deserialize)]
+pub struct VerifyEmailForm {
+    pub token: String,
+}
+
+/// Payload used to ask for a password reset
+#[derive(Deserialize)]
+pub struct ForgotPasswordForm {
+    pub email: String,
+}
+
+/// Payload used to reset a password
+#[derive(Deserialize)]
+pub struct ResetPasswordForm {
+    pub token: String,
+    pub password1: String,
+    pub password2: String,
+}
+
+/// Payload used to refresh an access token
+#[derive(Deserialize)]
+pub struct RefreshForm {
+    pub refresh_token: String,
+}
 
 impl Keys {
-    fn new(secret: &[u8]) -> Self {
+    pub fn new(secret: &[u8]) -> Self {
         Self {
             encoding: EncodingKey::from_secret(secret),
             decoding: DecodingKey::from_secret(secret),
@@ -64,35 +250,834 @@ impl Keys {
 }
 
 impl Claims {
-    /// Create a new Claim using the `user_id` and the current timestamp + 2 days
-    pub fn new(user_id: i32) -> Self {
-        let expiration = Local::now() + Duration::days(1);
+    /// Create a new Claim using the `user_id`, the `session_id` and the current timestamp +
+    /// `lifetime`
+    pub fn new(user_id: i32, session_id: i32, lifetime: Duration) -> Self {
+        let expiration = Local::now() + lifetime;
 
         Self {
             user_id,
+            session_id: Some(session_id),
             exp: expiration.timestamp() as usize,
+            scopes: None,
         }
     }
 
+    /// Raises an `AppError::Unauthorized` if the token has not been granted the `scope`
+    pub fn require(&self, scope: Scope) -> Result<(), AppError> {
+        match &self.scopes {
+            None => Ok(()),
+            Some(scopes) => {
+                let granted = scopes.contains(&scope)
+                    || (scope == Scope::Upload && scopes.contains(&Scope::Write));
+
+                if granted {
+                    Ok(())
+                } else {
+                    Err(AppError::Unauthorized)
+                }
+            }
+        }
+    }
+
+    /// Apply the active restriction of the user: a banned user is rejected, a suspended one can
+    /// only read
+    async fn restrict(mut self, state: &AppState) -> Result<Self, AppError> {
+        match UserBan::active(state, self.user_id).await? {
+            Some(ban) if ban.kind == BanKind::Banned => return Err(ban.error()),
+            Some(_) => {
+                let read = self
+                    .scopes
+                    .as_ref()
+                    .is_none_or(|scopes| scopes.contains(&Scope::Read));
+                self.scopes = Some(if read { vec![Scope::Read] } else { vec![] });
+            }
+            None => {}
+        }
+
+        Ok(self)
+    }
+
+    /// Returns the session id. Raises an `AppError::Unauthorized` for personal access tokens, which
+    /// can't be used to manage sessions and tokens
+    pub fn require_session(&self) -> Result<i32, AppError> {
+        self.session_id.ok_or(AppError::Unauthorized)
+    }
+
     /// Returns the token as a string. If a token is not encoded, raises an
     /// `AppError::TokenCreation`
-    pub fn get_token(&self) -> Result<String, AppError> {
-        let token = encode(&Header::default(), &self, &KEYS.encoding)
+    pub fn get_token(&self, keys: &Keys) -> Result<String, AppError> {
+        let token = encode(&Header::default(), &self, &keys.encoding)
             .map_err(|_| AppError::TokenCreation)?;
 
         Ok(token)
     }
 }
 
-impl AuthBody {
-    pub fn new(access_token: String) -> Self {
+impl RefreshToken {
+    /// Create a new refresh token for an user. Returns the saved model and the raw token, which
+    /// is shown only once
+    pub async fn create(
+        state: &AppState,
+        user_id: i32,
+    ) -> Result<(RefreshToken, String), AppError> {
+        let pool = &state.pool;
+
+        let token: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(64)
+            .map(char::from)
+            .collect();
+
+        let now = Local::now().naive_utc();
+        let expires = now + Duration::days(state.config.refresh_token_days);
+
+        let rec: RefreshToken = sqlx::query_as(
+            r#"
+                INSERT INTO refresh_tokens (user_id, token_hash, expires, created)
+                VALUES ( $1, $2, $3, $4)
+                RETURNING id, user_id, expires, revoked
+            "#,
+        )
+        .bind(user_id)
+        .bind(sha256::digest(token.as_str()))
+        .bind(expires)
+        .bind(now)
+        .fetch_one(pool)
+        .await?;
+
+        Ok((rec, token))
+    }
+
+    /// Rotate a refresh token: the passed one is revoked and a new one is created. If the token
+    /// has been already used, every session of its owner is revoked because it has been leaked.
+    /// Returns the new model and the new raw token
+    pub async fn rotate(state: &AppState, token: &str) -> Result<(RefreshToken, String), AppError> {
+        let pool = &state.pool;
+
+        let rec: RefreshToken = sqlx::query_as(
+            r#"
+                SELECT id, user_id, expires, revoked FROM refresh_tokens WHERE token_hash = $1
+            "#,
+        )
+        .bind(sha256::digest(token))
+        .fetch_optional(pool)
+        .await?
+        .ok_or(AppError::InvalidToken)?;
+
+        if rec.revoked {
+            RefreshToken::revoke_all(state, rec.user_id).await?;
+            return Err(AppError::InvalidToken);
+        }
+
+        if rec.expires < Local::now().naive_utc() {
+            return Err(AppError::InvalidToken);
+        }
+
+        // Revoke it only if no one else did it in the meantime
+        let result = sqlx::query(
+            r#"
+            UPDATE refresh_tokens SET revoked = true WHERE id = $1 AND revoked = false
+            "#,
+        )
+        .bind(rec.id)
+        .execute(pool)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(AppError::InvalidToken);
+        }
+
+        RefreshToken::create(state, rec.user_id).await
+    }
+
+    /// Returns `true` if the refresh token with id = `id` is not revoked nor expired
+    pub async fn is_active(state: &AppState, id: i32) -> Result<bool, AppError> {
+        let pool = &state.pool;
+
+        let rec: Option<(i32,)> = sqlx::query_as(
+            r#"
+                SELECT id FROM refresh_tokens WHERE id = $1 AND revoked = false AND expires > $2
+            "#,
+        )
+        .bind(id)
+        .bind(Local::now().naive_utc())
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(rec.is_some())
+    }
+
+    /// Revoke the refresh token with id = `id`
+    pub async fn revoke(state: &AppState, id: i32) -> Result<(), AppError> {
+        let pool = &state.pool;
+
+        sqlx::query(
+            r#"
+            UPDATE refresh_tokens SET revoked = true WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Revoke all the refresh tokens of an user, so log out every session
+    pub async fn revoke_all(state: &AppState, user_id: i32) -> Result<(), AppError> {
+        let pool = &state.pool;
+
+        sqlx::query(
+            r#"
+            UPDATE refresh_tokens SET revoked = true WHERE user_id = $1 AND revoked = false
+            "#,
+        )
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+impl MfaClaims {
+    const AUDIENCE: &'static str = "mfa";
+
+    /// Create a new token for `user_id` which expires in 5 minutes
+    pub fn new(user_id: i32) -> Self {
+        let expiration = Local::now() + Duration::minutes(5);
+
         Self {
-            access_token,
-            token_type: "Bearer".to_string(),
+            user_id,
+            aud: MfaClaims::AUDIENCE.to_string(),
+            exp: expiration.timestamp() as usize,
+        }
+    }
+
+    /// Decode a token, checking that it is a two-factor authentication one
+    pub fn decode(keys: &Keys, token: &str) -> Result<Self, AppError> {
+        let mut validation = Validation::default();
+        validation.set_audience(&[MfaClaims::AUDIENCE]);
+
+        let token_data = decode::<MfaClaims>(token, &keys.decoding, &validation)
+            .map_err(|_| AppError::InvalidToken)?;
+
+        Ok(token_data.claims)
+    }
+}
+
+impl MfaPending {
+    /// Make the response for a login of `user_id`
+    pub fn new(keys: &Keys, user_id: i32) -> Result<Self, AppError> {
+        let claims = MfaClaims::new(user_id);
+        let mfa_token = encode(&Header::default(), &claims, &keys.encoding)
+            .map_err(|_| AppError::TokenCreation)?;
+
+        Ok(Self {
+            mfa_required: true,
+            mfa_token,
+            expires_in: Duration::minutes(5).num_seconds(),
+        })
+    }
+}
+
+impl Totp {
+    /// Returns the settings of the user with id = `user_id`
+    pub async fn find(state: &AppState, user_id: i32) -> Result<Totp, AppError> {
+        let pool = &state.pool;
+
+        let rec: Totp = sqlx::query_as(
+            r#"
+                SELECT id, email, totp_secret, totp_enabled, totp_last_step FROM users WHERE id = $1
+            "#,
+        )
+        .bind(user_id)
+        .fetch_one(pool)
+        .await?;
+
+        Ok(rec)
+    }
+
+    /// Save a new secret which is not enabled until a first code is verified
+    pub async fn setup(&mut self, state: &AppState) -> Result<MfaSetup, AppError> {
+        let pool = &state.pool;
+
+        if self.totp_enabled {
+            return Err(AppError::BadRequest(
+                "Two-factor authentication is already enabled".to_string(),
+            ));
+        }
+
+        let secret = totp::generate_secret();
+
+        sqlx::query(
+            r#"
+            UPDATE users SET totp_secret = $1, totp_last_step = NULL WHERE id = $2
+            "#,
+        )
+        .bind(&secret)
+        .bind(self.id)
+        .execute(pool)
+        .await?;
+
+        self.totp_secret = Some(secret.clone());
+
+        Ok(MfaSetup {
+            provisioning_uri: totp::provisioning_uri("Verden", &self.email, &secret),
+            secret,
+        })
+    }
+
+    /// Enable or disable the two-factor authentication. Disabling it removes the secret and the
+    /// recovery codes too
+    pub async fn set_enabled(&mut self, state: &AppState, enabled: bool) -> Result<(), AppError> {
+        let pool = &state.pool;
+
+        if enabled {
+            sqlx::query(r#"UPDATE users SET totp_enabled = true WHERE id = $1"#)
+                .bind(self.id)
+                .execute(pool)
+                .await?;
+        } else {
+            sqlx::query(
+                r#"
+                UPDATE users SET totp_enabled = false, totp_secret = NULL, totp_last_step = NULL
+                WHERE id = $1
+                "#,
+            )
+            .bind(self.id)
+            .execute(pool)
+            .await?;
+
+            sqlx::query(r#"DELETE FROM recovery_codes WHERE user_id = $1"#)
+                .bind(self.id)
+                .execute(pool)
+                .await?;
+
+            self.totp_secret = None;
+        }
+
+        self.totp_enabled = enabled;
+
+        Ok(())
+    }
+
+    /// Check a TOTP code and mark its time step as used. Returns `false` if the code is not valid
+    pub async fn check_totp(&mut self, state: &AppState, code: &str) -> Result<bool, AppError> {
+        let pool = &state.pool;
+
+        let secret = match &self.totp_secret {
+            Some(secret) => secret,
+            None => return Ok(false),
+        };
+
+        let now = Local::now().timestamp();
+        let step = match totp::verify(secret, code, now, self.totp_last_step) {
+            Some(step) => step,
+            None => return Ok(false),
+        };
+
+        // Another request could have used the same step in the meantime
+        let result = sqlx::query(
+            r#"
+            UPDATE users SET totp_last_step = $1
+            WHERE id = $2 AND (totp_last_step IS NULL OR totp_last_step < $1)
+            "#,
+        )
+        .bind(step)
+        .bind(self.id)
+        .execute(pool)
+        .await?;
+
+        self.totp_last_step = Some(step);
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Check a TOTP code or, if it does not look like one, a recovery code
+    pub async fn check_code(&mut self, state: &AppState, code: &str) -> Result<bool, AppError> {
+        let code = code.trim();
+
+        if code.len() == 6 && code.chars().all(|c| c.is_ascii_digit()) {
+            self.check_totp(state, code).await
+        } else {
+            RecoveryCode::consume(state, self.id, code).await
+        }
+    }
+}
+
+impl RecoveryCode {
+    /// Replace the recovery codes of an user with 10 new ones. Returns the raw codes
+    pub async fn regenerate(state: &AppState, user_id: i32) -> Result<Vec<String>, AppError> {
+        let pool = &state.pool;
+        let now = Local::now().naive_utc();
+
+        sqlx::query(r#"DELETE FROM recovery_codes WHERE user_id = $1"#)
+            .bind(user_id)
+            .execute(pool)
+            .await?;
+
+        let codes: Vec<String> = (0..10).map(|_| totp::generate_recovery_code()).collect();
+
+        for code in codes.iter() {
+            sqlx::query(
+                r#"
+                INSERT INTO recovery_codes (user_id, code_hash, created)
+                VALUES ( $1, $2, $3)
+                "#,
+            )
+            .bind(user_id)
+            .bind(sha256::digest(code.as_str()))
+            .bind(now)
+            .execute(pool)
+            .await?;
+        }
+
+        Ok(codes)
+    }
+
+    /// Use a recovery code of an user. Returns `false` if it does not exist or it is already used
+    pub async fn consume(state: &AppState, user_id: i32, code: &str) -> Result<bool, AppError> {
+        let pool = &state.pool;
+
+        let result = sqlx::query(
+            r#"
+            UPDATE recovery_codes SET used = true
+            WHERE user_id = $1 AND code_hash = $2 AND used = false
+            "#,
+        )
+        .bind(user_id)
+        .bind(sha256::digest(code.to_lowercase().as_str()))
+        .execute(pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+impl LoginAttempt {
+    /// Save a new attempt
+    pub async fn save(
+        state: &AppState,
+        username: &str,
+        ip: &str,
+        success: bool,
+    ) -> Result<(), AppError> {
+        let pool = &state.pool;
+
+        sqlx::query(
+            r#"
+            INSERT INTO login_attempts (username, ip, success, created)
+            VALUES ( $1, $2, $3, $4)
+            "#,
+        )
+        .bind(username)
+        .bind(ip)
+        .bind(success)
+        .bind(Local::now().naive_utc())
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Returns the seconds to wait before a new attempt for `username` from `ip`, or `None` if
+    /// neither of them is locked out. Failures for an username are counted since its last
+    /// successful login; failures from an IP address are counted for the last day
+    pub async fn retry_after(
+        state: &AppState,
+        username: &str,
+        ip: &str,
+    ) -> Result<Option<i64>, AppError> {
+        let pool = &state.pool;
+        let config = &state.config;
+        let now = Local::now().naive_utc();
+        let since = now - Duration::days(1);
+
+        let by_username: (i64, Option<NaiveDateTime>) = sqlx::query_as(
+            r#"
+                SELECT COUNT(id), MAX(created) FROM login_attempts
+                WHERE username = $1 AND success = false AND created > GREATEST($2, COALESCE(
+                    (SELECT MAX(created) FROM login_attempts WHERE username = $1 AND success = true),
+                    $2
+                ))
+            "#,
+        )
+        .bind(username)
+        .bind(since)
+        .fetch_one(pool)
+        .await?;
+
+        let by_ip: (i64, Option<NaiveDateTime>) = sqlx::query_as(
+            r#"
+                SELECT COUNT(id), MAX(created) FROM login_attempts
+                WHERE ip = $1 AND success = false AND created > $2
+            "#,
+        )
+        .bind(ip)
+        .bind(since)
+        .fetch_one(pool)
+        .await?;
+
+        let wait = [
+            (by_username, config.login_max_failures),
+            (by_ip, config.login_ip_max_failures),
+        ]
+        .iter()
+        .filter_map(|((failures, last), max)| {
+            if failures < max {
+                return None;
+            }
+
+            // Exponential backoff, capped
+            let exponent = (failures - max).min(20) as u32;
+            let lockout = (config.login_lockout_seconds * 2_i64.pow(exponent))
+                .min(config.login_lockout_max_seconds);
+
+            let remaining = ((*last)? + Duration::seconds(lockout) - now).num_seconds();
+
+            if remaining > 0 {
+                Some(remaining)
+            } else {
+                None
+            }
+        })
+        .max();
+
+        Ok(wait)
+    }
+
+    /// List login attempts, the latest first
+    pub async fn list(
+        state: &AppState,
+        page: i64,
+        filter: &LoginAttemptFilter,
+    ) -> Result<Vec<LoginAttempt>, AppError> {
+        let pool = &state.pool;
+
+        let rows: Vec<LoginAttempt> = sqlx::query_as(
+            r#"
+                SELECT * FROM login_attempts
+                WHERE ($1::varchar IS NULL OR username = $1)
+                AND ($2::varchar IS NULL OR ip = $2)
+                AND ($3::boolean IS NULL OR success = $3)
+                ORDER BY id DESC
+                LIMIT $4 OFFSET $5
+            "#,
+        )
+        .bind(&filter.username)
+        .bind(&filter.ip)
+        .bind(filter.success)
+        .bind(state.config.page_limit)
+        .bind(state.config.page_limit * page)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows)
+    }
+
+    /// Return the number of filtered login attempts
+    pub async fn count(state: &AppState, filter: &LoginAttemptFilter) -> Result<i64, AppError> {
+        let pool = &state.pool;
+
+        let (count,): (i64,) = sqlx::query_as(
+            r#"
+                SELECT COUNT(id) FROM login_attempts
+                WHERE ($1::varchar IS NULL OR username = $1)
+                AND ($2::varchar IS NULL OR ip = $2)
+                AND ($3::boolean IS NULL OR success = $3)
+            "#,
+        )
+        .bind(&filter.username)
+        .bind(&filter.ip)
+        .bind(filter.success)
+        .fetch_one(pool)
+        .await?;
+
+        Ok(count)
+    }
+}
+
+impl Scope {
+    /// Returns the name used to store the scope
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Write => "write",
+            Scope::Upload => "upload",
+        }
+    }
+
+    /// Parse a stored scope name
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "read" => Some(Scope::Read),
+            "write" => Some(Scope::Write),
+            "upload" => Some(Scope::Upload),
+            _ => None,
+        }
+    }
+}
+
+impl ApiToken {
+    /// Prefix of every personal access token. It is used to tell them apart from JWTs
+    pub const PREFIX: &'static str = "vdn_";
+
+    /// Create a new personal access token. Returns the saved model and the raw token, which is
+    /// shown only once
+    pub async fn create(
+        state: &AppState,
+        user_id: i32,
+        payload: ApiTokenCreate,
+    ) -> Result<ApiTokenCreated, AppError> {
+        let pool = &state.pool;
+
+        if payload.name.trim().is_empty() {
+            return Err(AppError::BadRequest("Name can not be empty".to_string()));
+        }
+
+        if payload.scopes.is_empty() {
+            return Err(AppError::BadRequest(
+                "A token needs at least one scope".to_string(),
+            ));
+        }
+
+        let random: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(40)
+            .map(char::from)
+            .collect();
+        let token = format!("{}{}", ApiToken::PREFIX, random);
+
+        let now = Local::now().naive_utc();
+        let expires = payload
+            .expires_in_days
+            .map(|days| now + Duration::days(days));
+        let scopes: Vec<&str> = payload.scopes.iter().map(|x| x.as_str()).collect();
+
+        let rec: ApiToken = sqlx::query_as(
+            r#"
+                INSERT INTO api_tokens (user_id, name, token_hash, scopes, expires, created)
+                VALUES ( $1, $2, $3, $4, $5, $6)
+                RETURNING id, user_id, name, scopes, expires, last_used, created
+            "#,
+        )
+        .bind(user_id)
+        .bind(payload.name)
+        .bind(sha256::digest(token.as_str()))
+        .bind(scopes)
+        .bind(expires)
+        .bind(now)
+        .fetch_one(pool)
+        .await?;
+
+        Ok(ApiTokenCreated {
+            api_token: rec,
+            token,
+        })
+    }
+
+    /// List all the personal access tokens of an user
+    pub async fn list(state: &AppState, user_id: i32) -> Result<Vec<ApiToken>, AppError> {
+        let pool = &state.pool;
+
+        let rows: Vec<ApiToken> = sqlx::query_as(
+            r#"
+                SELECT id, user_id, name, scopes, expires, last_used, created FROM api_tokens WHERE user_id = $1 ORDER BY id DESC
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows)
+    }
+
+    /// Find a valid token from its raw value and mark it as used
+    pub async fn authenticate(state: &AppState, token: &str) -> Result<ApiToken, AppError> {
+        let pool = &state.pool;
+        let now = Local::now().naive_utc();
+
+        let rec: ApiToken = sqlx::query_as(
+            r#"
+                UPDATE api_tokens SET last_used = $1
+                WHERE token_hash = $2 AND (expires IS NULL OR expires > $1)
+                RETURNING id, user_id, name, scopes, expires, last_used, created
+            "#,
+        )
+        .bind(now)
+        .bind(sha256::digest(token))
+        .fetch_optional(pool)
+        .await?
+        .ok_or(AppError::InvalidToken)?;
+
+        Ok(rec)
+    }
+
+    /// Delete the token with id = `id` owned by `user_id`. Returns `false` if it does not exist
+    pub async fn delete(state: &AppState, user_id: i32, id: i32) -> Result<bool, AppError> {
+        let pool = &state.pool;
+
+        let result = sqlx::query(
+            r#"
+            DELETE FROM api_tokens WHERE id = $1 AND user_id = $2
+            "#,
+        )
+        .bind(id)
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Delete all the personal access tokens of an user
+    pub async fn revoke_all(state: &AppState, user_id: i32) -> Result<(), AppError> {
+        let pool = &state.pool;
+
+        sqlx::query(
+            r#"
+            DELETE FROM api_tokens WHERE user_id = $1
+            "#,
+        )
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+impl UserTokenKind {
+    /// Returns the name used to store the kind
+    fn as_str(&self) -> &'static str {
+        match self {
+            UserTokenKind::EmailVerification => "email_verification",
+            UserTokenKind::PasswordReset => "password_reset",
+        }
+    }
+
+    /// How long a token of this kind is valid
+    fn lifetime(&self) -> Duration {
+        match self {
+            UserTokenKind::EmailVerification => Duration::days(2),
+            UserTokenKind::PasswordReset => Duration::hours(1),
+        }
+    }
+}
+
+impl UserToken {
+    /// Create a new token for an user, invalidating the previous ones of the same kind. Returns
+    /// the raw token
+    pub async fn create(
+        state: &AppState,
+        user_id: i32,
+        kind: UserTokenKind,
+    ) -> Result<String, AppError> {
+        let pool = &state.pool;
+
+        sqlx::query(
+            r#"
+            UPDATE user_tokens SET used = true WHERE user_id = $1 AND kind = $2 AND used = false
+            "#,
+        )
+        .bind(user_id)
+        .bind(kind.as_str())
+        .execute(pool)
+        .await?;
+
+        let token: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(48)
+            .map(char::from)
+            .collect();
+
+        let now = Local::now().naive_utc();
+
+        sqlx::query(
+            r#"
+            INSERT INTO user_tokens (user_id, kind, token_hash, expires, created)
+            VALUES ( $1, $2, $3, $4, $5)
+            "#,
+        )
+        .bind(user_id)
+        .bind(kind.as_str())
+        .bind(sha256::digest(token.as_str()))
+        .bind(now + kind.lifetime())
+        .bind(now)
+        .execute(pool)
+        .await?;
+
+        Ok(token)
+    }
+
+    /// Use a token of a kind. It can't be used anymore. Returns the id of its user
+    pub async fn consume(
+        state: &AppState,
+        token: &str,
+        kind: UserTokenKind,
+    ) -> Result<i32, AppError> {
+        let pool = &state.pool;
+
+        let rec: Option<(i32,)> = sqlx::query_as(
+            r#"
+                UPDATE user_tokens SET used = true
+                WHERE token_hash = $1 AND kind = $2 AND used = false AND expires > $3
+                RETURNING user_id
+            "#,
+        )
+        .bind(sha256::digest(token))
+        .bind(kind.as_str())
+        .bind(Local::now().naive_utc())
+        .fetch_optional(pool)
+        .await?;
+
+        match rec {
+            Some((user_id,)) => Ok(user_id),
+            None => Err(AppError::InvalidToken),
         }
     }
 }
 
+impl AuthBody {
+    /// Open a new session for an user, returning both the access and the refresh tokens. Banned
+    /// users can not open new sessions
+    pub async fn new(state: &AppState, user_id: i32) -> Result<Self, AppError> {
+        UserBan::check_banned(state, user_id).await?;
+
+        let (refresh, refresh_token) = RefreshToken::create(state, user_id).await?;
+
+        Self::from_refresh_token(state, refresh, refresh_token)
+    }
+
+    /// Make a new access token for an already saved refresh token
+    pub fn from_refresh_token(
+        state: &AppState,
+        refresh: RefreshToken,
+        refresh_token: String,
+    ) -> Result<Self, AppError> {
+        let lifetime = Duration::minutes(state.config.access_token_minutes);
+        let claims = Claims::new(refresh.user_id, refresh.id, lifetime);
+
+        Ok(Self {
+            access_token: claims.get_token(&state.keys)?,
+            refresh_token,
+            token_type: "Bearer".to_string(),
+            expires_in: lifetime.num_seconds(),
+        })
+    }
+
+    /// Encode the tokens as an URL fragment, used to send them back to the frontend after a
+    /// redirect
+    pub fn to_fragment(&self) -> String {
+        format!(
+            "access_token={}&refresh_token={}&token_type={}&expires_in={}",
+            self.access_token, self.refresh_token, self.token_type, self.expires_in
+        )
+    }
+}
+
 /// Parse a request to get the Authorization header and then decode it checking its validation
 #[async_trait]
 impl<B> FromRequest<B> for Claims
@@ -102,21 +1087,52 @@ where
     type Rejection = AppError;
 
     async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let Extension(state) = Extension::<AppState>::from_request(req)
+            .await
+            .expect("`AppState` extension is missing");
+
         // Extract the token from the authorization header
         let TypedHeader(Authorization(bearer)) =
             TypedHeader::<Authorization<Bearer>>::from_request(req)
                 .await
                 .map_err(|_| AppError::InvalidToken)?;
-        // Decode the user data
-        let token_data = decode::<Claims>(bearer.token(), &KEYS.decoding, &Validation::default())
-            .map_err(|_| AppError::InvalidToken)?;
 
-        let now = Local::now().timestamp() as usize;
+        let claims = if bearer.token().starts_with(ApiToken::PREFIX) {
+            let api_token = ApiToken::authenticate(&state, bearer.token()).await?;
 
-        if token_data.claims.exp < now {
-            return Err(AppError::InvalidToken);
-        }
+            Claims {
+                user_id: api_token.user_id,
+                session_id: None,
+                exp: 0,
+                scopes: Some(
+                    api_token
+                        .scopes
+                        .iter()
+                        .filter_map(|x| Scope::from_name(x))
+                        .collect(),
+                ),
+            }
+        } else {
+            // Decode the user data
+            let token_data =
+                decode::<Claims>(bearer.token(), &state.keys.decoding, &Validation::default())
+                    .map_err(|_| AppError::InvalidToken)?;
 
-        Ok(token_data.claims)
+            let now = Local::now().timestamp() as usize;
+
+            if token_data.claims.exp < now {
+                return Err(AppError::InvalidToken);
+            }
+
+            // The session has been closed by a logout or by a refresh
+            let session_id = token_data.claims.session_id.ok_or(AppError::InvalidToken)?;
+            if !RefreshToken::is_active(&state, session_id).await? {
+                return Err(AppError::InvalidToken);
+            }
+
+            token_data.claims
+        };
+
+        claims.restrict(&state).await
     }
 }
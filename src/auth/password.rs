@@ -0,0 +1,131 @@
+use crate::errors::AppError;
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use lazy_static::lazy_static;
+
+/// Prefix used for legacy unsalted SHA-256 hex digests. New hashes are Argon2id PHC strings,
+/// which always start with `$argon2id$`.
+const LEGACY_SHA256_PREFIX: &str = "sha256$";
+
+lazy_static! {
+    /// Hash checked when an user does not exist, so the login takes the same time
+    static ref DUMMY_HASH: String =
+        hash_password("verden dummy password").expect("Dummy password can be hashed");
+}
+
+/// Check that a new password is strong enough
+pub fn validate(password: &str) -> Result<(), AppError> {
+    if password.chars().count() < 8 {
+        return Err(AppError::BadRequest(
+            "password: Must be min 8 chars length".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Hash a password using Argon2id with a random salt. Returns a PHC string.
+/// Argon2 takes a while on purpose: it runs out of the runtime threads
+pub async fn hash(password: &str) -> Result<String, AppError> {
+    let password = password.to_string();
+
+    tokio::task::spawn_blocking(move || hash_password(&password))
+        .await
+        .map_err(|_| AppError::PasswordHashing)?
+}
+
+/// Check a password against a stored hash, out of the runtime threads
+pub async fn verify(password: &str, stored: &str) -> bool {
+    let (password, stored) = (password.to_string(), stored.to_string());
+
+    tokio::task::spawn_blocking(move || verify_password(&password, &stored))
+        .await
+        .unwrap_or(false)
+}
+
+/// Check a password against a fixed hash, taking the time of a real check. Used when there is
+/// no user to check, so it can not be told by the response time
+pub async fn verify_dummy(password: &str) {
+    let password = password.to_string();
+
+    let _ = tokio::task::spawn_blocking(move || verify_password(&password, &DUMMY_HASH)).await;
+}
+
+fn hash_password(password: &str) -> Result<String, AppError> {
+    let salt = SaltString::generate(&mut OsRng);
+
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|_| AppError::PasswordHashing)?;
+
+    Ok(hash.to_string())
+}
+
+/// Both Argon2 PHC strings and legacy SHA-256 digests are supported
+fn verify_password(password: &str, stored: &str) -> bool {
+    if let Some(digest) = stored.strip_prefix(LEGACY_SHA256_PREFIX) {
+        return constant_time_eq(sha256::digest(password).as_bytes(), digest.as_bytes());
+    }
+
+    match PasswordHash::new(stored) {
+        Ok(parsed) => Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Returns `true` if the stored hash uses an outdated format and should be replaced
+pub fn needs_rehash(stored: &str) -> bool {
+    !stored.starts_with("$argon2id$")
+}
+
+/// Compare two byte slices without leaking the position of the first difference
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn verify_checks_an_argon2_hash() {
+        let stored = hash("correct horse").await.unwrap();
+
+        assert!(stored.starts_with("$argon2id$"));
+        assert!(verify("correct horse", &stored).await);
+        assert!(!verify("wrong horse", &stored).await);
+        assert!(!needs_rehash(&stored));
+    }
+
+    #[tokio::test]
+    async fn verify_checks_a_legacy_sha256_hash() {
+        let stored = format!("{}{}", LEGACY_SHA256_PREFIX, sha256::digest("password"));
+
+        assert!(verify("password", &stored).await);
+        assert!(!verify("Password", &stored).await);
+        assert!(needs_rehash(&stored));
+    }
+
+    #[tokio::test]
+    async fn verify_refuses_an_invalid_hash() {
+        assert!(!verify("password", "").await);
+        assert!(!verify("password", "$argon2id$broken").await);
+        assert!(!verify("password", LEGACY_SHA256_PREFIX).await);
+    }
+
+    #[test]
+    fn validate_requires_eight_chars() {
+        assert!(validate("1234567").is_err());
+        assert!(validate("12345678").is_ok());
+        // Chars, not bytes
+        assert!(validate("àèìòùàè").is_err());
+    }
+}
@@ -0,0 +1,113 @@
+use serde::{Deserialize, Serialize};
+
+/// Privilege level of an user. Each role grants a set of `Permission`s
+#[derive(Serialize, Deserialize, sqlx::Type, Clone, Copy, PartialEq, Eq, Debug)]
+#[sqlx(type_name = "user_role", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    User,
+    Moderator,
+    Admin,
+}
+
+/// Actions which go beyond what an user can do with their own resources
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Permission {
+    /// Edit models, and their uploads, created by other users
+    ModelEditAny,
+    /// Delete models, and their uploads, created by other users
+    ModelDeleteAny,
+    /// Edit the profile of other users
+    UserEditAny,
+    /// Delete the avatar of other users
+    UserAvatarDeleteAny,
+    /// Change the role of an user
+    UserPromote,
+    /// See warnings filed by other users
+    WarningReadAny,
+    /// Resolve warnings and write admin notes
+    WarningResolve,
+    /// Delete warnings
+    WarningDelete,
+    /// Review login attempts
+    LoginAttemptRead,
+    /// Suspend or ban users, and lift their bans
+    UserBan,
+    /// See the storage used by other users and change their quota
+    UserQuota,
+}
+
+/// Response used to show the role of an user and what it allows
+#[derive(Serialize)]
+pub struct RolePermissions {
+    pub role: Role,
+    pub permissions: Vec<&'static str>,
+}
+
+/// Permissions granted to moderators
+const MODERATOR_PERMISSIONS: &[Permission] = &[
+    Permission::ModelDeleteAny,
+    Permission::UserAvatarDeleteAny,
+    Permission::WarningReadAny,
+    Permission::WarningResolve,
+    Permission::LoginAttemptRead,
+    Permission::UserBan,
+];
+
+/// Permissions granted to admins
+const ADMIN_PERMISSIONS: &[Permission] = &[
+    Permission::ModelEditAny,
+    Permission::ModelDeleteAny,
+    Permission::UserEditAny,
+    Permission::UserAvatarDeleteAny,
+    Permission::UserPromote,
+    Permission::WarningReadAny,
+    Permission::WarningResolve,
+    Permission::WarningDelete,
+    Permission::LoginAttemptRead,
+    Permission::UserBan,
+    Permission::UserQuota,
+];
+
+impl Role {
+    /// Returns all the permissions granted to the role
+    pub fn permissions(&self) -> &'static [Permission] {
+        match self {
+            Role::User => &[],
+            Role::Moderator => MODERATOR_PERMISSIONS,
+            Role::Admin => ADMIN_PERMISSIONS,
+        }
+    }
+
+    /// Returns the role with the names of its permissions
+    pub fn describe(&self) -> RolePermissions {
+        RolePermissions {
+            role: *self,
+            permissions: self.permissions().iter().map(|x| x.name()).collect(),
+        }
+    }
+
+    /// Returns `true` if the role grants `permission`
+    pub fn can(&self, permission: Permission) -> bool {
+        self.permissions().contains(&permission)
+    }
+}
+
+impl Permission {
+    /// Returns the name of the permission, as it is shown to the clients
+    pub fn name(&self) -> &'static str {
+        match self {
+            Permission::ModelEditAny => "model.edit.any",
+            Permission::ModelDeleteAny => "model.delete.any",
+            Permission::UserEditAny => "user.edit.any",
+            Permission::UserAvatarDeleteAny => "user.avatar.delete.any",
+            Permission::UserPromote => "user.promote",
+            Permission::WarningReadAny => "warning.read.any",
+            Permission::WarningResolve => "warning.resolve",
+            Permission::WarningDelete => "warning.delete",
+            Permission::LoginAttemptRead => "login_attempt.read",
+            Permission::UserBan => "user.ban",
+            Permission::UserQuota => "user.quota",
+        }
+    }
+}
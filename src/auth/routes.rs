@@ -1,52 +1,233 @@
 use crate::{
+    auth::{
+        models::{
+            ApiToken, AuthBody, Claims, ForgotPasswordForm, LoginAttempt, LoginAttemptFilter,
+            LoginCredentials, LoginResponse, MfaClaims, MfaCodeForm, MfaPending, MfaSetup,
+            MfaVerifyForm, RecoveryCode, RecoveryCodes, RefreshForm, RefreshToken,
+            ResetPasswordForm, Scope, SignUpForm, Totp, UserToken, UserTokenKind, VerifyEmailForm,
+        },
+        oidc::{is_frontend_url, OidcCallback, OidcLogin, OidcState, UserIdentity},
+        password,
+        permissions::Permission,
+    },
     errors::AppError,
-    auth::models::{AuthBody, Claims, LoginCredentials, SignUpForm},
-    user::models::User,
-    routes::JsonCreate,
+    mailer::Mail,
+    pagination::{LoginAttemptPagination, Pagination},
+    routes::{ClientIp, JsonCreate},
+    state::AppState,
+    user::models::{User, UserList},
+};
+use axum::{
+    extract::{Extension, Query},
+    http::StatusCode,
+    response::{IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Json, Router,
 };
-use axum::{routing::post, Json, Router};
 
 /// Create routes for `/v1/auth/` namespace
 pub fn create_route() -> Router {
     Router::new()
         .route("/login", post(make_login))
         .route("/signup", post(signup))
+        .route("/refresh", post(refresh))
+        .route("/logout", post(logout))
+        .route("/logout/all", post(logout_all))
+        .route("/verify", post(verify_email))
+        .route("/verify/resend", post(resend_verification))
+        .route("/password/forgot", post(forgot_password))
+        .route("/password/reset", post(reset_password))
+        .route("/mfa/verify", post(mfa_verify))
+        .route("/mfa/setup", post(mfa_setup))
+        .route("/mfa/enable", post(mfa_enable))
+        .route("/mfa/disable", post(mfa_disable))
+        .route("/mfa/recovery-codes", post(mfa_recovery_codes))
+        .route("/attempts", get(list_login_attempts))
+        .route("/oidc/login", get(oidc_login))
+        .route("/oidc/callback", get(oidc_callback))
+}
+
+/// Send an email with a link to verify the user's address
+async fn send_verification_email(state: &AppState, user: &UserList) -> Result<(), AppError> {
+    let token = UserToken::create(state, user.id, UserTokenKind::EmailVerification).await?;
+
+    state
+        .mailer
+        .send(Mail {
+            to: user.email.clone(),
+            subject: "Verify your email address".to_string(),
+            body: format!(
+                "Hi {},\n\nconfirm your email address by visiting this link:\n{}/verify?token={}\n",
+                user.name, state.config.frontend_url, token
+            ),
+        })
+        .await
 }
 
 /// Make login. Check if a user with the email and password passed in request body exists into the
-/// database
-async fn make_login(Json(payload): Json<LoginCredentials>) -> Result<Json<AuthBody>, AppError> {
+/// database. If the user enabled the two-factor authentication, the login has to be completed by
+/// `/v1/auth/mfa/verify`.
+/// Too many failures for an username or from an IP address lock them out for a while
+async fn make_login(
+    Extension(state): Extension<AppState>,
+    ClientIp(ip): ClientIp,
+    Json(payload): Json<LoginCredentials>,
+) -> Result<Json<LoginResponse>, AppError> {
+    if let Some(seconds) = LoginAttempt::retry_after(&state, &payload.username, &ip).await? {
+        return Err(AppError::TooManyRequests(seconds));
+    }
+
+    let username = payload.username.clone();
     let user = User::new(
         String::new(),
         String::new(),
         payload.username,
         payload.password,
     );
-    match User::find(user).await {
-        Ok(user) => {
-            let claims = Claims::new(user.id);
-            let token = claims.get_token()?;
-            Ok(Json(AuthBody::new(token)))
+    let user = match User::find(&state, user).await {
+        Ok(user) => user,
+        Err(_) => {
+            LoginAttempt::save(&state, &username, &ip, false).await?;
+            return Err(AppError::NotFound("User not found".to_string()));
         }
-        Err(_) => Err(AppError::NotFound("User not found".to_string())),
+    };
+
+    // The attempt is saved as successful only when the second factor is verified too
+    if Totp::find(&state, user.id).await?.totp_enabled {
+        return Ok(Json(LoginResponse::MfaRequired(MfaPending::new(
+            &state.keys,
+            user.id,
+        )?)));
+    }
+
+    LoginAttempt::save(&state, &username, &ip, true).await?;
+
+    Ok(Json(LoginResponse::Authenticated(
+        AuthBody::new(&state, user.id).await?,
+    )))
+}
+
+/// Complete a login using the token returned by `/v1/auth/login` and a TOTP or recovery code
+async fn mfa_verify(
+    Extension(state): Extension<AppState>,
+    ClientIp(ip): ClientIp,
+    Json(payload): Json<MfaVerifyForm>,
+) -> Result<Json<AuthBody>, AppError> {
+    let claims = MfaClaims::decode(&state.keys, &payload.mfa_token)?;
+    let user = User::find_by_id(&state, claims.user_id).await?;
+
+    if let Some(seconds) = LoginAttempt::retry_after(&state, &user.username, &ip).await? {
+        return Err(AppError::TooManyRequests(seconds));
+    }
+
+    let mut totp = Totp::find(&state, user.id).await?;
+
+    if !totp.totp_enabled || !totp.check_code(&state, &payload.code).await? {
+        LoginAttempt::save(&state, &user.username, &ip, false).await?;
+        return Err(AppError::BadRequest("Invalid code".to_string()));
     }
+
+    LoginAttempt::save(&state, &user.username, &ip, true).await?;
+
+    Ok(Json(AuthBody::new(&state, user.id).await?))
+}
+
+/// Generate a new TOTP secret for the user linked to the claims. It is enabled by
+/// `/v1/auth/mfa/enable`
+async fn mfa_setup(
+    Extension(state): Extension<AppState>,
+    claims: Claims,
+) -> Result<Json<MfaSetup>, AppError> {
+    claims.require_session()?;
+
+    let mut totp = Totp::find(&state, claims.user_id).await?;
+
+    Ok(Json(totp.setup(&state).await?))
+}
+
+/// Enable the two-factor authentication checking a first code from the authenticator app.
+/// Returns the recovery codes
+async fn mfa_enable(
+    Extension(state): Extension<AppState>,
+    claims: Claims,
+    Json(payload): Json<MfaCodeForm>,
+) -> Result<Json<RecoveryCodes>, AppError> {
+    claims.require_session()?;
+
+    let mut totp = Totp::find(&state, claims.user_id).await?;
+
+    if totp.totp_enabled {
+        return Err(AppError::BadRequest(
+            "Two-factor authentication is already enabled".to_string(),
+        ));
+    }
+
+    if !totp.check_totp(&state, &payload.code).await? {
+        return Err(AppError::BadRequest("Invalid code".to_string()));
+    }
+
+    totp.set_enabled(&state, true).await?;
+    let recovery_codes = RecoveryCode::regenerate(&state, totp.id).await?;
+
+    Ok(Json(RecoveryCodes { recovery_codes }))
+}
+
+/// Disable the two-factor authentication. It needs a valid code
+async fn mfa_disable(
+    Extension(state): Extension<AppState>,
+    claims: Claims,
+    Json(payload): Json<MfaCodeForm>,
+) -> Result<StatusCode, AppError> {
+    claims.require_session()?;
+
+    let mut totp = Totp::find(&state, claims.user_id).await?;
+
+    if !totp.totp_enabled || !totp.check_code(&state, &payload.code).await? {
+        return Err(AppError::BadRequest("Invalid code".to_string()));
+    }
+
+    totp.set_enabled(&state, false).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Replace the recovery codes. It needs a valid code
+async fn mfa_recovery_codes(
+    Extension(state): Extension<AppState>,
+    claims: Claims,
+    Json(payload): Json<MfaCodeForm>,
+) -> Result<Json<RecoveryCodes>, AppError> {
+    claims.require_session()?;
+
+    let mut totp = Totp::find(&state, claims.user_id).await?;
+
+    if !totp.totp_enabled || !totp.check_code(&state, &payload.code).await? {
+        return Err(AppError::BadRequest("Invalid code".to_string()));
+    }
+
+    let recovery_codes = RecoveryCode::regenerate(&state, totp.id).await?;
+
+    Ok(Json(RecoveryCodes { recovery_codes }))
 }
 
 /// Create a new user
-async fn signup(Json(payload): Json<SignUpForm>) -> Result<JsonCreate<AuthBody>, AppError> {
+async fn signup(
+    Extension(state): Extension<AppState>,
+    Json(payload): Json<SignUpForm>,
+) -> Result<JsonCreate<AuthBody>, AppError> {
     if payload.password1 != payload.password2 {
         return Err(AppError::BadRequest(
             "The inserted passwords do not match".to_string(),
         ));
     }
 
-    if User::email_has_taken(&payload.email).await? {
+    if User::email_has_taken(&state, &payload.email).await? {
         return Err(AppError::BadRequest(
             "An user with this email already exists".to_string(),
         ));
     }
 
-    if User::username_has_taken(&payload.username).await? {
+    if User::username_has_taken(&state, &payload.username).await? {
         return Err(AppError::BadRequest(
             "An user with this username already exists".to_string(),
         ));
@@ -58,9 +239,217 @@ async fn signup(Json(payload): Json<SignUpForm>) -> Result<JsonCreate<AuthBody>,
         payload.username,
         payload.password1,
     );
-    let user = User::create(user).await?;
+    let user = User::create(&state, user).await?;
+
+    // The account exists anyway: the user can ask for a new email later
+    if send_verification_email(&state, &user).await.is_err() {
+        tracing::warn!("Verification email for user {} has not been sent", user.id);
+    }
+
+    Ok(JsonCreate(AuthBody::new(&state, user.id).await?))
+}
+
+/// Exchange a refresh token for a new pair of tokens. The old refresh token can't be used anymore
+async fn refresh(
+    Extension(state): Extension<AppState>,
+    Json(payload): Json<RefreshForm>,
+) -> Result<Json<AuthBody>, AppError> {
+    let (refresh, refresh_token) = RefreshToken::rotate(&state, &payload.refresh_token).await?;
+
+    Ok(Json(AuthBody::from_refresh_token(
+        &state,
+        refresh,
+        refresh_token,
+    )?))
+}
+
+/// Close the session linked to the access token
+async fn logout(
+    Extension(state): Extension<AppState>,
+    claims: Claims,
+) -> Result<StatusCode, AppError> {
+    RefreshToken::revoke(&state, claims.require_session()?).await?;
 
-    let claims = Claims::new(user.id);
-    let token = claims.get_token()?;
-    Ok(JsonCreate(AuthBody::new(token)))
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Close every session of the user linked to the access token
+async fn logout_all(
+    Extension(state): Extension<AppState>,
+    claims: Claims,
+) -> Result<StatusCode, AppError> {
+    claims.require_session()?;
+    RefreshToken::revoke_all(&state, claims.user_id).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Verify the email address linked to a token sent by email
+async fn verify_email(
+    Extension(state): Extension<AppState>,
+    Json(payload): Json<VerifyEmailForm>,
+) -> Result<StatusCode, AppError> {
+    let user_id =
+        UserToken::consume(&state, &payload.token, UserTokenKind::EmailVerification).await?;
+
+    User::verify_email(&state, user_id).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Send a new verification email to the user linked to the claims
+async fn resend_verification(
+    Extension(state): Extension<AppState>,
+    claims: Claims,
+) -> Result<StatusCode, AppError> {
+    claims.require(Scope::Write)?;
+
+    let user = User::find_by_id(&state, claims.user_id).await?;
+
+    if user.email_verified {
+        return Err(AppError::BadRequest(
+            "The email address is already verified".to_string(),
+        ));
+    }
+
+    send_verification_email(&state, &user).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Send a password reset link to an email address. The response is the same if no user has that
+/// address, so it can't be used to find out who is registered
+async fn forgot_password(
+    Extension(state): Extension<AppState>,
+    Json(payload): Json<ForgotPasswordForm>,
+) -> Result<StatusCode, AppError> {
+    if let Some(user) = User::find_by_email(&state, &payload.email).await? {
+        let token = UserToken::create(&state, user.id, UserTokenKind::PasswordReset).await?;
+
+        // A failure is only logged: the response must not tell if the email has an account
+        let sent = state
+            .mailer
+            .send(Mail {
+                to: user.email.clone(),
+                subject: "Reset your password".to_string(),
+                body: format!(
+                    "Hi {},\n\nchoose a new password by visiting this link:\n{}/password/reset?token={}\n\nIf you did not ask for it, ignore this email.\n",
+                    user.name, state.config.frontend_url, token
+                ),
+            })
+            .await;
+        if let Err(error) = sent {
+            tracing::error!("Password reset email not sent: {:?}", error);
+        }
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Set a new password using a token sent by email. Every open session is closed and every
+/// personal access token is deleted
+async fn reset_password(
+    Extension(state): Extension<AppState>,
+    Json(payload): Json<ResetPasswordForm>,
+) -> Result<StatusCode, AppError> {
+    if payload.password1 != payload.password2 {
+        return Err(AppError::BadRequest(
+            "The inserted passwords do not match".to_string(),
+        ));
+    }
+
+    // Validate it before the token is consumed
+    password::validate(&payload.password1)?;
+
+    let user_id = UserToken::consume(&state, &payload.token, UserTokenKind::PasswordReset).await?;
+
+    User::set_password(&state, user_id, &payload.password1).await?;
+    // The user could only receive the email, so the address is verified
+    User::verify_email(&state, user_id).await?;
+    // A reset can follow a compromise: the tokens made by someone else stop working too
+    RefreshToken::revoke_all(&state, user_id).await?;
+    ApiToken::revoke_all(&state, user_id).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// List login attempts. Only who can review them can perform this action
+async fn list_login_attempts(
+    Extension(state): Extension<AppState>,
+    claims: Claims,
+    pagination: Query<Pagination>,
+    Query(filter): Query<LoginAttemptFilter>,
+) -> Result<Json<LoginAttemptPagination>, AppError> {
+    claims.require(Scope::Read)?;
+
+    let user = User::find_by_id(&state, claims.user_id).await?;
+    user.require(Permission::LoginAttemptRead)?;
+
+    let page = pagination.0.page.unwrap_or_default();
+    let results = LoginAttempt::list(&state, page, &filter).await?;
+    let count = LoginAttempt::count(&state, &filter).await?;
+
+    Ok(Json(LoginAttemptPagination { count, results }))
+}
+
+/// Start a login by the OpenID Connect provider, redirecting to its authorization page.
+/// If `redirect_to` is passed, it must have the origin of the frontend: the callback will
+/// redirect there with the tokens in the URL fragment
+async fn oidc_login(
+    Extension(state): Extension<AppState>,
+    Query(payload): Query<OidcLogin>,
+) -> Result<Redirect, AppError> {
+    let oidc = match &state.oidc {
+        Some(oidc) => oidc.clone(),
+        None => return Err(AppError::NotFound("OIDC login is not enabled".to_string())),
+    };
+
+    if let Some(redirect_to) = &payload.redirect_to {
+        if !is_frontend_url(&state.config.frontend_url, redirect_to) {
+            return Err(AppError::BadRequest("Invalid redirect URL".to_string()));
+        }
+    }
+
+    let url = oidc.authorization_url(&state, payload.redirect_to).await?;
+
+    Ok(Redirect::to(&url))
+}
+
+/// Complete a login by the OpenID Connect provider. The user is found by the linked account or
+/// by its verified email, or created on the first login.
+/// The two-factor authentication is left to the provider
+async fn oidc_callback(
+    Extension(state): Extension<AppState>,
+    ClientIp(ip): ClientIp,
+    Query(payload): Query<OidcCallback>,
+) -> Result<Response, AppError> {
+    let oidc = match &state.oidc {
+        Some(oidc) => oidc.clone(),
+        None => return Err(AppError::NotFound("OIDC login is not enabled".to_string())),
+    };
+
+    let pending = OidcState::take(&state, &payload.state).await?;
+
+    let code = match (payload.code, payload.error) {
+        (Some(code), None) => code,
+        (_, error) => {
+            return Err(AppError::Forbidden(format!(
+                "Login refused by the identity provider: {}",
+                error.unwrap_or_default()
+            )))
+        }
+    };
+
+    let claims = oidc.exchange(&pending, &code).await?;
+    let user = UserIdentity::resolve(&state, oidc.issuer(), claims).await?;
+
+    LoginAttempt::save(&state, &user.username, &ip, true).await?;
+    let body = AuthBody::new(&state, user.id).await?;
+
+    Ok(match pending.redirect_to {
+        Some(redirect_to) => {
+            Redirect::to(&format!("{}#{}", redirect_to, body.to_fragment())).into_response()
+        }
+        None => Json(body).into_response(),
+    })
 }
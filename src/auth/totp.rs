@@ -0,0 +1,175 @@
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, Rng};
+use sha1::Sha1;
+
+/// Length of a time step, in seconds
+const STEP: i64 = 30;
+/// Number of digits of a code
+const DIGITS: u32 = 6;
+/// Steps accepted before and after the current one, to tolerate clock drift
+const SKEW: i64 = 1;
+
+/// Generate a new random secret, encoded in base32 as authenticator apps expect
+pub fn generate_secret() -> String {
+    let bytes: [u8; 20] = rand::random();
+
+    BASE32_NOPAD.encode(&bytes)
+}
+
+/// Build the `otpauth://` URI shown as QR code to enroll an authenticator app
+pub fn provisioning_uri(issuer: &str, account: &str, secret: &str) -> String {
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        urlencode(issuer),
+        urlencode(account),
+        secret,
+        urlencode(issuer),
+        DIGITS,
+        STEP
+    )
+}
+
+/// Returns the time step for a unix timestamp
+pub fn step_at(timestamp: i64) -> i64 {
+    timestamp.div_euclid(STEP)
+}
+
+/// Compute the code of a secret for a time step, as defined by RFC 6238 and RFC 4226
+pub fn code_at(secret: &str, step: i64) -> Option<String> {
+    let key = BASE32_NOPAD.decode(secret.as_bytes()).ok()?;
+
+    let mut mac = Hmac::<Sha1>::new_from_slice(&key).ok()?;
+    mac.update(&step.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    // Dynamic truncation
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+
+    Some(format!(
+        "{:0width$}",
+        binary % 10_u32.pow(DIGITS),
+        width = DIGITS as usize
+    ))
+}
+
+/// Check a code at a timestamp. Returns the matched time step, which has to be greater than
+/// `last_step` so that a code can't be used twice
+pub fn verify(secret: &str, code: &str, timestamp: i64, last_step: Option<i64>) -> Option<i64> {
+    let code = code.trim();
+    let current = step_at(timestamp);
+
+    (current - SKEW..=current + SKEW)
+        .filter(|step| last_step.is_none_or(|last| *step > last))
+        .find(|step| code_at(secret, *step).as_deref() == Some(code))
+}
+
+/// Generate a new recovery code in the format `xxxxx-xxxxx`
+pub fn generate_recovery_code() -> String {
+    let raw: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(10)
+        .map(|c| char::from(c).to_ascii_lowercase())
+        .collect();
+
+    format!("{}-{}", &raw[..5], &raw[5..])
+}
+
+/// Percent-encode a label of the provisioning URI
+fn urlencode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Secret of the test vectors of RFC 6238, `12345678901234567890` in base32
+    const SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    #[test]
+    fn code_at_matches_rfc_6238() {
+        // The RFC lists 8 digits codes: these are their last 6 digits
+        let vectors = [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130"),
+        ];
+
+        for (timestamp, code) in vectors {
+            assert_eq!(code_at(SECRET, step_at(timestamp)).as_deref(), Some(code));
+        }
+    }
+
+    #[test]
+    fn code_at_refuses_an_invalid_secret() {
+        assert_eq!(code_at("not base32!", 1), None);
+    }
+
+    #[test]
+    fn verify_accepts_the_near_steps() {
+        let step = step_at(1111111111);
+
+        assert_eq!(verify(SECRET, "050471", 1111111111, None), Some(step));
+        assert_eq!(verify(SECRET, " 050471 ", 1111111111, None), Some(step));
+        assert_eq!(
+            verify(SECRET, "050471", 1111111111 + STEP, None),
+            Some(step)
+        );
+        assert_eq!(
+            verify(SECRET, "050471", 1111111111 - STEP, None),
+            Some(step)
+        );
+        assert_eq!(verify(SECRET, "050471", 1111111111 + 3 * STEP, None), None);
+        assert_eq!(verify(SECRET, "000000", 1111111111, None), None);
+    }
+
+    #[test]
+    fn verify_refuses_a_used_step() {
+        let step = step_at(1111111111);
+
+        assert_eq!(verify(SECRET, "050471", 1111111111, Some(step)), None);
+        assert_eq!(
+            verify(SECRET, "050471", 1111111111, Some(step - 1)),
+            Some(step)
+        );
+    }
+
+    #[test]
+    fn provisioning_uri_encodes_the_labels() {
+        let uri = provisioning_uri("Verden", "alice@example.com", SECRET);
+
+        assert_eq!(
+            uri,
+            format!(
+                "otpauth://totp/Verden:alice%40example.com?secret={}&issuer=Verden&algorithm=SHA1&digits=6&period=30",
+                SECRET
+            )
+        );
+    }
+
+    #[test]
+    fn recovery_codes_have_two_groups() {
+        let code = generate_recovery_code();
+
+        assert_eq!(code.len(), 11);
+        assert_eq!(&code[5..6], "-");
+    }
+}
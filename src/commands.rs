@@ -0,0 +1,77 @@
+use crate::{
+    config::Configuration, consistency, errors::AppError, files::TempUpload, state::AppState,
+    storage,
+};
+
+/// Usage of the commands, printed on a wrong invocation
+const USAGE: &str = "Usage: verden [migrate-storage <from> <to> | check-storage [--fix]]";
+
+/// Run the command passed on the command line
+pub async fn run(config: Configuration, args: &[String]) -> Result<(), AppError> {
+    match args
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<&str>>()
+        .as_slice()
+    {
+        ["migrate-storage", from, to] => migrate_storage(&config, from, to).await,
+        ["check-storage"] => check_storage(config, false).await,
+        ["check-storage", "--fix"] => check_storage(config, true).await,
+        _ => Err(AppError::BadRequest(USAGE.to_string())),
+    }
+}
+
+/// Copy all the files from a storage backend to another one, like from "local" to "s3". Files
+/// which already exist in the destination are skipped, so it can be run again after a failure.
+/// The files in the source are not deleted
+async fn migrate_storage(config: &Configuration, from: &str, to: &str) -> Result<(), AppError> {
+    if from == to {
+        return Err(AppError::BadRequest(
+            "Source and destination are the same storage".to_string(),
+        ));
+    }
+
+    let source = storage::build(from, config)?;
+    let destination = storage::build(to, config)?;
+
+    let keys = source.list().await?;
+    tracing::info!("Migrating {} files from `{}` to `{}`", keys.len(), from, to);
+
+    let mut copied = 0;
+    for key in &keys {
+        if destination.exists(key).await? {
+            continue;
+        }
+
+        // Big files are copied through the temporary directory, never read all at once
+        let temp = TempUpload::from_storage(&*source, config, key).await?;
+        destination.put_file(key, &temp.path).await?;
+        copied += 1;
+    }
+
+    tracing::info!(
+        "{} files copied, {} already in `{}`",
+        copied,
+        keys.len() - copied,
+        to
+    );
+
+    Ok(())
+}
+
+/// Check the storage against the database, logging the drifts. With `fix` they are repaired.
+/// Without it, a drift makes the command fail, so it can be used by scripts
+async fn check_storage(config: Configuration, fix: bool) -> Result<(), AppError> {
+    let state = AppState::new(config).await?;
+
+    let report = consistency::check(&state, fix).await?;
+    report.log();
+
+    if !fix && !report.is_clean() {
+        return Err(AppError::Conflict(
+            "The storage is not consistent with the database".to_string(),
+        ));
+    }
+
+    Ok(())
+}
@@ -6,12 +6,174 @@ use serde::Deserialize;
 #[derive(Deserialize)]
 pub struct Configuration {
     pub page_limit: i64,
+    /// Directory used by the "local" storage
     pub save_file_base_path: String,
     pub uploads_endpoint: String,
+    /// Directory where uploads are written while they are received. On the same filesystem of
+    /// `save_file_base_path`, files are moved into the "local" storage without copies
+    #[serde(default = "default_upload_temp_path")]
+    pub upload_temp_path: String,
+    /// Biggest file which can be uploaded for a model, in bytes
+    #[serde(default = "default_max_upload_size")]
+    pub max_upload_size: u64,
+    /// Biggest avatar which can be uploaded, in bytes
+    #[serde(default = "default_max_avatar_size")]
+    pub max_avatar_size: u64,
+    /// Biggest 3D file which can be converted to another format, in bytes
+    #[serde(default = "default_max_convert_size")]
+    pub max_convert_size: u64,
+    /// Bytes of uploads an user can have on their models, if a staffer did not set another quota
+    #[serde(default = "default_storage_quota")]
+    pub default_storage_quota: i64,
+    /// Hours between two checks of the storage against the database. `0` disables the checks
+    #[serde(default)]
+    pub storage_check_hours: u64,
+    /// Repair the drifts found by the periodic storage checks, instead of only logging them
+    #[serde(default)]
+    pub storage_check_fix: bool,
     pub rust_log: String,
     pub database_url: String,
     pub jwt_secret: String,
     pub allowed_host: String,
+    /// Lifetime of an access token, in minutes
+    #[serde(default = "default_access_token_minutes")]
+    pub access_token_minutes: i64,
+    /// Lifetime of a refresh token, in days
+    #[serde(default = "default_refresh_token_days")]
+    pub refresh_token_days: i64,
+    /// URL of the frontend, used to build the links sent by email
+    #[serde(default = "default_frontend_url")]
+    pub frontend_url: String,
+    /// Mailer backend: "smtp", "file" or "log"
+    #[serde(default = "default_mailer")]
+    pub mailer: String,
+    #[serde(default = "default_mail_from")]
+    pub mail_from: String,
+    pub smtp_host: Option<String>,
+    #[serde(default = "default_smtp_port")]
+    pub smtp_port: u16,
+    pub smtp_username: Option<String>,
+    pub smtp_password: Option<String>,
+    /// Directory used by the "file" mailer
+    pub mail_outbox_path: Option<String>,
+    /// Failed logins for an username before it is locked out
+    #[serde(default = "default_login_max_failures")]
+    pub login_max_failures: i64,
+    /// Failed logins from an IP address before it is locked out
+    #[serde(default = "default_login_ip_max_failures")]
+    pub login_ip_max_failures: i64,
+    /// First lockout, in seconds. It doubles for every next failure
+    #[serde(default = "default_login_lockout_seconds")]
+    pub login_lockout_seconds: i64,
+    /// Longest lockout, in seconds
+    #[serde(default = "default_login_lockout_max_seconds")]
+    pub login_lockout_max_seconds: i64,
+    /// Read the client IP address from the last value of the `X-Forwarded-For` header. Enable it
+    /// only behind a proxy which appends the address it receives the request from
+    #[serde(default)]
+    pub trust_proxy_headers: bool,
+    /// Issuer URL of the OpenID Connect provider. The OIDC login is enabled only if it is set
+    pub oidc_issuer: Option<String>,
+    pub oidc_client_id: Option<String>,
+    pub oidc_client_secret: Option<String>,
+    /// Callback URL registered on the provider, that is `<host>/v1/auth/oidc/callback`
+    pub oidc_redirect_url: Option<String>,
+    /// Scopes asked to the provider, separated by spaces
+    #[serde(default = "default_oidc_scopes")]
+    pub oidc_scopes: String,
+    /// What happens to the models of a deleted account: "anonymize" keeps them under an anonymous
+    /// user, "cascade" deletes them with their files
+    #[serde(default = "default_account_deletion_policy")]
+    pub account_deletion_policy: String,
+    /// Storage backend of the uploaded files: "local" or "s3"
+    #[serde(default = "default_storage")]
+    pub storage: String,
+    pub s3_bucket: Option<String>,
+    #[serde(default = "default_s3_region")]
+    pub s3_region: String,
+    /// Endpoint of a S3-compatible service, like MinIO. If it is not set, AWS is used
+    pub s3_endpoint: Option<String>,
+    pub s3_access_key: Option<String>,
+    pub s3_secret_key: Option<String>,
+    /// Use `<endpoint>/<bucket>` URLs instead of `<bucket>.<endpoint>`. Needed by MinIO
+    #[serde(default)]
+    pub s3_path_style: bool,
+}
+
+fn default_upload_temp_path() -> String {
+    std::env::temp_dir().to_string_lossy().to_string()
+}
+
+fn default_max_upload_size() -> u64 {
+    40 * 1024 * 1024
+}
+
+fn default_max_avatar_size() -> u64 {
+    5 * 1024 * 1024
+}
+
+fn default_max_convert_size() -> u64 {
+    40 * 1024 * 1024
+}
+
+fn default_storage_quota() -> i64 {
+    1024 * 1024 * 1024
+}
+
+fn default_access_token_minutes() -> i64 {
+    15
+}
+
+fn default_refresh_token_days() -> i64 {
+    30
+}
+
+fn default_frontend_url() -> String {
+    "http://localhost:3000".to_string()
+}
+
+fn default_mailer() -> String {
+    "log".to_string()
+}
+
+fn default_mail_from() -> String {
+    "Verden <noreply@localhost>".to_string()
+}
+
+fn default_smtp_port() -> u16 {
+    587
+}
+
+fn default_login_max_failures() -> i64 {
+    5
+}
+
+fn default_login_ip_max_failures() -> i64 {
+    20
+}
+
+fn default_login_lockout_seconds() -> i64 {
+    30
+}
+
+fn default_login_lockout_max_seconds() -> i64 {
+    3600
+}
+
+fn default_oidc_scopes() -> String {
+    "openid email profile".to_string()
+}
+
+fn default_account_deletion_policy() -> String {
+    "anonymize".to_string()
+}
+
+fn default_storage() -> String {
+    "local".to_string()
+}
+
+fn default_s3_region() -> String {
+    "us-east-1".to_string()
 }
 
 pub struct Sentry(pub ClientInitGuard);
@@ -29,7 +191,7 @@ impl Sentry {
         match std::env::var("SENTRY_DSN") {
             Ok(dsn) => {
                 let guard = sentry::init((
-                    dsn.clone(),
+                    dsn,
                     sentry::ClientOptions {
                         release: sentry::release_name!(),
                         ..Default::default()
@@ -44,6 +206,5 @@ impl Sentry {
 }
 
 lazy_static! {
-    pub static ref CONFIG: Configuration = Configuration::new().expect("Config can be loaded");
     pub static ref SENTRY: Sentry = Sentry::new().expect("Sentry not configured.");
 }
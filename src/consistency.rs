@@ -0,0 +1,355 @@
+use crate::{
+    errors::AppError,
+    files::storage_key,
+    model::models::{Model, ModelUpload, PendingUpload},
+    state::AppState,
+};
+use chrono::{DateTime, Duration, Local, Utc};
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+};
+
+/// Files and blobs changed in the last minutes are not checked: their rows can still be on their
+/// way, like for an upload saved to the storage before its row is created
+const GRACE_MINUTES: i64 = 60;
+
+/// Drifts found between the rows which reference uploaded files and the files in the storage
+#[derive(Default)]
+pub struct StorageReport {
+    /// Keys of the stored files which no row references
+    pub orphaned_files: Vec<String>,
+    /// Model uploads, as `(id, model id, filepath)`, whose file is not stored
+    pub missing_uploads: Vec<(i32, i32, String)>,
+    /// Avatars, as `(user id, filepath)`, whose file is not stored
+    pub missing_avatars: Vec<(i32, String)>,
+    /// Derived files, as `(id, filepath)`, which are not stored or whose source is gone
+    pub stale_derivatives: Vec<(i32, String)>,
+    /// Blobs, as `(key, saved refcount, counted references)`, with a wrong reference count
+    pub wrong_refcounts: Vec<(String, i32, i32)>,
+    /// Partial files of the received uploads left in the temporary directory
+    pub stale_temp_files: Vec<PathBuf>,
+    /// Uploads, as `(id, filepath, stored size)`, saved before their size was counted: they do
+    /// not count toward the storage quota of the user
+    pub unsized_uploads: Vec<(i32, String, i64)>,
+}
+
+impl StorageReport {
+    /// Returns `true` if the storage is consistent with the database
+    pub fn is_clean(&self) -> bool {
+        self.orphaned_files.is_empty()
+            && self.missing_uploads.is_empty()
+            && self.missing_avatars.is_empty()
+            && self.stale_derivatives.is_empty()
+            && self.wrong_refcounts.is_empty()
+            && self.stale_temp_files.is_empty()
+            && self.unsized_uploads.is_empty()
+    }
+
+    /// Log every drift of the report
+    pub fn log(&self) {
+        for key in &self.orphaned_files {
+            tracing::warn!("Orphaned file `{}`", key);
+        }
+        for (id, model_id, filepath) in &self.missing_uploads {
+            tracing::warn!(
+                "Upload {} of model {} points to missing file `{}`",
+                id,
+                model_id,
+                filepath
+            );
+        }
+        for (user_id, filepath) in &self.missing_avatars {
+            tracing::warn!(
+                "Avatar of user {} points to missing file `{}`",
+                user_id,
+                filepath
+            );
+        }
+        for (id, filepath) in &self.stale_derivatives {
+            tracing::warn!("Derived file {} `{}` is stale", id, filepath);
+        }
+        for (key, saved, counted) in &self.wrong_refcounts {
+            tracing::warn!(
+                "Blob `{}` has {} references instead of {}",
+                key,
+                saved,
+                counted
+            );
+        }
+        for path in &self.stale_temp_files {
+            tracing::warn!("Stale temporary file `{}`", path.display());
+        }
+        for (id, filepath, size) in &self.unsized_uploads {
+            tracing::warn!(
+                "Upload {} `{}` has no size, its file has {} bytes",
+                id,
+                filepath,
+                size
+            );
+        }
+
+        tracing::info!(
+            "Storage check: {} orphaned files, {} missing uploads, {} missing avatars, {} stale derived files, {} wrong reference counts, {} stale temporary files, {} uploads without size",
+            self.orphaned_files.len(),
+            self.missing_uploads.len(),
+            self.missing_avatars.len(),
+            self.stale_derivatives.len(),
+            self.wrong_refcounts.len(),
+            self.stale_temp_files.len(),
+            self.unsized_uploads.len()
+        );
+    }
+}
+
+/// Compare the files referenced by `uploads`, `users.avatar`, `upload_derivatives` and `blobs`
+/// with the files in the storage. With `fix`, the drifts are repaired: orphaned files are
+/// deleted, rows pointing to missing files are deleted (or emptied, for the avatars), the
+/// reference counts of the blobs are recounted and the uploads without a size get the one of
+/// their file
+pub async fn check(state: &AppState, fix: bool) -> Result<StorageReport, AppError> {
+    let mut report = StorageReport::default();
+    let grace = Local::now().naive_utc() - Duration::minutes(GRACE_MINUTES);
+
+    // Rows are read before the storage is listed: a file saved in the meantime is younger than
+    // the grace period, and a row never points to a file saved after it.
+    // They are read from the same snapshot, so the references are counted at the same time as
+    // the blobs. A blob is acquired before its row is created, so the recently changed ones are
+    // not counted anyway
+    let mut tx = state.pool.begin().await?;
+    sqlx::query(r#"SET TRANSACTION ISOLATION LEVEL REPEATABLE READ, READ ONLY"#)
+        .execute(&mut tx)
+        .await?;
+    let uploads: Vec<(i32, i32, String, i64)> =
+        sqlx::query_as(r#"SELECT id, model_id, filepath, size FROM uploads"#)
+            .fetch_all(&mut tx)
+            .await?;
+    let avatars: Vec<(i32, String)> =
+        sqlx::query_as(r#"SELECT id, avatar FROM users WHERE avatar IS NOT NULL"#)
+            .fetch_all(&mut tx)
+            .await?;
+    let derivatives: Vec<(i32, String, String)> =
+        sqlx::query_as(r#"SELECT id, source, filepath FROM upload_derivatives"#)
+            .fetch_all(&mut tx)
+            .await?;
+    let blobs: Vec<(String, i32)> =
+        sqlx::query_as(r#"SELECT key, refcount FROM blobs WHERE updated < $1"#)
+            .bind(grace)
+            .fetch_all(&mut tx)
+            .await?;
+    tx.commit().await?;
+
+    let stored: HashSet<String> = state.storage.list().await?.into_iter().collect();
+    let is_stored = |filepath: &str| stored.contains(storage_key(filepath));
+
+    // References to a file which exists, counted as `Blob::acquire` does
+    let mut references: HashMap<&str, i32> = HashMap::new();
+    for (id, model_id, filepath, size) in &uploads {
+        if is_stored(filepath) {
+            *references.entry(storage_key(filepath)).or_default() += 1;
+
+            // Uploads saved before the quotas have no blob with their size
+            if *size == 0 {
+                let info = state.storage.stat(storage_key(filepath)).await?;
+                report
+                    .unsized_uploads
+                    .push((*id, filepath.clone(), info.size as i64));
+            }
+        } else {
+            report
+                .missing_uploads
+                .push((*id, *model_id, filepath.clone()));
+        }
+    }
+    for (user_id, filepath) in &avatars {
+        if is_stored(filepath) {
+            *references.entry(storage_key(filepath)).or_default() += 1;
+        } else {
+            report.missing_avatars.push((*user_id, filepath.clone()));
+        }
+    }
+
+    let mut referenced: HashSet<&str> = references.keys().copied().collect();
+    for (id, source, filepath) in &derivatives {
+        if is_stored(filepath) && references.contains_key(storage_key(source)) {
+            referenced.insert(storage_key(filepath));
+        } else {
+            report.stale_derivatives.push((*id, filepath.clone()));
+        }
+    }
+
+    for (key, refcount) in &blobs {
+        let counted = references.get(key.as_str()).copied().unwrap_or_default();
+        if *refcount != counted {
+            report
+                .wrong_refcounts
+                .push((key.clone(), *refcount, counted));
+        }
+    }
+
+    for key in &stored {
+        if referenced.contains(key.as_str()) {
+            continue;
+        }
+        if state.storage.stat(key).await?.modified < grace {
+            report.orphaned_files.push(key.clone());
+        }
+    }
+    report.orphaned_files.sort();
+
+    report.stale_temp_files = stale_temp_files(state).await?;
+
+    if fix {
+        repair(state, &report).await?;
+    }
+
+    Ok(report)
+}
+
+/// Partial files in the temporary directory which have not been written for longer than the
+/// expiration of the resumable uploads
+async fn stale_temp_files(state: &AppState) -> Result<Vec<PathBuf>, AppError> {
+    let limit = Local::now().naive_utc() - Duration::hours(PendingUpload::EXPIRATION_HOURS);
+    let mut files = vec![];
+
+    let mut entries = match tokio::fs::read_dir(&state.config.upload_temp_path).await {
+        Ok(entries) => entries,
+        Err(_) => return Ok(files),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !name.starts_with("verden-") || !name.ends_with(".part") {
+            continue;
+        }
+
+        let modified = entry
+            .metadata()
+            .await
+            .and_then(|metadata| metadata.modified())
+            .map(|time| DateTime::<Utc>::from(time).naive_utc());
+        if modified.is_ok_and(|modified| modified < limit) {
+            files.push(entry.path());
+        }
+    }
+    files.sort();
+
+    Ok(files)
+}
+
+/// Repair the drifts of a report
+async fn repair(state: &AppState, report: &StorageReport) -> Result<(), AppError> {
+    let pool = &state.pool;
+
+    let mut models = HashSet::new();
+    for (id, model_id, _) in &report.missing_uploads {
+        ModelUpload::delete(state, *id).await?;
+        models.insert(*model_id);
+    }
+    for model_id in models {
+        Model::update_height_from_uploads(state, model_id).await?;
+    }
+
+    // An user who changed the avatar in the meantime is not touched
+    for (user_id, filepath) in &report.missing_avatars {
+        sqlx::query(r#"UPDATE users SET avatar = NULL WHERE id = $1 AND avatar = $2"#)
+            .bind(user_id)
+            .bind(filepath)
+            .execute(pool)
+            .await?;
+    }
+
+    for (id, _) in &report.stale_derivatives {
+        sqlx::query(r#"DELETE FROM upload_derivatives WHERE id = $1"#)
+            .bind(id)
+            .execute(pool)
+            .await?;
+    }
+
+    // A blob acquired or released in the meantime keeps its count, even if it went back to the
+    // saved one
+    let grace = Local::now().naive_utc() - Duration::minutes(GRACE_MINUTES);
+    for (key, saved, counted) in &report.wrong_refcounts {
+        if *counted == 0 {
+            sqlx::query(r#"DELETE FROM blobs WHERE key = $1 AND refcount = $2 AND updated < $3"#)
+                .bind(key)
+                .bind(saved)
+                .bind(grace)
+                .execute(pool)
+                .await?;
+        } else {
+            sqlx::query(
+                r#"
+                    UPDATE blobs SET refcount = $3
+                    WHERE key = $1 AND refcount = $2 AND updated < $4
+                "#,
+            )
+            .bind(key)
+            .bind(saved)
+            .bind(counted)
+            .bind(grace)
+            .execute(pool)
+            .await?;
+        }
+    }
+
+    for key in &report.orphaned_files {
+        if let Err(error) = state.storage.delete(key).await {
+            tracing::warn!("File `{}` has not been deleted: {:?}", key, error);
+        }
+    }
+
+    for (id, _, size) in &report.unsized_uploads {
+        sqlx::query(r#"UPDATE uploads SET size = $2 WHERE id = $1 AND size = 0"#)
+            .bind(id)
+            .bind(size)
+            .execute(pool)
+            .await?;
+    }
+
+    PendingUpload::delete_expired(state).await?;
+    for path in &report.stale_temp_files {
+        let _ = tokio::fs::remove_file(path).await;
+    }
+
+    Ok(())
+}
+
+/// Warn about the uploads saved before their size was counted. Until `check-storage --fix` fills
+/// their sizes, they do not count toward the storage quotas
+pub async fn warn_unsized_uploads(state: &AppState) {
+    let count: Result<(i64,), _> = sqlx::query_as(r#"SELECT COUNT(*) FROM uploads WHERE size = 0"#)
+        .fetch_one(&state.pool)
+        .await;
+
+    match count {
+        Ok((0,)) => {}
+        Ok((count,)) => tracing::warn!(
+            "{} uploads have no size and do not count toward the storage quotas: run `check-storage --fix`",
+            count
+        ),
+        Err(error) => tracing::error!("Uploads without size not counted: {:?}", error),
+    }
+}
+
+/// Run the storage check every `STORAGE_CHECK_HOURS`, if it is set. With `STORAGE_CHECK_FIX` the
+/// drifts are also repaired
+pub fn schedule(state: AppState) {
+    let hours = state.config.storage_check_hours;
+    if hours == 0 {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let period = std::time::Duration::from_secs(hours * 60 * 60);
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+
+        loop {
+            interval.tick().await;
+
+            match check(&state, state.config.storage_check_fix).await {
+                Ok(report) => report.log(),
+                Err(error) => tracing::error!("Storage check failed: {:?}", error),
+            }
+        }
+    });
+}
@@ -1,11 +1,12 @@
 use axum::{
-    http::StatusCode,
+    http::{header, HeaderValue, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
 use serde_json::json;
 
 /// All errors raised by the web app
+#[derive(Debug)]
 pub enum AppError {
     /// Database error
     Database,
@@ -17,8 +18,34 @@ pub enum AppError {
     TokenCreation,
     /// Raised when a passed token is not valid
     InvalidToken,
+    /// Raised when a password can't be hashed
+    PasswordHashing,
     /// Raised if an user wants to do something can't do
     Unauthorized,
+    /// Raised if an user is authenticated but not allowed to do something. It is handled with a
+    /// message value
+    Forbidden(String),
+    /// Raised when an email can't be sent
+    Mail,
+    /// Raised when a client has to wait before retrying. It is handled with the number of seconds
+    /// to wait
+    TooManyRequests(i64),
+    /// Raised when the storage of the uploaded files fails
+    Storage,
+    /// Raised when an upstream service, like an identity provider, fails. It is handled with a
+    /// message value
+    BadGateway(String),
+    /// Raised when an uploaded file is bigger than allowed. It is handled with the limit, in bytes
+    PayloadTooLarge(u64),
+    /// Raised when an upload does not fit in the storage quota of an user. It is handled with the
+    /// available bytes
+    QuotaExceeded(i64),
+    /// Raised when a request does not match the current state of a resource. It is handled with a
+    /// message value
+    Conflict(String),
+    /// Raised when a precondition of the request, like a protocol version, is not met. It is
+    /// handled with a message value
+    PreconditionFailed(String),
 }
 
 /// Use `AppError` as response for an endpoint
@@ -29,6 +56,11 @@ impl IntoResponse for AppError {
     /// { "error": "<message>" }
     /// ```
     fn into_response(self) -> Response {
+        let retry_after = match self {
+            AppError::TooManyRequests(seconds) => Some(seconds),
+            _ => None,
+        };
+
         let (status, error_message) = match self {
             AppError::Database => (
                 StatusCode::INTERNAL_SERVER_ERROR,
@@ -41,17 +73,52 @@ impl IntoResponse for AppError {
                 "Token creation error".to_string(),
             ),
             AppError::InvalidToken => (StatusCode::BAD_REQUEST, "Invalid token".to_string()),
+            AppError::PasswordHashing => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Password hashing error".to_string(),
+            ),
             AppError::Unauthorized => (
                 StatusCode::UNAUTHORIZED,
                 "Can't perform this action".to_string(),
             ),
+            AppError::Forbidden(value) => (StatusCode::FORBIDDEN, value),
+            AppError::Mail => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Error sending the email".to_string(),
+            ),
+            AppError::TooManyRequests(seconds) => (
+                StatusCode::TOO_MANY_REQUESTS,
+                format!("Too many attempts, retry in {} seconds", seconds),
+            ),
+            AppError::Storage => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Error with the file storage".to_string(),
+            ),
+            AppError::BadGateway(value) => (StatusCode::BAD_GATEWAY, value),
+            AppError::PayloadTooLarge(limit) => (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("File is too large, the limit is {} bytes", limit),
+            ),
+            AppError::QuotaExceeded(available) => (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("Storage quota exceeded, {} bytes are available", available),
+            ),
+            AppError::Conflict(value) => (StatusCode::CONFLICT, value),
+            AppError::PreconditionFailed(value) => (StatusCode::PRECONDITION_FAILED, value),
         };
 
         let body = Json(json!({
             "error": error_message,
         }));
 
-        (status, body).into_response()
+        let mut response = (status, body).into_response();
+        if let Some(seconds) = retry_after {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(seconds));
+        }
+
+        response
     }
 }
 
@@ -1,102 +1,973 @@
-use crate::{config::CONFIG, errors::AppError};
+use crate::{
+    config::Configuration,
+    errors::AppError,
+    filetype::{self, FileType},
+    gcode,
+    mesh::{self, MeshFormat},
+    routes::{http_date, parse_http_date},
+    state::AppState,
+    storage::{storage_error, Storage},
+    variants,
+    variants::IMAGE_EXTENSIONS,
+};
 use axum::{
-    extract::{Multipart, Path},
-    http::header::{HeaderMap, HeaderName, HeaderValue},
+    body::{boxed, Body},
+    extract::{multipart::Field, Extension, Multipart, Path, Query},
+    http::{
+        header::{self, HeaderMap, HeaderValue},
+        Method, StatusCode,
+    },
+    response::{IntoResponse, Response},
 };
-use std::{fs, path};
+use chrono::{Local, NaiveDateTime};
+use data_encoding::HEXLOWER;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use sqlx::types::JsonValue;
+use std::path::PathBuf;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Size of the chunks read from the storage while a file is sent
+pub const STREAM_CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Files generated from an uploaded one, like the thumbnails of a mesh. They are linked to their
+/// source by its public path, and deleted with it
+pub struct UploadDerivative;
+
+/// Files of the storage, named by the SHA-256 digest of their content. A file uploaded many
+/// times is stored once, and it is deleted when nothing references it anymore
+pub struct Blob;
+
+/// Query used to ask for a derived file
+#[derive(Deserialize)]
+pub struct UploadVariant {
+    pub size: Option<i32>,
+    /// Extension of the derived file. Without it, WebP files are the last choice
+    pub format: Option<String>,
+    /// Id of the model upload of the file, whose name is used for the download
+    pub upload: Option<i32>,
+}
+
+/// A file saved by `upload`
+pub struct SavedUpload {
+    /// Public path of the file
+    pub filepath: String,
+    /// SHA-256 digest of the content, in hex
+    pub hash: String,
+    /// Values computed from a 3D file
+    pub geometry: Option<JsonValue>,
+    /// Name of the file on the computer of the user
+    pub filename: Option<String>,
+    /// Size of the saved file, in bytes
+    pub size: i64,
+    /// Values read from a G-code file
+    pub print_info: Option<JsonValue>,
+}
+
+/// A file received from a client, kept into the temporary directory. It is deleted when it is
+/// dropped, if it was not moved into the storage
+pub struct TempUpload {
+    pub path: PathBuf,
+    /// Size of the file, in bytes
+    pub size: u64,
+    /// SHA-256 digest of the content, in hex
+    pub hash: String,
+}
+
+impl TempUpload {
+    /// Path of a new file into the temporary directory
+    fn new_path(config: &Configuration) -> PathBuf {
+        PathBuf::from(&config.upload_temp_path)
+            .join(format!("verden-{:016x}.part", rand::random::<u64>()))
+    }
+
+    /// Copy a file of a storage into the temporary directory, so it is never held in memory.
+    /// The file is read by chunks
+    pub async fn from_storage(
+        storage: &dyn Storage,
+        config: &Configuration,
+        key: &str,
+    ) -> Result<Self, AppError> {
+        let size = storage.stat(key).await?.size;
+        let mut temp = TempUpload {
+            path: Self::new_path(config),
+            size,
+            hash: String::new(),
+        };
+
+        let mut file = tokio::fs::File::create(&temp.path)
+            .await
+            .map_err(storage_error)?;
+        let mut hasher = Sha256::new();
+
+        let mut position = 0;
+        while position < size {
+            let chunk_end = (size - 1).min(position + STREAM_CHUNK_SIZE - 1);
+            let chunk = storage.get_range(key, position, chunk_end).await?;
+            if chunk.is_empty() {
+                return Err(storage_error(format!("File `{}` is truncated", key)));
+            }
+
+            hasher.update(&chunk);
+            file.write_all(&chunk).await.map_err(storage_error)?;
+            position += chunk.len() as u64;
+        }
+
+        file.flush().await.map_err(storage_error)?;
+        temp.hash = HEXLOWER.encode(&hasher.finalize());
+
+        Ok(temp)
+    }
+
+    /// Take a file already written, computing its size and its digest. The file is read by
+    /// chunks
+    pub async fn from_path(path: PathBuf) -> Result<Self, AppError> {
+        let mut file = tokio::fs::File::open(&path).await.map_err(storage_error)?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0; 64 * 1024];
+        let mut size = 0;
+
+        loop {
+            let read = file.read(&mut buffer).await.map_err(storage_error)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+            size += read as u64;
+        }
+
+        Ok(Self {
+            path,
+            size,
+            hash: HEXLOWER.encode(&hasher.finalize()),
+        })
+    }
+}
 
-use rand::random;
+impl TempUpload {
+    /// Read the first bytes of the file, used to detect its type
+    pub async fn head(&self) -> Result<Vec<u8>, AppError> {
+        let file = tokio::fs::File::open(&self.path)
+            .await
+            .map_err(storage_error)?;
+        let mut head = Vec::with_capacity(filetype::HEAD_SIZE);
+        file.take(filetype::HEAD_SIZE as u64)
+            .read_to_end(&mut head)
+            .await
+            .map_err(storage_error)?;
+
+        Ok(head)
+    }
+}
 
-/// Upload a file. Returns an `AppError` or the path of the uploaded file.
-/// If `filename` param has a value choose it as filename
+impl Drop for TempUpload {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Upload a file. Returns an `AppError` or the saved file.
+/// The file is streamed into a temporary file, refused as soon as it is bigger than `max_size`,
+/// then it is saved by `save_upload`. The file is named by the digest of its content: it is not
+/// saved again if it already exists
 pub async fn upload(
+    state: &AppState,
     mut multipart: Multipart,
     allowed_extensions: Vec<&str>,
+    max_size: u64,
+) -> Result<SavedUpload, AppError> {
+    let file = match multipart
+        .next_field()
+        .await
+        .map_err(|error| AppError::BadRequest(error.to_string()))?
+    {
+        Some(file) => file,
+        None => return Err(AppError::BadRequest("File is missing".to_string())),
+    };
+
+    let declared = extension_from_content_type(file.content_type().unwrap_or_default());
+    let filename = file.file_name().and_then(filetype::clean_filename);
+
+    if !filetype::accepts(&declared, &allowed_extensions) {
+        return Err(AppError::BadRequest(
+            "File extension not supported".to_string(),
+        ));
+    }
+
+    let temp = receive(state, file, max_size).await?;
+
+    save_upload(state, temp, &declared, filename, &allowed_extensions).await
+}
+
+/// Move a received file into the storage, with the files derived from it. A file which is
+/// already stored is kept
+async fn store_upload(
+    state: &AppState,
+    key: &str,
+    filepath: &str,
+    temp: &TempUpload,
+    is_new: bool,
+    derivatives: Vec<(u32, &str, Vec<u8>)>,
+) -> Result<(), AppError> {
+    if is_new || !state.storage.exists(key).await? {
+        state.storage.put_file(key, &temp.path).await?;
+
+        for (size, format, derivative) in derivatives {
+            UploadDerivative::save(state, filepath, size as i32, format, &derivative).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Release the reference to a saved file which is not used, because a next step failed. A
+/// failure is only logged, so the error of that step is returned
+pub async fn discard_upload(state: &AppState, filepath: &str) {
+    if let Err(error) = delete_upload(state, filepath).await {
+        tracing::warn!("Unused file `{}` not released: {:?}", filepath, error);
+    }
+}
+
+/// Returns the extension declared by a content type: its subtype, like `png` for `image/png`.
+/// A missing content type is like `application/octet-stream`
+pub fn extension_from_content_type(content_type: &str) -> String {
+    let content_type = content_type.split(';').next().unwrap_or_default().trim();
+
+    match content_type.find('/') {
+        Some(index) => content_type[index + 1..].to_lowercase(),
+        None => "octet-stream".to_string(),
+    }
+}
+
+/// Save a received file into the storage, with the files derived from it. The type of the file
+/// is detected from its content, which must match the `declared` content type and the extension
+/// of its `filename`. Images are cleaned of their metadata and resized, 3D files are parsed and
+/// their thumbnails are rendered
+pub async fn save_upload(
+    state: &AppState,
+    mut temp: TempUpload,
+    declared: &str,
     filename: Option<String>,
+    allowed_extensions: &[&str],
+) -> Result<SavedUpload, AppError> {
+    let file_type = filetype::check(
+        &temp.head().await?,
+        temp.size,
+        declared,
+        filename.as_deref(),
+    )?;
+    if !allowed_extensions.contains(&file_type.extension()) {
+        return Err(AppError::BadRequest(
+            "File extension not supported".to_string(),
+        ));
+    }
+    let ext_name = file_type.extension();
+
+    let mut derivatives = vec![];
+    let mut geometry = None;
+
+    // Images are small enough to be processed in memory. The cleaned image replaces the
+    // received one
+    if IMAGE_EXTENSIONS.contains(&ext_name) {
+        let data = tokio::fs::read(&temp.path).await.map_err(storage_error)?;
+        if let Some(image) = variants::process_image(ext_name, data).await? {
+            tokio::fs::write(&temp.path, &image.data)
+                .await
+                .map_err(storage_error)?;
+            temp.size = image.data.len() as u64;
+            temp.hash = sha256::digest_bytes(&image.data);
+            derivatives = image.variants;
+        }
+    }
+
+    let max_size = state.config.max_upload_size;
+    if let Some(analysis) = mesh::analyze(ext_name, temp.path.clone(), max_size).await? {
+        derivatives = analysis
+            .thumbnails
+            .into_iter()
+            .map(|(size, png)| (size, "png", png))
+            .collect();
+        geometry = Some(serde_json::to_value(analysis.geometry).unwrap());
+    }
+
+    let print_info = gcode::analyze(ext_name, temp.path.clone())
+        .await?
+        .map(|info| serde_json::to_value(info).unwrap());
+
+    let key = format!("{}.{}", temp.hash, ext_name);
+    let filepath = format!("{}/{}", state.config.uploads_endpoint, key);
+
+    let is_new = Blob::acquire(state, &key, &temp.hash, temp.size as i64).await?;
+    if let Err(error) = store_upload(state, &key, &filepath, &temp, is_new, derivatives).await {
+        discard_upload(state, &filepath).await;
+        return Err(error);
+    }
+
+    Ok(SavedUpload {
+        filepath,
+        hash: temp.hash.clone(),
+        geometry,
+        filename,
+        size: temp.size as i64,
+        print_info,
+    })
+}
+
+/// Write a multipart field into a temporary file, chunk by chunk, computing its digest. Raises
+/// an `AppError::PayloadTooLarge` as soon as the file is bigger than `max_size`
+pub async fn receive(
+    state: &AppState,
+    mut field: Field<'_>,
+    max_size: u64,
+) -> Result<TempUpload, AppError> {
+    let mut temp = TempUpload {
+        path: TempUpload::new_path(&state.config),
+        size: 0,
+        hash: String::new(),
+    };
+
+    let mut file = tokio::fs::File::create(&temp.path)
+        .await
+        .map_err(storage_error)?;
+    let mut hasher = Sha256::new();
+
+    while let Some(chunk) = field
+        .chunk()
+        .await
+        .map_err(|error| AppError::BadRequest(error.to_string()))?
+    {
+        temp.size += chunk.len() as u64;
+        if temp.size > max_size {
+            return Err(AppError::PayloadTooLarge(max_size));
+        }
+
+        hasher.update(&chunk);
+        file.write_all(&chunk).await.map_err(storage_error)?;
+    }
+
+    file.flush().await.map_err(storage_error)?;
+    temp.hash = HEXLOWER.encode(&hasher.finalize());
+
+    Ok(temp)
+}
+
+/// Returns the key used by the storage for an uploaded file, from its public path
+pub fn storage_key(filename: &str) -> &str {
+    &filename[filename.rfind('/').map_or(0, |index| index + 1)..]
+}
+
+/// Returns the path of an uploaded 3D file converted to another format. Conversions are saved
+/// as files derived from the uploaded one, with size `0`, so they are made once and deleted with
+/// it. A file already in the format is returned as it is
+pub async fn convert_upload(
+    state: &AppState,
+    filepath: &str,
+    format: MeshFormat,
 ) -> Result<String, AppError> {
-    let mut uploaded_file = String::new();
+    let source = filepath
+        .rsplit_once('.')
+        .and_then(|(_, ext)| MeshFormat::from_extension(ext))
+        .ok_or_else(|| AppError::BadRequest("Only 3D files can be converted".to_string()))?;
+    if source == format {
+        return Ok(filepath.to_string());
+    }
+
+    let ext = format.extension();
+    if let Some(converted) = UploadDerivative::find(state, filepath, 0, Some(ext)).await? {
+        if state.storage.exists(storage_key(&converted)).await? {
+            return Ok(converted);
+        }
+    }
 
-    if let Some(file) = multipart.next_field().await.unwrap() {
-        let content_type = file.content_type().unwrap().to_string();
+    // Parsing a mesh takes a while and its memory grows with the file
+    let key = storage_key(filepath);
+    let max_size = state.config.max_convert_size;
+    if state.storage.stat(key).await?.size > max_size {
+        return Err(AppError::PayloadTooLarge(max_size));
+    }
 
-        let index = content_type.find('/').unwrap_or(usize::max_value());
-        let mut ext_name = "xxx";
-        if index != usize::max_value() {
-            ext_name = &content_type[index + 1..];
+    let temp = TempUpload::from_storage(&*state.storage, &state.config, key).await?;
+    let converted = mesh::convert(temp.path.clone(), source, format, max_size).await?;
+
+    UploadDerivative::save(state, filepath, 0, ext, &converted).await
+}
+
+/// Release a reference to an uploaded file. The file, with the files derived from it, is deleted
+/// from the storage when nothing else references it
+pub async fn delete_upload(state: &AppState, filename: &str) -> Result<(), AppError> {
+    if !Blob::release(state, storage_key(filename)).await? {
+        return Ok(());
+    }
+
+    for derivative in UploadDerivative::delete_for(state, filename).await? {
+        if let Err(error) = state.storage.delete(storage_key(&derivative)).await {
+            tracing::warn!("Derived file `{}` not deleted: {:?}", derivative, error);
         }
+    }
+
+    state.storage.delete(storage_key(filename)).await
+}
+
+impl Blob {
+    /// Add a reference to the file `key`. Returns `true` if it is a new file
+    pub async fn acquire(
+        state: &AppState,
+        key: &str,
+        hash: &str,
+        size: i64,
+    ) -> Result<bool, AppError> {
+        let pool = &state.pool;
+
+        let (refcount,): (i32,) = sqlx::query_as(
+            r#"
+                INSERT INTO blobs (key, hash, size, refcount, created, updated)
+                VALUES ( $1, $2, $3, 1, $4, $4)
+                ON CONFLICT (key) DO UPDATE SET refcount = blobs.refcount + 1, updated = $4
+                RETURNING refcount
+            "#,
+        )
+        .bind(key)
+        .bind(hash)
+        .bind(size)
+        .bind(Local::now().naive_utc())
+        .fetch_one(pool)
+        .await?;
+
+        Ok(refcount == 1)
+    }
 
-        if allowed_extensions
+    /// Patterns of the keys of images. Images are cleaned when they are received, so their
+    /// digest is not the one of the file sent: they are never found by hash
+    fn image_keys() -> Vec<String> {
+        IMAGE_EXTENSIONS
             .iter()
-            .any(|&x| x.to_lowercase() == ext_name)
-        {
-            let mut name = match filename {
-                Some(name) => name,
-                None => (random::<f32>() * 1000000000 as f32).to_string(),
-            };
+            .map(|ext| format!("%.{}", ext))
+            .collect()
+    }
 
-            loop {
-                let save_filename = format!("{}/{}.{}", CONFIG.save_file_base_path, name, ext_name);
+    /// Returns the size of the stored file with the digest `hash`, if it exists and it is not an
+    /// image
+    pub async fn size_by_hash(state: &AppState, hash: &str) -> Result<Option<i64>, AppError> {
+        let pool = &state.pool;
 
-                if path::Path::exists(&path::Path::new(&save_filename)) {
-                    name = (random::<f32>() * 1000000000 as f32).to_string();
-                    continue;
-                }
+        let rec: Option<(i64,)> = sqlx::query_as(
+            r#"
+                SELECT size FROM blobs
+                WHERE hash = $1 AND refcount > 0 AND key NOT LIKE ALL($2)
+                LIMIT 1
+            "#,
+        )
+        .bind(hash)
+        .bind(Self::image_keys())
+        .fetch_optional(pool)
+        .await?;
 
-                uploaded_file = format!("{}/{}.{}", CONFIG.uploads_endpoint, name, ext_name);
+        Ok(rec.map(|(size,)| size))
+    }
 
-                let data = file.bytes().await.unwrap();
+    /// Add a reference to an already stored file with the digest `hash`. Returns its public path,
+    /// or `None` if there is no file with that digest which is not an image
+    pub async fn retain_by_hash(state: &AppState, hash: &str) -> Result<Option<String>, AppError> {
+        let pool = &state.pool;
 
-                tokio::fs::write(&save_filename, &data)
-                    .await
-                    .map_err(|err| err.to_string())?;
-                break;
+        let rec: Option<(String,)> = sqlx::query_as(
+            r#"
+                UPDATE blobs SET refcount = refcount + 1, updated = $3
+                WHERE key = (
+                    SELECT key FROM blobs
+                    WHERE hash = $1 AND refcount > 0 AND key NOT LIKE ALL($2)
+                    LIMIT 1
+                )
+                RETURNING key
+            "#,
+        )
+        .bind(hash)
+        .bind(Self::image_keys())
+        .bind(Local::now().naive_utc())
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(rec.map(|(key,)| format!("{}/{}", state.config.uploads_endpoint, key)))
+    }
+
+    /// Remove a reference to the file `key`. Returns `true` if the file is not referenced anymore
+    /// and it can be deleted. Files saved before the content addressing are not counted, so they
+    /// can always be deleted
+    pub async fn release(state: &AppState, key: &str) -> Result<bool, AppError> {
+        let pool = &state.pool;
+
+        let rec: Option<(i32,)> = sqlx::query_as(
+            r#"
+                UPDATE blobs SET refcount = refcount - 1, updated = $2 WHERE key = $1
+                RETURNING refcount
+            "#,
+        )
+        .bind(key)
+        .bind(Local::now().naive_utc())
+        .fetch_optional(pool)
+        .await?;
+
+        match rec {
+            Some((refcount,)) if refcount > 0 => Ok(false),
+            Some(_) => {
+                sqlx::query(r#"DELETE FROM blobs WHERE key = $1 AND refcount <= 0"#)
+                    .bind(key)
+                    .execute(pool)
+                    .await?;
+
+                Ok(true)
             }
+            None => Ok(true),
         }
     }
+}
+
+impl UploadDerivative {
+    /// Save a file derived from `source`, replacing the previous one with the same size and
+    /// format. Returns its path
+    pub async fn save(
+        state: &AppState,
+        source: &str,
+        size: i32,
+        format: &str,
+        data: &[u8],
+    ) -> Result<String, AppError> {
+        let pool = &state.pool;
 
-    if !uploaded_file.is_empty() {
-        return Ok(uploaded_file);
+        let key = format!("{}.{}.{}", storage_key(source), size, format);
+        let filepath = format!("{}/{}", state.config.uploads_endpoint, key);
+        state.storage.put(&key, data).await?;
+
+        sqlx::query(
+            r#"
+                INSERT INTO upload_derivatives (source, size, format, filepath, created)
+                VALUES ( $1, $2, $3, $4, $5)
+                ON CONFLICT (source, size, format) DO UPDATE
+                SET filepath = EXCLUDED.filepath, created = EXCLUDED.created
+            "#,
+        )
+        .bind(source)
+        .bind(size)
+        .bind(format)
+        .bind(&filepath)
+        .bind(Local::now().naive_utc())
+        .execute(pool)
+        .await?;
+
+        Ok(filepath)
+    }
+
+    /// Returns the path of the file derived from `source` with the given size and format, if it
+    /// exists
+    pub async fn find(
+        state: &AppState,
+        source: &str,
+        size: i32,
+        format: Option<&str>,
+    ) -> Result<Option<String>, AppError> {
+        let pool = &state.pool;
+
+        let rec: Option<(String,)> = sqlx::query_as(
+            r#"
+                SELECT filepath FROM upload_derivatives
+                WHERE source = $1 AND size = $2 AND ($3::varchar IS NULL OR format = $3)
+                ORDER BY format = 'webp', id LIMIT 1
+            "#,
+        )
+        .bind(source)
+        .bind(size)
+        .bind(format)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(rec.map(|(filepath,)| filepath))
     }
 
-    Err(AppError::BadRequest(
-        "File extension not supported".to_string(),
-    ))
+    /// Delete the rows of the files derived from `source`, returning their paths
+    pub async fn delete_for(state: &AppState, source: &str) -> Result<Vec<String>, AppError> {
+        let pool = &state.pool;
+
+        let rows: Vec<(String,)> = sqlx::query_as(
+            r#"
+                DELETE FROM upload_derivatives WHERE source = $1
+                RETURNING filepath
+            "#,
+        )
+        .bind(source)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows.into_iter().map(|(filepath,)| filepath).collect())
+    }
+}
+
+/// Axum endpoint which shows uploaded file. With `?size=` it shows the file derived from it
+/// with that size, like the thumbnail of a mesh or a resized image. `?format=` chooses the
+/// format of the derived file, like `webp`. With `?upload=` the file is named as the model upload
+/// with that id; otherwise it is named by its digest, since other users can upload it too.
+/// It answers the conditional requests with `If-None-Match` and `If-Modified-Since`, and the
+/// requests of a single byte range. Files are streamed by chunks
+pub async fn show_uploads(
+    Extension(state): Extension<AppState>,
+    Path(id): Path<String>,
+    Query(variant): Query<UploadVariant>,
+    method: Method,
+    request_headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let source = format!("{}/{}", state.config.uploads_endpoint, id);
+    let id = match variant.size {
+        Some(size) => {
+            match UploadDerivative::find(&state, &source, size, variant.format.as_deref()).await? {
+                Some(filepath) => storage_key(&filepath).to_string(),
+                None => return Err(AppError::NotFound("Size not found".to_string())),
+            }
+        }
+        None => id,
+    };
+
+    let disposition = match variant.size {
+        Some(_) => None,
+        None => {
+            let filename = match variant.upload {
+                Some(upload_id) => original_filename(&state, &source, upload_id).await?,
+                None => None,
+            };
+            Some(content_disposition(
+                "inline",
+                filename.as_deref().unwrap_or(&id),
+            ))
+        }
+    };
+
+    serve_file(&state, id, disposition, &method, &request_headers).await
 }
 
-/// Delete a file from the filesystem
-pub fn delete_upload(filename: &str) -> Result<(), AppError> {
-    let last_slash_index = filename.rfind('/').unwrap();
-    let path = format!(
-        "{}/{}",
-        CONFIG.save_file_base_path,
-        &filename[last_slash_index + 1..]
+/// Send the stored file `key`, with the value of its `Content-Disposition` header. It answers the
+/// conditional requests and the requests of a single byte range
+pub async fn serve_file(
+    state: &AppState,
+    key: String,
+    disposition: Option<String>,
+    method: &Method,
+    request_headers: &HeaderMap,
+) -> Result<Response, AppError> {
+    let info = state.storage.stat(&key).await?;
+    let etag = format!("\"{:x}-{:x}\"", info.size, info.modified.timestamp());
+
+    // Files are saved with the extension of the type detected from their content
+    let content_type = key
+        .rsplit_once('.')
+        .and_then(|(_, ext)| FileType::from_extension(ext))
+        .map_or("application/octet-stream", |file_type| file_type.mime());
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    headers.insert(header::ETAG, HeaderValue::from_str(&etag).unwrap());
+    headers.insert(
+        header::LAST_MODIFIED,
+        HeaderValue::from_str(&http_date(info.modified)).unwrap(),
+    );
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    // A file named by the digest of its content never changes. Older files, like the avatars,
+    // could be replaced
+    headers.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static(if is_content_addressed(&key) {
+            "public, max-age=31536000, immutable"
+        } else {
+            "no-cache"
+        }),
     );
+    if let Some(disposition) = disposition {
+        headers.insert(
+            header::CONTENT_DISPOSITION,
+            HeaderValue::from_str(&disposition).unwrap(),
+        );
+    }
 
-    fs::remove_file(path)?;
+    if is_not_modified(request_headers, &etag, info.modified) {
+        return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
+    }
 
-    Ok(())
+    let mut status = StatusCode::OK;
+    let mut range = match info.size {
+        0 => None,
+        size => Some((0, size - 1)),
+    };
+
+    let requested = request_headers
+        .get(header::RANGE)
+        .and_then(|x| x.to_str().ok())
+        .filter(|_| if_range_matches(request_headers, &etag, info.modified))
+        .and_then(|x| parse_range(x, info.size));
+    match requested {
+        Some(Ok((start, end))) => {
+            status = StatusCode::PARTIAL_CONTENT;
+            range = Some((start, end));
+            headers.insert(
+                header::CONTENT_RANGE,
+                HeaderValue::from_str(&format!("bytes {}-{}/{}", start, end, info.size)).unwrap(),
+            );
+        }
+        Some(Err(())) => {
+            headers.insert(
+                header::CONTENT_RANGE,
+                HeaderValue::from_str(&format!("bytes */{}", info.size)).unwrap(),
+            );
+            return Ok((StatusCode::RANGE_NOT_SATISFIABLE, headers).into_response());
+        }
+        None => {}
+    }
+
+    let length = range.map_or(0, |(start, end)| end + 1 - start);
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(length));
+
+    let (mut sender, body) = Body::channel();
+    if let (Some((start, end)), false) = (range, method == Method::HEAD) {
+        let storage = state.storage.clone();
+        tokio::spawn(async move {
+            let mut position = start;
+            while position <= end {
+                let chunk_end = end.min(position + STREAM_CHUNK_SIZE - 1);
+                match storage.get_range(&key, position, chunk_end).await {
+                    Ok(data) => {
+                        // The client went away
+                        if sender.send_data(data.into()).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(_) => {
+                        sender.abort();
+                        break;
+                    }
+                }
+                position = chunk_end + 1;
+            }
+        });
+    }
+
+    Ok((status, headers, boxed(body)).into_response())
+}
+
+/// A key starting with the SHA-256 digest of the content
+fn is_content_addressed(key: &str) -> bool {
+    key.len() > 64
+        && key.as_bytes()[..64].iter().all(u8::is_ascii_hexdigit)
+        && key.as_bytes()[64] == b'.'
 }
 
-/// Axum endpoint which shows uploaded file
-pub async fn show_uploads(Path(id): Path<String>) -> (HeaderMap, Vec<u8>) {
-    let index = id.find('.').unwrap_or(usize::max_value());
+/// Returns the name of an uploaded file on the computer of the user who sent it, from the model
+/// upload `upload_id` of the file
+async fn original_filename(
+    state: &AppState,
+    filepath: &str,
+    upload_id: i32,
+) -> Result<Option<String>, AppError> {
+    let pool = &state.pool;
+
+    let rec: Option<(String,)> = sqlx::query_as(
+        r#"
+            SELECT filename FROM uploads
+            WHERE id = $1 AND filepath = $2 AND filename IS NOT NULL
+        "#,
+    )
+    .bind(upload_id)
+    .bind(filepath)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(rec.map(|(filename,)| filename))
+}
 
-    let mut ext_name = "xxx";
-    if index != usize::max_value() {
-        ext_name = &id[index + 1..];
+/// Value of the `Content-Disposition` header for a file name, with a disposition like `inline`
+/// or `attachment`. Old clients read the ASCII `filename`, the others the UTF-8 `filename*`
+pub fn content_disposition(disposition: &str, filename: &str) -> String {
+    let ascii: String = filename
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect();
+    let encoded: String = filename
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect();
+
+    format!(
+        "{}; filename=\"{}\"; filename*=UTF-8''{}",
+        disposition, ascii, encoded
+    )
+}
+
+/// Returns `true` if the copy of the client is still good. `If-None-Match` wins on
+/// `If-Modified-Since`
+fn is_not_modified(headers: &HeaderMap, etag: &str, modified: NaiveDateTime) -> bool {
+    if let Some(value) = headers.get(header::IF_NONE_MATCH) {
+        return value.to_str().unwrap_or_default().split(',').any(|x| {
+            let x = x.trim();
+            x == "*" || x.trim_start_matches("W/") == etag
+        });
     }
-    let mut headers = HeaderMap::new();
 
-    if vec!["jpg", "jpeg", "png", "gif", "webp"]
-        .iter()
-        .any(|&x| x == ext_name)
-    {
-        let content_type = format!("image/{}", ext_name);
-        headers.insert(
-            HeaderName::from_static("content-type"),
-            HeaderValue::from_str(&content_type).unwrap(),
+    headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|x| x.to_str().ok())
+        .and_then(parse_http_date)
+        .is_some_and(|since| modified.timestamp() <= since.timestamp())
+}
+
+/// A range is sent only if the file did not change since the client got a part of it. Without
+/// `If-Range`, it is always sent
+fn if_range_matches(headers: &HeaderMap, etag: &str, modified: NaiveDateTime) -> bool {
+    match headers.get(header::IF_RANGE).and_then(|x| x.to_str().ok()) {
+        Some(value) if value.trim().starts_with('"') => value.trim() == etag,
+        Some(value) => {
+            parse_http_date(value).is_some_and(|date| modified.timestamp() <= date.timestamp())
+        }
+        None => true,
+    }
+}
+
+/// Parse a `Range` header for a file of `size` bytes. Returns `None` if it is ignored, like a
+/// multiple range, `Some(Err(()))` if it can not be satisfied, else the first and the last byte
+fn parse_range(value: &str, size: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = value.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+
+    if start.is_empty() {
+        // The last `end` bytes
+        let suffix: u64 = end.parse().ok()?;
+        if suffix == 0 || size == 0 {
+            return Some(Err(()));
+        }
+        return Some(Ok((size.saturating_sub(suffix), size - 1)));
+    }
+
+    let start: u64 = start.parse().ok()?;
+    let end: u64 = match end {
+        "" => u64::MAX,
+        end => end.parse().ok()?,
+    };
+
+    if end < start {
+        return None;
+    }
+    if start >= size {
+        return Some(Err(()));
+    }
+
+    Some(Ok((start, end.min(size - 1))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_range_of_bytes() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some(Ok((0, 99))));
+        assert_eq!(parse_range(" bytes=10 - 10 ", 1000), Some(Ok((10, 10))));
+        // The end is cut to the size
+        assert_eq!(parse_range("bytes=900-2000", 1000), Some(Ok((900, 999))));
+    }
+
+    #[test]
+    fn parse_range_open_ended() {
+        assert_eq!(parse_range("bytes=100-", 1000), Some(Ok((100, 999))));
+        assert_eq!(parse_range("bytes=999-", 1000), Some(Ok((999, 999))));
+    }
+
+    #[test]
+    fn parse_range_suffix() {
+        assert_eq!(parse_range("bytes=-100", 1000), Some(Ok((900, 999))));
+        // A suffix longer than the file is the whole file
+        assert_eq!(parse_range("bytes=-5000", 1000), Some(Ok((0, 999))));
+    }
+
+    #[test]
+    fn parse_range_unsatisfiable() {
+        assert_eq!(parse_range("bytes=1000-", 1000), Some(Err(())));
+        assert_eq!(parse_range("bytes=2000-3000", 1000), Some(Err(())));
+        assert_eq!(parse_range("bytes=-0", 1000), Some(Err(())));
+        assert_eq!(parse_range("bytes=0-", 0), Some(Err(())));
+        assert_eq!(parse_range("bytes=-10", 0), Some(Err(())));
+    }
+
+    #[test]
+    fn parse_range_ignored() {
+        for value in [
+            "bytes=0-10,20-30",
+            "items=0-10",
+            "bytes=10-5",
+            "bytes=a-b",
+            "bytes=10",
+            "bytes=-",
+        ] {
+            assert_eq!(parse_range(value, 1000), None, "{}", value);
+        }
+    }
+
+    #[test]
+    fn conditional_requests() {
+        let modified = parse_http_date("Wed, 02 Nov 2022 10:00:00 GMT").unwrap();
+        let etag = "\"3e8-6362404a\"";
+        let headers = |name, value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(name, HeaderValue::from_str(value).unwrap());
+            headers
+        };
+
+        let if_none_match =
+            |value| is_not_modified(&headers(header::IF_NONE_MATCH, value), etag, modified);
+        assert!(if_none_match("\"3e8-6362404a\""));
+        assert!(if_none_match("\"other\", W/\"3e8-6362404a\""));
+        assert!(if_none_match("*"));
+        assert!(!if_none_match("\"other\""));
+
+        let since =
+            |value| is_not_modified(&headers(header::IF_MODIFIED_SINCE, value), etag, modified);
+        assert!(since("Wed, 02 Nov 2022 10:00:00 GMT"));
+        assert!(!since("Wed, 02 Nov 2022 09:59:59 GMT"));
+        assert!(!is_not_modified(&HeaderMap::new(), etag, modified));
+
+        let if_range = |value| if_range_matches(&headers(header::IF_RANGE, value), etag, modified);
+        assert!(if_range(etag));
+        assert!(!if_range("\"other\""));
+        assert!(if_range("Wed, 02 Nov 2022 10:00:00 GMT"));
+        assert!(!if_range("Tue, 01 Nov 2022 10:00:00 GMT"));
+        assert!(if_range_matches(&HeaderMap::new(), etag, modified));
+    }
+
+    #[test]
+    fn content_disposition_of_any_name() {
+        assert_eq!(
+            content_disposition("inline", "cube.stl"),
+            "inline; filename=\"cube.stl\"; filename*=UTF-8''cube.stl"
+        );
+        assert_eq!(
+            content_disposition("attachment", "dé \"1\".stl"),
+            "attachment; filename=\"d_ _1_.stl\"; filename*=UTF-8''d%C3%A9%20%221%22.stl"
         );
     }
-    let file_name = format!("{}/{}", CONFIG.save_file_base_path, id);
-    (headers, fs::read(&file_name).unwrap())
+
+    #[test]
+    fn content_addressed_keys() {
+        let digest = "a".repeat(64);
+
+        assert!(is_content_addressed(&format!("{}.stl", digest)));
+        assert!(!is_content_addressed(&digest));
+        assert!(!is_content_addressed("avatar-12.png"));
+        assert!(!is_content_addressed(&format!("{}x.stl", "g".repeat(63))));
+    }
 }
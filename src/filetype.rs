@@ -0,0 +1,406 @@
+use crate::errors::AppError;
+
+/// Bytes read from the start of a file to detect its type
+pub const HEAD_SIZE: usize = 4096;
+
+/// Types of the files which can be uploaded, detected from their content
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FileType {
+    Png,
+    Jpeg,
+    Gif,
+    Webp,
+    Stl,
+    Obj,
+    ThreeMf,
+    Blend,
+    Gcode,
+    Bgcode,
+}
+
+impl FileType {
+    /// Returns the type of a file from its extension or from the subtype of its content type.
+    /// `sla` is the subtype of the `application/sla` STL content type, `x.gcode` of the
+    /// `text/x.gcode` G-code one
+    pub fn from_extension(ext: &str) -> Option<Self> {
+        match ext.to_lowercase().as_str() {
+            "png" => Some(FileType::Png),
+            "jpg" | "jpeg" => Some(FileType::Jpeg),
+            "gif" => Some(FileType::Gif),
+            "webp" => Some(FileType::Webp),
+            "stl" | "sla" => Some(FileType::Stl),
+            "obj" => Some(FileType::Obj),
+            "3mf" => Some(FileType::ThreeMf),
+            "blend" => Some(FileType::Blend),
+            "gcode" | "gco" | "x.gcode" | "x-gcode" => Some(FileType::Gcode),
+            "bgcode" | "x-bgcode" => Some(FileType::Bgcode),
+            _ => None,
+        }
+    }
+
+    /// Extension used to save a file of this type
+    pub fn extension(&self) -> &'static str {
+        match self {
+            FileType::Png => "png",
+            FileType::Jpeg => "jpg",
+            FileType::Gif => "gif",
+            FileType::Webp => "webp",
+            FileType::Stl => "stl",
+            FileType::Obj => "obj",
+            FileType::ThreeMf => "3mf",
+            FileType::Blend => "blend",
+            FileType::Gcode => "gcode",
+            FileType::Bgcode => "bgcode",
+        }
+    }
+
+    /// Content type used to show a file of this type
+    pub fn mime(&self) -> &'static str {
+        match self {
+            FileType::Png => "image/png",
+            FileType::Jpeg => "image/jpeg",
+            FileType::Gif => "image/gif",
+            FileType::Webp => "image/webp",
+            FileType::Stl => "model/stl",
+            FileType::Obj => "model/obj",
+            FileType::ThreeMf => "model/3mf",
+            FileType::Blend => "application/x-blender",
+            FileType::Gcode => "text/x.gcode",
+            FileType::Bgcode => "application/x-bgcode",
+        }
+    }
+}
+
+/// Detect the type of a file from its first bytes, `head`, and its size
+pub fn detect(head: &[u8], len: u64) -> Option<FileType> {
+    if head.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return Some(FileType::Png);
+    }
+    if head.starts_with(b"\xff\xd8\xff") {
+        return Some(FileType::Jpeg);
+    }
+    if head.starts_with(b"GIF87a") || head.starts_with(b"GIF89a") {
+        return Some(FileType::Gif);
+    }
+    if head.len() >= 12 && head.starts_with(b"RIFF") && &head[8..12] == b"WEBP" {
+        return Some(FileType::Webp);
+    }
+    if head.starts_with(b"BLENDER") {
+        return Some(FileType::Blend);
+    }
+    if head.starts_with(b"GCDE") {
+        return Some(FileType::Bgcode);
+    }
+    // A ZIP archive: the parser checks that it has a 3D model inside
+    if head.starts_with(b"PK\x03\x04") {
+        return Some(FileType::ThreeMf);
+    }
+
+    let text = is_text(head);
+
+    // The size of a binary STL is given by the number of triangles after its 80 bytes header.
+    // Some exporters add bytes after the triangles
+    if head.len() >= 84 {
+        let count = u32::from_le_bytes(head[80..84].try_into().unwrap());
+        let size = 84 + count as u64 * 50;
+        if len == size || (!text && count > 0 && len > size) {
+            return Some(FileType::Stl);
+        }
+    }
+
+    if !text {
+        return None;
+    }
+    if head.trim_ascii_start().starts_with(b"solid") {
+        return Some(FileType::Stl);
+    }
+    if is_obj(head) {
+        return Some(FileType::Obj);
+    }
+    if is_gcode(head) {
+        return Some(FileType::Gcode);
+    }
+
+    None
+}
+
+/// A text file has no control chars except the whitespaces. The head can cut a UTF-8 char
+fn is_text(head: &[u8]) -> bool {
+    let text = match std::str::from_utf8(head) {
+        Ok(text) => text,
+        Err(error) if error.error_len().is_none() => {
+            std::str::from_utf8(&head[..error.valid_up_to()]).unwrap()
+        }
+        Err(_) => return false,
+    };
+
+    !text.is_empty()
+        && text
+            .chars()
+            .all(|c| !c.is_control() || c.is_ascii_whitespace())
+}
+
+/// An OBJ file is made of lines starting with a keyword. The last line of the head can be cut,
+/// so it is not checked
+fn is_obj(head: &[u8]) -> bool {
+    let text = String::from_utf8_lossy(head);
+    let mut lines = text.lines().collect::<Vec<&str>>();
+    if head.len() == HEAD_SIZE {
+        lines.pop();
+    }
+
+    let mut statements = lines
+        .iter()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .peekable();
+
+    statements.peek().is_some()
+        && statements.all(|line| {
+            matches!(
+                line.split_ascii_whitespace().next(),
+                Some(
+                    "v" | "vt"
+                        | "vn"
+                        | "vp"
+                        | "f"
+                        | "l"
+                        | "p"
+                        | "o"
+                        | "g"
+                        | "s"
+                        | "mtllib"
+                        | "usemtl"
+                )
+            )
+        })
+}
+
+/// A G-code file is made of commands, like `G1 X10` or `M104 S200`, and of `;` comments. Macros
+/// of Klipper, like `PRINT_START`, are uppercase words. The last line of the head can be cut
+fn is_gcode(head: &[u8]) -> bool {
+    let text = String::from_utf8_lossy(head);
+    let mut lines = text.lines().collect::<Vec<&str>>();
+    if head.len() == HEAD_SIZE {
+        lines.pop();
+    }
+
+    let mut statements = lines
+        .iter()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .peekable();
+
+    statements.peek().is_some()
+        && statements.all(|line| {
+            let word = line
+                .split(|c: char| c.is_ascii_whitespace() || c == ';')
+                .next()
+                .unwrap_or_default();
+            let mut chars = word.chars();
+
+            line.starts_with(';')
+                || match chars.next() {
+                    Some('G' | 'M' | 'T' | 'N' | 'g' | 'm' | 't' | 'n') => {
+                        chars.next().is_some_and(|c| c.is_ascii_digit())
+                    }
+                    _ => {
+                        word.len() > 1
+                            && word
+                                .chars()
+                                .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+                    }
+                }
+        })
+}
+
+/// Returns `true` if a file declared with the content type subtype `declared` can be one of the
+/// `allowed` extensions
+pub fn accepts(declared: &str, allowed: &[&str]) -> bool {
+    declared == "octet-stream"
+        || FileType::from_extension(declared).is_some_and(|x| allowed.contains(&x.extension()))
+}
+
+/// Checks that the content of a file matches the types it is declared with: the subtype of its
+/// content type and the extension of its name. A generic content type, like
+/// `application/octet-stream`, declares nothing. Returns the type detected from the content
+pub fn check(
+    head: &[u8],
+    len: u64,
+    declared: &str,
+    filename: Option<&str>,
+) -> Result<FileType, AppError> {
+    let detected = match detect(head, len) {
+        Some(detected) => detected,
+        None => return Err(AppError::BadRequest("File type not supported".to_string())),
+    };
+
+    let mut declarations = vec![];
+    if declared != "octet-stream" {
+        declarations.push(
+            FileType::from_extension(declared)
+                .ok_or_else(|| AppError::BadRequest("File extension not supported".to_string()))?,
+        );
+    }
+    if let Some(ext) = filename
+        .and_then(|x| x.rsplit_once('.'))
+        .map(|(_, ext)| ext)
+    {
+        declarations.extend(FileType::from_extension(ext));
+    }
+
+    if declarations
+        .iter()
+        .any(|&declaration| declaration != detected)
+    {
+        return Err(AppError::BadRequest(
+            "The content of the file does not match its type".to_string(),
+        ));
+    }
+
+    Ok(detected)
+}
+
+/// Clean the name of an uploaded file: only the last component of a path is kept, without
+/// control chars
+pub fn clean_filename(filename: &str) -> Option<String> {
+    let name = filename
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(255)
+        .collect::<String>();
+
+    let name = name.trim();
+    if name.is_empty() || name == "." || name == ".." {
+        None
+    } else {
+        Some(name.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Head of a binary STL with `count` triangles, and the size of the file
+    fn binary_stl(header: &[u8], count: u32) -> (Vec<u8>, u64) {
+        let mut head = header.to_vec();
+        head.resize(80, b' ');
+        head.extend(count.to_le_bytes());
+
+        (head, 84 + count as u64 * 50)
+    }
+
+    #[test]
+    fn detect_by_signature() {
+        let cases: [(&[u8], FileType); 7] = [
+            (b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR", FileType::Png),
+            (b"\xff\xd8\xff\xe0\0\x10JFIF", FileType::Jpeg),
+            (b"GIF89a\x01\0\x01\0", FileType::Gif),
+            (b"RIFF\x24\0\0\0WEBPVP8 ", FileType::Webp),
+            (b"BLENDER-v300", FileType::Blend),
+            (b"GCDE\x01\0\0\0", FileType::Bgcode),
+            (b"PK\x03\x04\x14\0\0\0", FileType::ThreeMf),
+        ];
+
+        for (head, file_type) in cases {
+            assert_eq!(detect(head, 1000), Some(file_type));
+        }
+        assert_eq!(detect(b"RIFF\x24\0\0\0WAVEfmt ", 1000), None);
+        assert_eq!(detect(b"\0\x01\x02\x03", 4), None);
+    }
+
+    #[test]
+    fn detect_stl() {
+        let ascii = b"solid cube\n  facet normal 0 0 1\n    outer loop\n";
+        assert_eq!(detect(ascii, 1000), Some(FileType::Stl));
+
+        let (head, len) = binary_stl(b"\x01\x02 binary", 12);
+        assert_eq!(detect(&head, len), Some(FileType::Stl));
+        // Exporters can write `solid` in the header of a binary file
+        let (head, len) = binary_stl(b"solid exported", 12);
+        assert_eq!(detect(&head, len), Some(FileType::Stl));
+        // Bytes after the triangles
+        let (head, len) = binary_stl(b"\x01\x02 binary", 12);
+        assert_eq!(detect(&head, len + 10), Some(FileType::Stl));
+    }
+
+    #[test]
+    fn detect_text_files() {
+        let obj = b"# cube\nmtllib cube.mtl\no cube\nv 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\n";
+        assert_eq!(detect(obj, obj.len() as u64), Some(FileType::Obj));
+
+        let gcode = b"; generated\nG28\nM104 S200 ; hotend\nPRINT_START\nG1 X10 Y10\n";
+        assert_eq!(detect(gcode, gcode.len() as u64), Some(FileType::Gcode));
+
+        let text = b"Some notes\nabout the model\n";
+        assert_eq!(detect(text, text.len() as u64), None);
+    }
+
+    #[test]
+    fn detect_ignores_a_cut_last_line() {
+        // The head ends with `vt` cut to `vx`, which is not a keyword
+        let mut head = b"v 0 0 0\n".repeat(HEAD_SIZE / 8 - 1);
+        head.extend(b"v 1 0 0\n");
+        head.truncate(HEAD_SIZE - 3);
+        head.extend(b"\nvx");
+
+        assert_eq!(head.len(), HEAD_SIZE);
+        assert_eq!(detect(&head, 100_000), Some(FileType::Obj));
+        // A whole file is checked to its end
+        assert_eq!(detect(b"v 0 0 0\nvx", 100_000), None);
+    }
+
+    #[test]
+    fn check_the_declared_types() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+        assert_eq!(
+            check(png, 100, "png", Some("a.png")).ok(),
+            Some(FileType::Png)
+        );
+        assert_eq!(
+            check(png, 100, "octet-stream", Some("a.PNG")).ok(),
+            Some(FileType::Png)
+        );
+        assert_eq!(
+            check(png, 100, "octet-stream", None).ok(),
+            Some(FileType::Png)
+        );
+        // An unknown extension of the name declares nothing
+        assert_eq!(
+            check(png, 100, "png", Some("a.tmp")).ok(),
+            Some(FileType::Png)
+        );
+
+        assert!(check(png, 100, "jpeg", Some("a.png")).is_err());
+        assert!(check(png, 100, "png", Some("a.stl")).is_err());
+        assert!(check(png, 100, "x-unknown", None).is_err());
+        assert!(check(b"plain text", 10, "octet-stream", None).is_err());
+    }
+
+    #[test]
+    fn accepts_the_allowed_extensions() {
+        assert!(accepts("sla", &["stl", "png"]));
+        assert!(accepts("octet-stream", &["stl"]));
+        assert!(!accepts("gif", &["stl", "png"]));
+        assert!(!accepts("x-unknown", &["stl"]));
+    }
+
+    #[test]
+    fn clean_filename_keeps_the_name() {
+        assert_eq!(
+            clean_filename("C:\\models\\cube.stl").as_deref(),
+            Some("cube.stl")
+        );
+        assert_eq!(
+            clean_filename("../../cube\n.stl").as_deref(),
+            Some("cube.stl")
+        );
+        assert_eq!(clean_filename("models/.."), None);
+        assert_eq!(clean_filename("  "), None);
+    }
+}
@@ -0,0 +1,473 @@
+use crate::errors::AppError;
+use flate2::read::ZlibDecoder;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader, ErrorKind, Read},
+    path::PathBuf,
+};
+
+/// Diameter of the filament, in millimeters, used when the slicer does not write it
+const DEFAULT_FILAMENT_DIAMETER: f64 = 1.75;
+/// Density of PLA, in grams per cubic centimeter, used when the slicer does not write it
+const DEFAULT_FILAMENT_DENSITY: f64 = 1.24;
+/// Biggest metadata block of a binary G-code which is read
+const MAX_BLOCK_SIZE: u32 = 16 * 1024 * 1024;
+
+/// Values read from the comments a slicer writes into a G-code file, saved on the upload row
+#[derive(Default, Serialize, Deserialize)]
+pub struct PrintInfo {
+    /// Slicer which generated the file, like "PrusaSlicer"
+    pub slicer: Option<String>,
+    /// Estimated print time, in seconds
+    pub duration: Option<f64>,
+    /// Filament used, in millimeters
+    pub filament_length: Option<f64>,
+    /// Filament used, in grams. If the slicer does not write it, it is estimated from the length
+    pub filament_weight: Option<f64>,
+    /// In millimeters
+    pub layer_height: Option<f64>,
+    /// In millimeters
+    pub nozzle_diameter: Option<f64>,
+    pub printer: Option<String>,
+    pub material: Option<String>,
+}
+
+/// Settings read from a G-code file, by lowercase key. The first value of a key wins
+type Settings = HashMap<String, String>;
+
+/// Read the settings of a G-code file from the comments written by PrusaSlicer, OrcaSlicer and
+/// Cura: `; key = value` and `;KEY:value`. The file is read line by line
+pub fn parse_text<R: BufRead>(mut reader: R) -> Result<PrintInfo, AppError> {
+    let mut settings = Settings::new();
+    let mut line = vec![];
+
+    while reader.read_until(b'\n', &mut line)? > 0 {
+        let text = String::from_utf8_lossy(&line);
+        if let Some(comment) = text.trim_start().strip_prefix(';') {
+            add_comment(&mut settings, comment);
+        }
+        line.clear();
+    }
+
+    Ok(PrintInfo::from_settings(&settings))
+}
+
+/// Add the settings of a comment. OrcaSlicer writes more of them on a line, split by `;`
+fn add_comment(settings: &mut Settings, comment: &str) {
+    let comment = comment.trim();
+    let lowercase = comment.to_lowercase();
+
+    for prefix in ["generated by ", "generated with "] {
+        if lowercase.starts_with(prefix) {
+            add_setting(settings, "producer", &comment[prefix.len()..]);
+            return;
+        }
+    }
+
+    for segment in comment.split(';') {
+        if let Some(index) = segment.find(['=', ':']) {
+            add_setting(settings, &segment[..index], &segment[index + 1..]);
+        }
+    }
+}
+
+fn add_setting(settings: &mut Settings, key: &str, value: &str) {
+    let key = key.trim().to_lowercase();
+    let value = value.trim().trim_matches('"').trim();
+
+    if !key.is_empty() && !value.is_empty() {
+        settings.entry(key).or_insert_with(|| value.to_string());
+    }
+}
+
+/// Read the settings of a binary G-code file, the `.bgcode` of PrusaSlicer. The metadata blocks
+/// come before the G-code ones and hold `key=value` lines; the G-code is not read
+pub fn parse_binary<R: Read>(mut reader: R) -> Result<PrintInfo, AppError> {
+    let error = || AppError::BadRequest("Invalid binary G-code file".to_string());
+
+    let mut header = [0; 10];
+    reader.read_exact(&mut header).map_err(|_| error())?;
+    if &header[..4] != b"GCDE" {
+        return Err(error());
+    }
+    let checksum_size = match u16::from_le_bytes([header[8], header[9]]) {
+        0 => 0,
+        // CRC32
+        1 => 4,
+        _ => return Err(error()),
+    };
+
+    let mut settings = Settings::new();
+    loop {
+        let mut block = [0; 8];
+        match reader.read_exact(&mut block) {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(_) => return Err(error()),
+        }
+
+        let kind = u16::from_le_bytes([block[0], block[1]]);
+        let compression = u16::from_le_bytes([block[2], block[3]]);
+        let size = u32::from_le_bytes(block[4..8].try_into().unwrap());
+        let compressed_size = match compression {
+            0 => size,
+            _ => {
+                let mut value = [0; 4];
+                reader.read_exact(&mut value).map_err(|_| error())?;
+                u32::from_le_bytes(value)
+            }
+        };
+
+        // G-code blocks: the metadata is over
+        if kind == 1 {
+            break;
+        }
+
+        // File, slicer, printer and print metadata, encoded as INI. Thumbnails are skipped
+        let is_metadata = matches!(kind, 0 | 2 | 3 | 4);
+        let parameters_size = if kind == 5 { 6 } else { 2 };
+
+        let mut parameters = vec![0; parameters_size];
+        reader.read_exact(&mut parameters).map_err(|_| error())?;
+
+        if is_metadata && compressed_size <= MAX_BLOCK_SIZE && size <= MAX_BLOCK_SIZE {
+            let mut data = vec![0; compressed_size as usize];
+            reader.read_exact(&mut data).map_err(|_| error())?;
+
+            let text = match compression {
+                0 => data,
+                1 => {
+                    let mut text = Vec::with_capacity(size as usize);
+                    ZlibDecoder::new(&data[..])
+                        .take(size as u64)
+                        .read_to_end(&mut text)
+                        .map_err(|_| error())?;
+                    text
+                }
+                // Heatshrink is used only by the G-code blocks
+                _ => vec![],
+            };
+
+            for line in String::from_utf8_lossy(&text).lines() {
+                if let Some((key, value)) = line.split_once('=') {
+                    add_setting(&mut settings, key, value);
+                }
+            }
+        } else {
+            let skipped = std::io::copy(
+                &mut (&mut reader).take(compressed_size as u64),
+                &mut std::io::sink(),
+            )
+            .map_err(|_| error())?;
+            if skipped < compressed_size as u64 {
+                return Err(error());
+            }
+        }
+
+        let mut checksum = [0; 4];
+        reader
+            .read_exact(&mut checksum[..checksum_size])
+            .map_err(|_| error())?;
+    }
+
+    Ok(PrintInfo::from_settings(&settings))
+}
+
+/// Parse an uploaded G-code file. Returns `None` if the extension is not of a G-code file
+pub async fn analyze(ext: &str, path: PathBuf) -> Result<Option<PrintInfo>, AppError> {
+    let binary = match ext {
+        "gcode" => false,
+        "bgcode" => true,
+        _ => return Ok(None),
+    };
+
+    // A G-code file can be big, and it is read until its end
+    let info = tokio::task::spawn_blocking(move || {
+        let file = BufReader::new(File::open(path)?);
+
+        if binary {
+            parse_binary(file)
+        } else {
+            parse_text(file)
+        }
+    })
+    .await
+    .map_err(|error| error.to_string())??;
+
+    Ok(Some(info))
+}
+
+impl PrintInfo {
+    /// Pick the values from the settings, by the keys of the slicers. Numbers which are negative
+    /// or not finite, like `-1` or `inf`, are dropped
+    fn from_settings(settings: &Settings) -> Self {
+        let first = |keys: &[&str]| keys.iter().find_map(|key| settings.get(*key));
+        let number = |keys: &[&str]| {
+            first(keys)
+                .and_then(|value| first_number(value))
+                .and_then(valid)
+        };
+
+        let duration = first(&[
+            "estimated printing time (normal mode)",
+            "total estimated time",
+            "estimated printing time",
+            "print.time",
+            "time",
+        ])
+        .and_then(|value| parse_duration(value))
+        .and_then(valid);
+
+        let filament_length = match settings.get("filament used [mm]") {
+            Some(value) => sum(value, ""),
+            // Cura writes meters, like `1.2345m`
+            None => settings
+                .get("filament used")
+                .and_then(|value| sum(value, "m"))
+                .map(|meters| meters * 1000.0),
+        }
+        .and_then(valid);
+
+        let filament_weight = first(&[
+            "total filament used [g]",
+            "total filament weight [g]",
+            "filament used [g]",
+        ])
+        .and_then(|value| sum(value, ""))
+        .or_else(|| {
+            let diameter = number(&["filament_diameter"]).unwrap_or(DEFAULT_FILAMENT_DIAMETER);
+            let density = number(&["filament_density"]).unwrap_or(DEFAULT_FILAMENT_DENSITY);
+            let radius = diameter / 2.0;
+
+            // Cubic millimeters to cubic centimeters
+            filament_length
+                .map(|length| length * std::f64::consts::PI * radius * radius / 1000.0 * density)
+        })
+        .and_then(valid)
+        .map(|weight| (weight * 100.0).round() / 100.0);
+
+        let slicer = settings.get("producer").and_then(|value| {
+            let name = value.split_whitespace().next()?;
+            match name.starts_with("Cura") {
+                true => Some("Cura".to_string()),
+                false => Some(name.to_string()),
+            }
+        });
+
+        Self {
+            slicer,
+            duration,
+            filament_length,
+            filament_weight,
+            layer_height: number(&["layer_height", "layer height"]),
+            nozzle_diameter: number(&["nozzle_diameter", "extruder_train.0.nozzle.diameter"]),
+            printer: first(&[
+                "printer_model",
+                "target_machine.name",
+                "printer_settings_id",
+            ])
+            .cloned(),
+            material: first(&["filament_type"]).and_then(|value| {
+                value
+                    .split([',', ';'])
+                    .map(|x| x.trim().trim_matches('"'))
+                    .find(|x| !x.is_empty())
+                    .map(str::to_string)
+            }),
+        }
+    }
+}
+
+/// A number which can be saved: finite and not negative
+fn valid(value: f64) -> Option<f64> {
+    (value.is_finite() && value >= 0.0).then_some(value)
+}
+
+/// The first number of a list, like the nozzles of more extruders `0.4,0.4`
+fn first_number(value: &str) -> Option<f64> {
+    value.split(',').next()?.trim().parse().ok()
+}
+
+/// The sum of a list of numbers, like the filament used by more extruders `120.5, 0.0`. Every
+/// number can end with a `unit`
+fn sum(value: &str, unit: &str) -> Option<f64> {
+    value
+        .split(',')
+        .map(|x| x.trim().trim_end_matches(unit).trim().parse::<f64>().ok())
+        .sum()
+}
+
+/// Parse a duration in seconds, like `3723`, or with units, like `1d 2h 3m 4s`
+fn parse_duration(value: &str) -> Option<f64> {
+    if let Ok(seconds) = value.parse::<f64>() {
+        return Some(seconds);
+    }
+
+    let mut seconds = 0.0;
+    for token in value.split_whitespace() {
+        let split = token.find(|c: char| c.is_alphabetic())?;
+        let (number, unit) = token.split_at(split);
+        let number: f64 = number.parse().ok()?;
+
+        seconds += number
+            * match unit {
+                "d" => 86400.0,
+                "h" => 3600.0,
+                "m" => 60.0,
+                "s" => 1.0,
+                _ => return None,
+            };
+    }
+
+    (!value.trim().is_empty()).then_some(seconds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::ZlibEncoder, Compression};
+    use std::io::Write;
+
+    const PRUSA_SLICER: &str =
+        "; generated by PrusaSlicer 2.6.0+linux-x64-GTK3 on 2023-08-01 at 10:00:00 UTC
+G28 ; home
+G1 X10 Y10
+; filament used [mm] = 1234.56, 0.00
+; filament used [g] = 3.70, 0.00
+; estimated printing time (normal mode) = 1h 2m 3s
+; layer_height = 0.2
+; nozzle_diameter = 0.4,0.6
+; filament_type = PETG;PLA
+; printer_model = MK4
+";
+
+    #[test]
+    fn parse_text_of_prusa_slicer() {
+        let info = parse_text(PRUSA_SLICER.as_bytes()).unwrap();
+
+        assert_eq!(info.slicer.as_deref(), Some("PrusaSlicer"));
+        assert_eq!(info.duration, Some(3723.0));
+        assert_eq!(info.filament_length, Some(1234.56));
+        assert_eq!(info.filament_weight, Some(3.7));
+        assert_eq!(info.layer_height, Some(0.2));
+        assert_eq!(info.nozzle_diameter, Some(0.4));
+        assert_eq!(info.printer.as_deref(), Some("MK4"));
+        assert_eq!(info.material.as_deref(), Some("PETG"));
+    }
+
+    #[test]
+    fn parse_text_of_cura() {
+        let gcode = ";FLAVOR:Marlin
+;TIME:5400
+;Filament used: 2.5m
+;Layer height: 0.12
+;Generated with Cura_SteamEngine 5.4.0
+;TARGET_MACHINE.NAME:Creality Ender-3
+G28
+";
+        let info = parse_text(gcode.as_bytes()).unwrap();
+
+        assert_eq!(info.slicer.as_deref(), Some("Cura"));
+        assert_eq!(info.duration, Some(5400.0));
+        assert_eq!(info.filament_length, Some(2500.0));
+        // Estimated for PLA of 1.75 mm: 2500 * pi * 0.875^2 / 1000 * 1.24
+        assert_eq!(info.filament_weight, Some(7.46));
+        assert_eq!(info.layer_height, Some(0.12));
+        assert_eq!(info.printer.as_deref(), Some("Creality Ender-3"));
+    }
+
+    #[test]
+    fn parse_text_drops_invalid_numbers() {
+        let gcode = "; estimated printing time (normal mode) = 1e400h
+; filament used [mm] = -100
+; filament used [g] = NaN
+; layer_height = inf
+; nozzle_diameter = -0.4
+";
+        let info = parse_text(gcode.as_bytes()).unwrap();
+
+        assert_eq!(info.duration, None);
+        assert_eq!(info.filament_length, None);
+        assert_eq!(info.filament_weight, None);
+        assert_eq!(info.layer_height, None);
+        assert_eq!(info.nozzle_diameter, None);
+    }
+
+    /// Block of a binary G-code file, with its parameters and a CRC32 checksum
+    fn block(kind: u16, data: &[u8], compressed: bool) -> Vec<u8> {
+        let mut block = vec![];
+        block.extend(kind.to_le_bytes());
+        block.extend((compressed as u16).to_le_bytes());
+        block.extend((data.len() as u32).to_le_bytes());
+
+        let data = match compressed {
+            true => {
+                let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+                encoder.write_all(data).unwrap();
+                let data = encoder.finish().unwrap();
+                block.extend((data.len() as u32).to_le_bytes());
+                data
+            }
+            false => data.to_vec(),
+        };
+        // Encoding of the INI metadata
+        block.extend(vec![0; if kind == 5 { 6 } else { 2 }]);
+        block.extend(data);
+        block.extend(crc32fast::hash(&block).to_le_bytes());
+
+        block
+    }
+
+    fn binary(blocks: &[Vec<u8>]) -> Vec<u8> {
+        let mut file = b"GCDE".to_vec();
+        file.extend(1_u32.to_le_bytes());
+        file.extend(1_u16.to_le_bytes());
+        file.extend(blocks.concat());
+
+        file
+    }
+
+    #[test]
+    fn parse_binary_metadata() {
+        let file = binary(&[
+            block(0, b"Producer=PrusaSlicer 2.6.0\n", false),
+            block(3, b"printer_model=XL\n", true),
+            block(5, &[0; 100], false),
+            block(
+                4,
+                b"estimated printing time (normal mode)=2h 30m\nfilament used [g]=12.34\n",
+                true,
+            ),
+            block(1, b"G28\n", false),
+            block(2, b"printer_model=ignored\n", false),
+        ]);
+        let info = parse_binary(&file[..]).unwrap();
+
+        assert_eq!(info.slicer.as_deref(), Some("PrusaSlicer"));
+        assert_eq!(info.printer.as_deref(), Some("XL"));
+        assert_eq!(info.duration, Some(9000.0));
+        assert_eq!(info.filament_weight, Some(12.34));
+    }
+
+    #[test]
+    fn parse_binary_refuses_a_broken_file() {
+        assert!(parse_binary(&b"GCDX\x01\0\0\0\x01\0"[..]).is_err());
+        assert!(parse_binary(&b"GCDE\x01"[..]).is_err());
+
+        let mut file = binary(&[block(0, b"Producer=PrusaSlicer\n", false)]);
+        file.truncate(file.len() - 8);
+        assert!(parse_binary(&file[..]).is_err());
+    }
+
+    #[test]
+    fn parse_duration_with_units() {
+        assert_eq!(parse_duration("3723"), Some(3723.0));
+        assert_eq!(parse_duration("1d 2h 3m 4s"), Some(93784.0));
+        assert_eq!(parse_duration("45m"), Some(2700.0));
+        assert_eq!(parse_duration("1h 2x"), None);
+        assert_eq!(parse_duration("soon"), None);
+        assert_eq!(parse_duration(""), None);
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{db::get_client, errors::AppError};
+use crate::{errors::AppError, state::AppState};
 use chrono::{Local, NaiveDateTime};
 use serde::{Deserialize, Serialize};
 use sqlx::Row;
@@ -7,8 +7,10 @@ use sqlx::Row;
 #[derive(Serialize, Deserialize, sqlx::FromRow)]
 pub struct Like {
     id: i32,
-    user_id: i32,
-    model_id: i32,
+    /// It is `NULL` if the user has been deleted
+    user_id: Option<i32>,
+    /// It is `NULL` if the model has been deleted
+    model_id: Option<i32>,
     created: NaiveDateTime,
 }
 
@@ -18,15 +20,15 @@ impl Like {
         let now = Local::now().naive_utc();
         Self {
             id: 0,
-            user_id,
-            model_id,
+            user_id: Some(user_id),
+            model_id: Some(model_id),
             created: now,
         }
     }
 
     /// Returns `true` if an user has already assigned a like to a model
-    pub async fn exists(&self) -> Result<bool, AppError> {
-        let pool = unsafe { get_client() };
+    pub async fn exists(&self, state: &AppState) -> Result<bool, AppError> {
+        let pool = &state.pool;
         let cursor = sqlx::query(
             r#"
                 SELECT COUNT(id) as count FROM likes WHERE user_id = $1 AND model_id = $2
@@ -43,10 +45,10 @@ impl Like {
     }
 
     /// Save new like into db
-    pub async fn save(&self) -> Result<Like, AppError> {
-        let pool = unsafe { get_client() };
+    pub async fn save(&self, state: &AppState) -> Result<Like, AppError> {
+        let pool = &state.pool;
 
-        if self.exists().await? {
+        if self.exists(state).await? {
             return Err(AppError::BadRequest(
                 "This user already likes this model".to_string(),
             ));
@@ -69,10 +71,10 @@ impl Like {
     }
 
     /// Remove a like
-    pub async fn remove(&self) -> Result<(), AppError> {
-        let pool = unsafe { get_client() };
+    pub async fn remove(&self, state: &AppState) -> Result<(), AppError> {
+        let pool = &state.pool;
 
-        if !self.exists().await? {
+        if !self.exists(state).await? {
             return Err(AppError::NotFound("Like not found".to_string()));
         }
 
@@ -88,4 +90,20 @@ impl Like {
 
         Ok(())
     }
+
+    /// List all the likes of an user
+    pub async fn find_by_user(state: &AppState, user_id: i32) -> Result<Vec<Like>, AppError> {
+        let pool = &state.pool;
+
+        let rows: Vec<Like> = sqlx::query_as(
+            r#"
+                SELECT * FROM likes WHERE user_id = $1 ORDER BY id
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows)
+    }
 }
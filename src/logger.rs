@@ -1,11 +1,9 @@
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 /// Setup tracing subscriber logger
-pub fn setup() {
+pub fn setup(rust_log: &str) {
     tracing_subscriber::registry()
-        .with(tracing_subscriber::EnvFilter::new(
-            crate::config::CONFIG.rust_log.clone(),
-        ))
+        .with(tracing_subscriber::EnvFilter::new(rust_log))
         .with(tracing_subscriber::fmt::layer())
         .init();
 }
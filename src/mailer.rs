@@ -0,0 +1,146 @@
+use crate::{config::Configuration, errors::AppError};
+use async_trait::async_trait;
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
+    AsyncTransport, Message, Tokio1Executor,
+};
+use rand::random;
+use std::{path::PathBuf, sync::Arc};
+
+/// An email sent by the app
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Something which can deliver emails
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, mail: Mail) -> Result<(), AppError>;
+}
+
+/// Mailer which sends emails through a SMTP server
+pub struct SmtpMailer {
+    from: Mailbox,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+/// Mailer which writes emails as `.eml` files into an outbox directory. Useful for tests
+pub struct FileMailer {
+    from: Mailbox,
+    outbox: PathBuf,
+}
+
+/// Mailer which only prints emails to the log
+pub struct LogMailer;
+
+/// Build the mailer chosen by the `MAILER` variable: "smtp", "file" or "log"
+pub fn from_config(config: &Configuration) -> Result<Arc<dyn Mailer>, AppError> {
+    match config.mailer.as_str() {
+        "smtp" => Ok(Arc::new(SmtpMailer::new(config)?)),
+        "file" => Ok(Arc::new(FileMailer::new(config)?)),
+        "log" => Ok(Arc::new(LogMailer)),
+        other => Err(AppError::BadRequest(format!("Unknown mailer `{}`", other))),
+    }
+}
+
+/// Build a `Message` ready to be delivered
+fn build_message(from: &Mailbox, mail: Mail) -> Result<Message, AppError> {
+    let to: Mailbox = mail
+        .to
+        .parse()
+        .map_err(|_| AppError::BadRequest("Invalid email address".to_string()))?;
+
+    Message::builder()
+        .from(from.clone())
+        .to(to)
+        .subject(mail.subject)
+        .body(mail.body)
+        .map_err(|_| AppError::Mail)
+}
+
+/// Parse the `MAIL_FROM` variable
+fn parse_from(config: &Configuration) -> Result<Mailbox, AppError> {
+    config
+        .mail_from
+        .parse()
+        .map_err(|_| AppError::BadRequest("Invalid `MAIL_FROM` address".to_string()))
+}
+
+impl SmtpMailer {
+    pub fn new(config: &Configuration) -> Result<Self, AppError> {
+        let host = config
+            .smtp_host
+            .as_ref()
+            .ok_or_else(|| AppError::BadRequest("`SMTP_HOST` is not set".to_string()))?;
+
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::relay(host)
+            .map_err(|_| AppError::Mail)?
+            .port(config.smtp_port);
+
+        if let (Some(username), Some(password)) = (&config.smtp_username, &config.smtp_password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        Ok(Self {
+            from: parse_from(config)?,
+            transport: builder.build(),
+        })
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, mail: Mail) -> Result<(), AppError> {
+        let message = build_message(&self.from, mail)?;
+
+        self.transport.send(message).await.map_err(|e| {
+            tracing::error!("{:?}", e);
+            AppError::Mail
+        })?;
+
+        Ok(())
+    }
+}
+
+impl FileMailer {
+    pub fn new(config: &Configuration) -> Result<Self, AppError> {
+        let outbox = config
+            .mail_outbox_path
+            .as_ref()
+            .ok_or_else(|| AppError::BadRequest("`MAIL_OUTBOX_PATH` is not set".to_string()))?;
+
+        Ok(Self {
+            from: parse_from(config)?,
+            outbox: PathBuf::from(outbox),
+        })
+    }
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, mail: Mail) -> Result<(), AppError> {
+        let message = build_message(&self.from, mail)?;
+
+        let filename = format!(
+            "{}-{}.eml",
+            chrono::Local::now().format("%Y%m%d%H%M%S"),
+            random::<u32>()
+        );
+
+        tokio::fs::create_dir_all(&self.outbox).await?;
+        tokio::fs::write(self.outbox.join(filename), message.formatted()).await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Mailer for LogMailer {
+    async fn send(&self, mail: Mail) -> Result<(), AppError> {
+        tracing::info!("Mail to <{}>: {}\n{}", mail.to, mail.subject, mail.body);
+
+        Ok(())
+    }
+}
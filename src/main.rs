@@ -1,23 +1,35 @@
 mod auth;
+mod commands;
 mod config;
+mod consistency;
 mod db;
 mod errors;
 mod files;
+mod filetype;
+mod gcode;
 mod json;
 mod likes;
 mod logger;
+mod mailer;
+mod mesh;
 mod model;
 mod pagination;
 mod routes;
+mod state;
+mod storage;
 mod user;
+mod variants;
 mod warning;
 
-use crate::config::{CONFIG, SENTRY};
+use crate::{
+    config::{Configuration, SENTRY},
+    state::AppState,
+};
 use axum::{
     handler::Handler,
-    http::{header, Method, Request},
+    http::{header, header::HeaderName, Method, Request},
     routing::get,
-    Router,
+    Extension, Router,
 };
 
 use std::net::{SocketAddr, ToSocketAddrs};
@@ -33,9 +45,26 @@ use tracing::Span;
 /// Main application, called by the execution of the software
 #[tokio::main]
 async fn main() {
-    let app = create_app().await;
+    let config = Configuration::new().expect("Config can be loaded");
+    logger::setup(&config.rust_log);
+
+    // Run a maintenance command instead of the server, if it is passed
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        if let Err(error) = commands::run(config, &args).await {
+            tracing::error!("{:?}", error);
+            std::process::exit(1);
+        }
+        return;
+    }
 
-    let host = &CONFIG.allowed_host;
+    let host = config.allowed_host.clone();
+    let state = AppState::new(config)
+        .await
+        .expect("App state can be created");
+    consistency::warn_unsized_uploads(&state).await;
+    consistency::schedule(state.clone());
+    let app = create_app(state);
 
     let addr = match host.parse::<SocketAddr>() {
         Ok(addr) => addr,
@@ -50,16 +79,13 @@ async fn main() {
     tracing::info!("[Sentry] Guard enabled: {}", SENTRY.0.is_enabled());
 
     axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await
         .unwrap();
 }
 
-/// Create the app: setup everything and returns a `Router`
-async fn create_app() -> Router {
-    logger::setup();
-    let _ = db::setup().await;
-
+/// Create the app for a state: setup everything and returns a `Router`
+fn create_app(state: AppState) -> Router {
     let api_routes = Router::new()
         .nest("/users", user::routes::create_route())
         .nest("/auth", auth::routes::create_route())
@@ -68,12 +94,13 @@ async fn create_app() -> Router {
 
     Router::new()
         .route(
-            &format!("{}/:id", CONFIG.uploads_endpoint),
+            &format!("{}/:id", state.config.uploads_endpoint),
             get(crate::files::show_uploads),
         )
         // Map all routes to `/v1/*` namespace
         .nest("/v1", api_routes)
         .fallback(crate::routes::page_404.into_service())
+        .layer(Extension(state))
         // Mark the `Authorization` request header as sensitive so it doesn't
         // show in logs.
         .layer(SetSensitiveHeadersLayer::new(std::iter::once(
@@ -98,9 +125,26 @@ async fn create_app() -> Router {
                     Method::GET,
                     Method::POST,
                     Method::PUT,
+                    Method::PATCH,
+                    Method::HEAD,
                     Method::DELETE,
                 ])
-                .allow_headers(vec![header::CONTENT_TYPE, header::AUTHORIZATION])
+                .allow_headers([
+                    header::CONTENT_TYPE,
+                    header::AUTHORIZATION,
+                    HeaderName::from_static("tus-resumable"),
+                    HeaderName::from_static("upload-length"),
+                    HeaderName::from_static("upload-offset"),
+                    HeaderName::from_static("upload-metadata"),
+                ])
+                // Headers read by the clients of the resumable uploads
+                .expose_headers([
+                    header::LOCATION,
+                    HeaderName::from_static("tus-resumable"),
+                    HeaderName::from_static("upload-length"),
+                    HeaderName::from_static("upload-offset"),
+                    HeaderName::from_static("upload-expires"),
+                ])
                 .allow_origin(Any),
         )
 }
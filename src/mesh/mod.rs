@@ -0,0 +1,329 @@
+pub mod obj;
+pub mod render;
+pub mod stl;
+pub mod threemf;
+
+use crate::errors::AppError;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{Read, Seek},
+    path::PathBuf,
+};
+
+/// Indexed triangle mesh. Vertices with the same coordinates are merged, so triangles sharing
+/// an edge share its vertices too
+#[derive(Default)]
+pub struct Mesh {
+    pub vertices: Vec<[f64; 3]>,
+    pub triangles: Vec<[u32; 3]>,
+    /// Index of the vertices by their coordinates, used to merge them
+    index: HashMap<[u64; 3], u32>,
+}
+
+/// Axis-aligned box which contains the whole mesh
+#[derive(Serialize, Deserialize)]
+pub struct BoundingBox {
+    pub min: [f64; 3],
+    pub max: [f64; 3],
+}
+
+/// Values computed from a mesh, saved on the upload row. Lengths are in millimeters
+#[derive(Serialize, Deserialize)]
+pub struct Geometry {
+    pub triangles: usize,
+    pub vertices: usize,
+    pub bounding_box: BoundingBox,
+    /// Size of the bounding box on the x, y and z axis
+    pub size: [f64; 3],
+    /// Volume enclosed by the mesh, in cubic millimeters. It is meaningful only if the mesh is
+    /// watertight
+    pub volume: f64,
+    /// Surface area, in square millimeters
+    pub surface_area: f64,
+    /// `true` if every edge is shared by exactly two triangles with a consistent orientation
+    pub watertight: bool,
+}
+
+/// Supported formats of a 3D file
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MeshFormat {
+    Stl,
+    Obj,
+    ThreeMf,
+}
+
+impl MeshFormat {
+    /// Returns the format of a file from its extension. `sla` is the subtype of the
+    /// `application/sla` STL content type
+    pub fn from_extension(ext: &str) -> Option<Self> {
+        match ext.to_lowercase().as_str() {
+            "stl" | "sla" => Some(MeshFormat::Stl),
+            "obj" => Some(MeshFormat::Obj),
+            "3mf" => Some(MeshFormat::ThreeMf),
+            _ => None,
+        }
+    }
+
+    /// Extension of a file of this format
+    pub fn extension(&self) -> &'static str {
+        match self {
+            MeshFormat::Stl => "stl",
+            MeshFormat::Obj => "obj",
+            MeshFormat::ThreeMf => "3mf",
+        }
+    }
+}
+
+/// Parse a 3D file, which can be at most `max_size` bytes big
+pub fn parse<R: Read + Seek>(
+    format: MeshFormat,
+    reader: R,
+    max_size: u64,
+) -> Result<Mesh, AppError> {
+    let mesh = match format {
+        MeshFormat::Stl => stl::parse(reader)?,
+        MeshFormat::Obj => obj::parse(reader)?,
+        MeshFormat::ThreeMf => threemf::parse(reader, max_size)?,
+    };
+
+    if mesh.triangles.is_empty() {
+        return Err(AppError::BadRequest(
+            "The mesh has no triangles".to_string(),
+        ));
+    }
+
+    Ok(mesh)
+}
+
+/// Write a mesh as a 3D file
+pub fn write(format: MeshFormat, mesh: &Mesh) -> Result<Vec<u8>, AppError> {
+    match format {
+        MeshFormat::Stl => Ok(stl::write(mesh)),
+        MeshFormat::Obj => Ok(obj::write(mesh)),
+        MeshFormat::ThreeMf => threemf::write(mesh),
+    }
+}
+
+/// Convert a 3D file from a format to another, returning the content of the converted one. Only
+/// the geometry is kept: the objects of a 3MF file are merged, colors and materials are lost
+pub async fn convert(
+    path: PathBuf,
+    from: MeshFormat,
+    to: MeshFormat,
+    max_size: u64,
+) -> Result<Vec<u8>, AppError> {
+    tokio::task::spawn_blocking(move || write(to, &parse(from, File::open(path)?, max_size)?))
+        .await
+        .map_err(|error| error.to_string())?
+}
+
+/// What is computed from an uploaded 3D file
+pub struct Analysis {
+    pub geometry: Geometry,
+    /// PNG thumbnails, with their size
+    pub thumbnails: Vec<(u32, Vec<u8>)>,
+}
+
+/// Parse an uploaded file, computing its geometry and rendering its thumbnails. Returns `None`
+/// if the extension is not of a supported 3D file
+pub async fn analyze(
+    ext: &str,
+    path: PathBuf,
+    max_size: u64,
+) -> Result<Option<Analysis>, AppError> {
+    let format = match MeshFormat::from_extension(ext) {
+        Some(format) => format,
+        None => return Ok(None),
+    };
+
+    // Big meshes take a while: keep the runtime threads free
+    let analysis = tokio::task::spawn_blocking(move || {
+        let file = File::open(path)?;
+
+        parse(format, file, max_size).map(|mesh| Analysis {
+            geometry: mesh.geometry(),
+            thumbnails: render::thumbnails(&mesh),
+        })
+    })
+    .await
+    .map_err(|error| error.to_string())??;
+
+    Ok(Some(analysis))
+}
+
+impl Mesh {
+    /// Add a vertex, returning its index. A vertex with the same coordinates is reused
+    pub fn add_vertex(&mut self, vertex: [f64; 3]) -> u32 {
+        // `0.0` and `-0.0` are the same point
+        let key = vertex.map(|x| if x == 0.0 { 0 } else { x.to_bits() });
+
+        if let Some(&index) = self.index.get(&key) {
+            return index;
+        }
+
+        let index = self.vertices.len() as u32;
+        self.vertices.push(vertex);
+        self.index.insert(key, index);
+
+        index
+    }
+
+    /// Add a triangle by the indexes of its vertices. Degenerate triangles, with a repeated
+    /// vertex, are skipped
+    pub fn add_triangle(&mut self, triangle: [u32; 3]) {
+        if triangle[0] != triangle[1] && triangle[1] != triangle[2] && triangle[0] != triangle[2] {
+            self.triangles.push(triangle);
+        }
+    }
+
+    /// Compute the geometry values of the mesh
+    pub fn geometry(&self) -> Geometry {
+        let mut min = [f64::INFINITY; 3];
+        let mut max = [f64::NEG_INFINITY; 3];
+        for vertex in self.triangles.iter().flatten() {
+            let vertex = self.vertices[*vertex as usize];
+            for axis in 0..3 {
+                min[axis] = min[axis].min(vertex[axis]);
+                max[axis] = max[axis].max(vertex[axis]);
+            }
+        }
+
+        let mut volume = 0.0;
+        let mut surface_area = 0.0;
+        // Directed edges: in a closed and oriented mesh each one is used once, and its opposite
+        // is used by the neighbour triangle
+        let mut edges: HashMap<(u32, u32), u32> = HashMap::new();
+
+        for triangle in &self.triangles {
+            let [a, b, c] = triangle.map(|x| self.vertices[x as usize]);
+
+            // Signed volume of the tetrahedron made by the triangle and the origin
+            volume += dot(a, cross(b, c)) / 6.0;
+            surface_area += length(cross(sub(b, a), sub(c, a))) / 2.0;
+
+            for edge in [
+                (triangle[0], triangle[1]),
+                (triangle[1], triangle[2]),
+                (triangle[2], triangle[0]),
+            ] {
+                *edges.entry(edge).or_default() += 1;
+            }
+        }
+
+        let watertight = edges
+            .iter()
+            .all(|(&(a, b), &count)| count == 1 && edges.get(&(b, a)) == Some(&1));
+
+        Geometry {
+            triangles: self.triangles.len(),
+            vertices: self.vertices.len(),
+            size: [max[0] - min[0], max[1] - min[1], max[2] - min[2]],
+            bounding_box: BoundingBox { min, max },
+            volume: volume.abs(),
+            surface_area,
+            watertight,
+        }
+    }
+}
+
+fn sub(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn dot(a: [f64; 3], b: [f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+fn length(a: [f64; 3]) -> f64 {
+    dot(a, a).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Cube with a side of 10, with the triangles facing outwards
+    pub(super) fn cube() -> Mesh {
+        let mut mesh = Mesh::default();
+        for x in [0.0, 10.0] {
+            for y in [0.0, 10.0] {
+                for z in [0.0, 10.0] {
+                    mesh.add_vertex([x, y, z]);
+                }
+            }
+        }
+        // Vertex indexes are `x * 4 + y * 2 + z`
+        for triangle in [
+            [0, 1, 3],
+            [0, 3, 2],
+            [4, 6, 7],
+            [4, 7, 5],
+            [0, 4, 5],
+            [0, 5, 1],
+            [2, 3, 7],
+            [2, 7, 6],
+            [0, 2, 6],
+            [0, 6, 4],
+            [1, 5, 7],
+            [1, 7, 3],
+        ] {
+            mesh.add_triangle(triangle);
+        }
+
+        mesh
+    }
+
+    #[test]
+    fn geometry_of_a_cube() {
+        let geometry = cube().geometry();
+
+        assert_eq!(geometry.triangles, 12);
+        assert_eq!(geometry.vertices, 8);
+        assert_eq!(geometry.size, [10.0; 3]);
+        assert_eq!(geometry.bounding_box.min, [0.0; 3]);
+        assert!((geometry.volume - 1000.0).abs() < 1e-9);
+        assert!((geometry.surface_area - 600.0).abs() < 1e-9);
+        assert!(geometry.watertight);
+    }
+
+    #[test]
+    fn geometry_of_an_open_mesh() {
+        let mut mesh = cube();
+        mesh.triangles.pop();
+        let geometry = mesh.geometry();
+
+        assert_eq!(geometry.triangles, 11);
+        assert!((geometry.surface_area - 550.0).abs() < 1e-9);
+        assert!(!geometry.watertight);
+    }
+
+    #[test]
+    fn add_vertex_reuses_the_same_point() {
+        let mut mesh = Mesh::default();
+
+        assert_eq!(mesh.add_vertex([1.0, 0.0, 2.0]), 0);
+        assert_eq!(mesh.add_vertex([1.0, -0.0, 2.0]), 0);
+        assert_eq!(mesh.add_vertex([1.0, 1.0, 2.0]), 1);
+
+        mesh.add_triangle([0, 1, 1]);
+        assert!(mesh.triangles.is_empty());
+    }
+
+    #[test]
+    fn parse_refuses_a_mesh_without_triangles() {
+        let obj = "v 0 0 0\nv 1 0 0\nv 0 1 0\n";
+
+        assert!(parse(MeshFormat::Obj, Cursor::new(obj), 1024).is_err());
+    }
+}
@@ -0,0 +1,124 @@
+use super::Mesh;
+use crate::errors::AppError;
+use std::{
+    fmt::Write,
+    io::{BufRead, BufReader, Read},
+};
+
+/// Parse a Wavefront OBJ file. Only vertices and faces are read; polygons are split in triangles
+/// as a fan. The file is read line by line
+pub fn parse<R: Read>(reader: R) -> Result<Mesh, AppError> {
+    let mut mesh = Mesh::default();
+    // Indexes of the mesh vertices, by their position in the file
+    let mut vertices: Vec<u32> = vec![];
+
+    let mut reader = BufReader::new(reader);
+    let mut buffer = vec![];
+    let mut number = 0;
+
+    loop {
+        buffer.clear();
+        number += 1;
+        let error = || AppError::BadRequest(format!("Invalid OBJ file at line {}", number));
+
+        if reader.read_until(b'\n', &mut buffer).map_err(|_| error())? == 0 {
+            break;
+        }
+        let line = String::from_utf8_lossy(&buffer);
+        let mut tokens = line.split_ascii_whitespace();
+
+        match tokens.next() {
+            Some("v") => {
+                let mut vertex = [0.0; 3];
+                for coord in vertex.iter_mut() {
+                    *coord = tokens
+                        .next()
+                        .and_then(|x| x.parse().ok())
+                        .ok_or_else(error)?;
+                }
+                vertices.push(mesh.add_vertex(vertex));
+            }
+            Some("f") => {
+                let face = tokens
+                    .map(|token| {
+                        // A face vertex can be "v", "v/vt", "v//vn" or "v/vt/vn"
+                        let index: i64 = token.split('/').next()?.parse().ok()?;
+                        let position = if index < 0 {
+                            vertices.len() as i64 + index
+                        } else {
+                            index - 1
+                        };
+                        vertices.get(usize::try_from(position).ok()?).copied()
+                    })
+                    .collect::<Option<Vec<u32>>>()
+                    .ok_or_else(error)?;
+
+                if face.len() < 3 {
+                    return Err(error());
+                }
+                for i in 1..face.len() - 1 {
+                    mesh.add_triangle([face[0], face[i], face[i + 1]]);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(mesh)
+}
+
+/// Write a mesh as a Wavefront OBJ file, with its vertices and its triangles
+pub fn write(mesh: &Mesh) -> Vec<u8> {
+    let mut text = String::from("# Written by Verden\n");
+
+    for [x, y, z] in &mesh.vertices {
+        writeln!(text, "v {} {} {}", x, y, z).unwrap();
+    }
+    // Indexes of the OBJ vertices start from 1
+    for [a, b, c] in &mesh.triangles {
+        writeln!(text, "f {} {} {}", a + 1, b + 1, c + 1).unwrap();
+    }
+
+    text.into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::tests::cube;
+
+    #[test]
+    fn parse_faces() {
+        let obj = "# square and triangle
+o test
+v 0 0 0
+v 1 0 0
+v 1 1 0
+v 0 1 0
+vn 0 0 1
+f 1//1 2//1 3//1 4//1
+f -4/1 -3/1 -1/1
+";
+        let mesh = parse(obj.as_bytes()).unwrap();
+
+        assert_eq!(mesh.vertices.len(), 4);
+        assert_eq!(mesh.triangles, [[0, 1, 2], [0, 2, 3], [0, 1, 3]]);
+    }
+
+    #[test]
+    fn parse_refuses_a_bad_face() {
+        assert!(parse("v 0 0 0\nv 1 0 0\nf 1 2\n".as_bytes()).is_err());
+        assert!(parse("v 0 0 0\nv 1 0 0\nf 1 2 3\n".as_bytes()).is_err());
+        assert!(parse("v 0 0 0\nv 1 0 0\nf 0 1 2\n".as_bytes()).is_err());
+        assert!(parse("v 0 zero 0\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn write_and_parse() {
+        let geometry = parse(&write(&cube())[..]).unwrap().geometry();
+
+        assert_eq!(geometry.triangles, 12);
+        assert!((geometry.volume - 1000.0).abs() < 1e-9);
+        assert!(geometry.watertight);
+    }
+}
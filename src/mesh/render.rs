@@ -0,0 +1,212 @@
+use super::{cross, dot, sub, Mesh};
+use image::{imageops, imageops::FilterType, ImageFormat, Rgba, RgbaImage};
+use std::io::Cursor;
+
+/// Sizes, in pixels, of the thumbnails rendered for a mesh
+pub const THUMBNAIL_SIZES: [u32; 3] = [128, 256, 512];
+
+/// The image is rendered bigger and then scaled down, to smooth the edges
+const SUPERSAMPLING: u32 = 2;
+
+/// Color of the mesh surface
+const COLOR: [f64; 3] = [90.0, 140.0, 210.0];
+
+/// Light which does not depend on the surface orientation
+const AMBIENT: f64 = 0.3;
+
+/// Most triangles which are drawn. A bigger mesh is drawn skipping triangles evenly: in a
+/// thumbnail they are smaller than a pixel anyway
+const MAX_TRIANGLES: usize = 2_000_000;
+
+/// Most pixels which are tested, in the bounding boxes of the triangles drawn: big triangles
+/// which cover each other can take as long as many small ones
+const MAX_PIXELS: u64 = 100_000_000;
+
+fn normalize(a: [f64; 3]) -> [f64; 3] {
+    let length = dot(a, a).sqrt();
+    if length == 0.0 {
+        a
+    } else {
+        a.map(|x| x / length)
+    }
+}
+
+/// Render an isometric view of the mesh, from the top front right corner, with a transparent
+/// background. The z axis points up, as for 3D printing
+pub fn render(mesh: &Mesh, size: u32) -> RgbaImage {
+    let canvas = size * SUPERSAMPLING;
+
+    // Camera basis: the eye looks toward the origin from `eye`
+    let eye = normalize([1.0, -1.0, 1.0]);
+    let right = normalize(cross([0.0, 0.0, 1.0], eye));
+    let up = cross(eye, right);
+    let light = normalize([0.4, -1.0, 1.6]);
+
+    // Vertices on the screen plane, with their depth (bigger is nearer)
+    let projected: Vec<[f64; 3]> = mesh
+        .vertices
+        .iter()
+        .map(|&v| [dot(v, right), dot(v, up), dot(v, eye)])
+        .collect();
+
+    let mut min = [f64::INFINITY; 2];
+    let mut max = [f64::NEG_INFINITY; 2];
+    for index in mesh.triangles.iter().flatten() {
+        let p = projected[*index as usize];
+        for axis in 0..2 {
+            min[axis] = min[axis].min(p[axis]);
+            max[axis] = max[axis].max(p[axis]);
+        }
+    }
+
+    // Keep a margin of 5% on every side
+    let extent = (max[0] - min[0]).max(max[1] - min[1]).max(f64::EPSILON);
+    let scale = canvas as f64 * 0.9 / extent;
+    let offset = [
+        (canvas as f64 - (max[0] - min[0]) * scale) / 2.0,
+        (canvas as f64 - (max[1] - min[1]) * scale) / 2.0,
+    ];
+    let to_screen = |p: [f64; 3]| {
+        [
+            offset[0] + (p[0] - min[0]) * scale,
+            // Images have the y axis pointing down
+            canvas as f64 - offset[1] - (p[1] - min[1]) * scale,
+            p[2],
+        ]
+    };
+
+    // Every `step`th triangle is drawn, to keep the time bounded
+    let pixels: u64 = mesh
+        .triangles
+        .iter()
+        .map(|triangle| {
+            let [x_start, x_end, y_start, y_end] =
+                bounds(triangle.map(|x| to_screen(projected[x as usize])), canvas);
+            (x_end.saturating_sub(x_start) as u64 + 1) * (y_end.saturating_sub(y_start) as u64 + 1)
+        })
+        .sum();
+    let step = step(mesh.triangles.len(), pixels);
+
+    let mut image = RgbaImage::new(canvas, canvas);
+    let mut depth = vec![f64::NEG_INFINITY; (canvas * canvas) as usize];
+
+    for triangle in mesh.triangles.iter().step_by(step) {
+        let [a, b, c] = triangle.map(|x| mesh.vertices[x as usize]);
+        let normal = normalize(cross(sub(b, a), sub(c, a)));
+        // Both sides are lit, so a mesh with a wrong winding is shown anyway
+        let intensity = AMBIENT + (1.0 - AMBIENT) * dot(normal, light).abs();
+        let pixel = Rgba([
+            (COLOR[0] * intensity) as u8,
+            (COLOR[1] * intensity) as u8,
+            (COLOR[2] * intensity) as u8,
+            255,
+        ]);
+
+        let [a, b, c] = triangle.map(|x| to_screen(projected[x as usize]));
+        let area = edge(a, b, c);
+        if area.abs() < f64::EPSILON {
+            continue;
+        }
+
+        let [x_start, x_end, y_start, y_end] = bounds([a, b, c], canvas);
+        for y in y_start..=y_end {
+            for x in x_start..=x_end {
+                let p = [x as f64 + 0.5, y as f64 + 0.5, 0.0];
+                let w0 = edge(b, c, p) / area;
+                let w1 = edge(c, a, p) / area;
+                let w2 = edge(a, b, p) / area;
+                if w0 < 0.0 || w1 < 0.0 || w2 < 0.0 {
+                    continue;
+                }
+
+                let z = w0 * a[2] + w1 * b[2] + w2 * c[2];
+                let index = (y * canvas + x) as usize;
+                if z > depth[index] {
+                    depth[index] = z;
+                    image.put_pixel(x, y, pixel);
+                }
+            }
+        }
+    }
+
+    imageops::resize(&image, size, size, FilterType::Triangle)
+}
+
+/// Returns how often a triangle is drawn, to keep `triangles` and the `pixels` of their
+/// bounding boxes within `MAX_TRIANGLES` and `MAX_PIXELS`
+fn step(triangles: usize, pixels: u64) -> usize {
+    let step = triangles
+        .div_ceil(MAX_TRIANGLES)
+        .max(pixels.div_ceil(MAX_PIXELS) as usize);
+
+    step.max(1)
+}
+
+/// Pixels of the canvas which the triangle can cover, as `[x_start, x_end, y_start, y_end]`
+fn bounds([a, b, c]: [[f64; 3]; 3], canvas: u32) -> [u32; 4] {
+    [
+        a[0].min(b[0]).min(c[0]).floor().max(0.0) as u32,
+        (a[0].max(b[0]).max(c[0]).ceil() as u32).min(canvas - 1),
+        a[1].min(b[1]).min(c[1]).floor().max(0.0) as u32,
+        (a[1].max(b[1]).max(c[1]).ceil() as u32).min(canvas - 1),
+    ]
+}
+
+/// Signed area (doubled) of the triangle `abc` on the screen plane
+fn edge(a: [f64; 3], b: [f64; 3], c: [f64; 3]) -> f64 {
+    (b[0] - a[0]) * (c[1] - a[1]) - (b[1] - a[1]) * (c[0] - a[0])
+}
+
+/// Render the thumbnails of the mesh at all the `THUMBNAIL_SIZES`, encoded as PNG. The mesh is
+/// rendered once, at the biggest size
+pub fn thumbnails(mesh: &Mesh) -> Vec<(u32, Vec<u8>)> {
+    let biggest = THUMBNAIL_SIZES.iter().max().copied().unwrap_or_default();
+    let image = render(mesh, biggest);
+
+    THUMBNAIL_SIZES
+        .iter()
+        .filter_map(|&size| {
+            let resized = if size == biggest {
+                image.clone()
+            } else {
+                imageops::resize(&image, size, size, FilterType::Triangle)
+            };
+
+            let mut png = Cursor::new(Vec::new());
+            resized.write_to(&mut png, ImageFormat::Png).ok()?;
+
+            Some((size, png.into_inner()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::tests::cube;
+
+    #[test]
+    fn render_a_cube() {
+        let image = render(&cube(), 64);
+
+        assert_eq!(image.dimensions(), (64, 64));
+        assert_eq!(image.get_pixel(32, 32)[3], 255);
+        assert_eq!(image.get_pixel(0, 0)[3], 0);
+    }
+
+    #[test]
+    fn step_keeps_the_budget() {
+        assert_eq!(step(0, 0), 1);
+        assert_eq!(step(MAX_TRIANGLES, MAX_PIXELS), 1);
+        assert_eq!(step(MAX_TRIANGLES + 1, 0), 2);
+        assert_eq!(step(10, MAX_PIXELS * 3), 3);
+        assert_eq!(step(MAX_TRIANGLES * 5, MAX_PIXELS * 3), 5);
+    }
+
+    #[test]
+    fn bounds_are_clipped_to_the_canvas() {
+        let triangle = [[-5.0, 2.5, 0.0], [300.0, 10.0, 0.0], [20.0, 40.2, 0.0]];
+
+        assert_eq!(bounds(triangle, 256), [0, 255, 2, 41]);
+    }
+}
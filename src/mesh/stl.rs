@@ -0,0 +1,204 @@
+use super::{cross, length, sub, Mesh};
+use crate::errors::AppError;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+
+/// Size of the header of a binary STL
+const HEADER_SIZE: usize = 80;
+/// Size of a triangle in a binary STL: normal, three vertices and an attribute
+const TRIANGLE_SIZE: usize = 50;
+
+/// Parse an ASCII or a binary STL file. The file is read as a stream, so a big file is never
+/// kept in memory
+pub fn parse<R: Read + Seek>(mut reader: R) -> Result<Mesh, AppError> {
+    let error = |_| AppError::BadRequest("Invalid STL file".to_string());
+
+    let len = reader.seek(SeekFrom::End(0)).map_err(error)?;
+    reader.rewind().map_err(error)?;
+
+    let mut reader = BufReader::new(reader);
+    let mut header = vec![];
+    (&mut reader)
+        .take((HEADER_SIZE + 4) as u64)
+        .read_to_end(&mut header)
+        .map_err(error)?;
+
+    if is_binary(&header, len) {
+        parse_binary(&header, reader)
+    } else {
+        parse_ascii(BufReader::new(header.chain(reader)))
+    }
+}
+
+/// A binary STL can start with "solid" too, so the size is checked before the header
+fn is_binary(header: &[u8], len: u64) -> bool {
+    if header.len() == HEADER_SIZE + 4 {
+        let count = u32::from_le_bytes(header[HEADER_SIZE..].try_into().unwrap());
+        if len == (HEADER_SIZE + 4) as u64 + count as u64 * TRIANGLE_SIZE as u64 {
+            return true;
+        }
+    }
+
+    !header.trim_ascii_start().starts_with(b"solid")
+}
+
+fn parse_binary<R: Read>(header: &[u8], mut reader: R) -> Result<Mesh, AppError> {
+    if header.len() < HEADER_SIZE + 4 {
+        return Err(AppError::BadRequest("Invalid STL file".to_string()));
+    }
+
+    let count = u32::from_le_bytes(header[HEADER_SIZE..].try_into().unwrap());
+
+    let mut mesh = Mesh::default();
+    let mut chunk = [0; TRIANGLE_SIZE];
+    for _ in 0..count {
+        reader
+            .read_exact(&mut chunk)
+            .map_err(|_| AppError::BadRequest("Invalid STL file: it is truncated".to_string()))?;
+
+        let mut triangle = [0; 3];
+        // The first 12 bytes are the normal, recomputed from the vertices when it is needed
+        for (i, vertex) in chunk[12..48].chunks_exact(12).enumerate() {
+            let coord =
+                |i: usize| f32::from_le_bytes(vertex[i * 4..i * 4 + 4].try_into().unwrap()) as f64;
+            triangle[i] = mesh.add_vertex([coord(0), coord(1), coord(2)]);
+        }
+        mesh.add_triangle(triangle);
+    }
+
+    Ok(mesh)
+}
+
+fn parse_ascii<R: BufRead>(reader: R) -> Result<Mesh, AppError> {
+    let mut mesh = Mesh::default();
+    let mut facet: Vec<u32> = Vec::with_capacity(3);
+
+    for line in reader.lines() {
+        let line = line.map_err(|_| AppError::BadRequest("Invalid STL file".to_string()))?;
+        let mut tokens = line.split_ascii_whitespace();
+
+        match tokens.next() {
+            Some("vertex") => {
+                let mut vertex = [0.0; 3];
+                for coord in vertex.iter_mut() {
+                    *coord = tokens.next().and_then(|x| x.parse().ok()).ok_or_else(|| {
+                        AppError::BadRequest("Invalid STL file: bad vertex".to_string())
+                    })?;
+                }
+                facet.push(mesh.add_vertex(vertex));
+            }
+            Some("endfacet") => {
+                if facet.len() != 3 {
+                    return Err(AppError::BadRequest(
+                        "Invalid STL file: a facet has not 3 vertices".to_string(),
+                    ));
+                }
+                mesh.add_triangle([facet[0], facet[1], facet[2]]);
+                facet.clear();
+            }
+            _ => {}
+        }
+    }
+
+    Ok(mesh)
+}
+
+/// Write a mesh as a binary STL file
+pub fn write(mesh: &Mesh) -> Vec<u8> {
+    let mut data = Vec::with_capacity(HEADER_SIZE + 4 + mesh.triangles.len() * TRIANGLE_SIZE);
+
+    let mut header = b"Binary STL written by Verden".to_vec();
+    header.resize(HEADER_SIZE, b' ');
+    data.extend(header);
+    data.extend((mesh.triangles.len() as u32).to_le_bytes());
+
+    for triangle in &mesh.triangles {
+        let [a, b, c] = triangle.map(|x| mesh.vertices[x as usize]);
+
+        let normal = cross(sub(b, a), sub(c, a));
+        let norm = length(normal);
+        let normal = match norm > 0.0 {
+            true => normal.map(|x| x / norm),
+            false => [0.0; 3],
+        };
+
+        for vector in [normal, a, b, c] {
+            for coord in vector {
+                data.extend((coord as f32).to_le_bytes());
+            }
+        }
+        // Attribute byte count
+        data.extend([0; 2]);
+    }
+
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::tests::cube;
+    use std::io::Cursor;
+
+    const ASCII: &str = "solid test
+  facet normal 0 0 1
+    outer loop
+      vertex 0 0 0
+      vertex 1 0 0
+      vertex 0 1 0
+    endloop
+  endfacet
+  facet normal 0 0 1
+    outer loop
+      vertex 1 0 0
+      vertex 1 1 0
+      vertex 0 1 0
+    endloop
+  endfacet
+endsolid test
+";
+
+    #[test]
+    fn parse_ascii() {
+        let mesh = parse(Cursor::new(ASCII)).unwrap();
+
+        assert_eq!(mesh.vertices.len(), 4);
+        assert_eq!(mesh.triangles, [[0, 1, 2], [1, 3, 2]]);
+    }
+
+    #[test]
+    fn parse_binary() {
+        let stl = write(&cube());
+        assert_eq!(stl.len(), HEADER_SIZE + 4 + 12 * TRIANGLE_SIZE);
+
+        let geometry = parse(Cursor::new(stl)).unwrap().geometry();
+        assert_eq!(geometry.triangles, 12);
+        assert_eq!(geometry.vertices, 8);
+        assert!((geometry.volume - 1000.0).abs() < 1e-9);
+        assert!(geometry.watertight);
+    }
+
+    #[test]
+    fn parse_binary_starting_with_solid() {
+        let mut stl = write(&cube());
+        stl[..5].copy_from_slice(b"solid");
+
+        assert_eq!(parse(Cursor::new(stl)).unwrap().triangles.len(), 12);
+    }
+
+    #[test]
+    fn parse_refuses_a_truncated_binary() {
+        let mut stl = write(&cube());
+        stl.truncate(stl.len() - 10);
+
+        assert!(parse(Cursor::new(stl)).is_err());
+    }
+
+    #[test]
+    fn parse_refuses_a_bad_facet() {
+        let ascii = ASCII.replacen("      vertex 0 1 0\n", "", 1);
+        assert!(parse(Cursor::new(ascii)).is_err());
+
+        let ascii = ASCII.replacen("vertex 1 0 0", "vertex 1 x 0", 1);
+        assert!(parse(Cursor::new(ascii)).is_err());
+    }
+}
@@ -0,0 +1,711 @@
+use super::Mesh;
+use crate::errors::AppError;
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    io::{BufRead, BufReader, Cursor, Read, Seek, Write},
+};
+use zip::{write::FileOptions, ZipArchive, ZipWriter};
+
+/// Deepest nesting of components which is followed
+const MAX_DEPTH: usize = 16;
+/// Most triangles of a file, both read from the model files and in the built mesh: build items
+/// and components can repeat an object many times
+const MAX_TRIANGLES: u64 = 20_000_000;
+/// Most vertices of a file, both read from the model files and in the built mesh
+const MAX_VERTICES: u64 = 20_000_000;
+/// Most references to objects, by build items and components, which are followed
+const MAX_REFERENCES: u64 = 1_000_000;
+/// Times the XML read from the archive can be bigger than the file itself may be
+const MAX_EXPANSION: u64 = 16;
+/// Longest tag of the XML, with the text after it
+const MAX_TAG_SIZE: u64 = 1024 * 1024;
+
+/// Affine transform of 3MF, as `m00 m01 m02 m10 m11 m12 m20 m21 m22 m30 m31 m32`. A point is a
+/// row vector multiplied by it: the last row is the translation
+type Transform = [f64; 12];
+
+const IDENTITY: Transform = [1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0];
+
+/// Reference to an object: a build item or a component. The object is in the model file at
+/// `path`, or in the same file of the reference
+#[derive(Clone)]
+struct Reference {
+    path: Option<String>,
+    id: String,
+    transform: Transform,
+}
+
+/// Object of a model file, with a mesh or with components
+#[derive(Default)]
+struct Object {
+    vertices: Vec<[f64; 3]>,
+    triangles: Vec<[usize; 3]>,
+    components: Vec<Reference>,
+}
+
+/// Parsed model file of the archive
+#[derive(Default)]
+struct ModelFile {
+    /// Millimeters in a unit
+    scale: f64,
+    objects: HashMap<String, Object>,
+    build: Vec<Reference>,
+}
+
+/// What can still be read from the archive. An archive of a few KB can expand to GBs of XML, and
+/// an object can be repeated by components nested in many levels
+struct Budget {
+    /// Bytes of XML
+    xml: u64,
+    /// Triangles and vertices read from the model files
+    triangles: u64,
+    vertices: u64,
+    /// References followed
+    references: u64,
+}
+
+/// Parse a 3MF file: a ZIP archive with the model in an XML file, the one which `_rels/.rels`
+/// points to. The objects of the build items are merged, with the transforms of the items and of
+/// their components applied. Components can be in other model files of the archive.
+/// The XML read is limited by `max_size`, the size the file itself can have
+pub fn parse<R: Read + Seek>(reader: R, max_size: u64) -> Result<Mesh, AppError> {
+    let archive = ZipArchive::new(reader).map_err(|_| error())?;
+    let mut parser = Parser {
+        archive,
+        files: HashMap::new(),
+        counts: HashMap::new(),
+        budget: Budget {
+            xml: max_size.saturating_mul(MAX_EXPANSION),
+            triangles: MAX_TRIANGLES,
+            vertices: MAX_VERTICES,
+            references: MAX_REFERENCES,
+        },
+        mesh: Mesh::default(),
+        scale: 1.0,
+    };
+
+    let root = parser.root_model()?;
+    parser.load(&root)?;
+    let model = parser.files.get_mut(&root).ok_or_else(error)?;
+    parser.scale = model.scale;
+    let mut build = std::mem::take(&mut model.build);
+    if build.is_empty() {
+        // Nothing to print: keep the objects of the file
+        let mut ids: Vec<&String> = model.objects.keys().collect();
+        ids.sort();
+        build = ids
+            .into_iter()
+            .map(|id| Reference {
+                path: None,
+                id: id.clone(),
+                transform: IDENTITY,
+            })
+            .collect();
+    }
+
+    // The size of the mesh is known before it is built
+    let (mut triangles, mut vertices) = (0_u64, 0_u64);
+    for item in &build {
+        let (item_triangles, item_vertices) = parser.count(&root, item, 0)?;
+        triangles = triangles.saturating_add(item_triangles);
+        vertices = vertices.saturating_add(item_vertices);
+    }
+    if triangles > MAX_TRIANGLES || vertices > MAX_VERTICES {
+        return Err(too_big());
+    }
+
+    for item in &build {
+        parser.add(&root, item, &IDENTITY, 0)?;
+    }
+
+    Ok(parser.mesh)
+}
+
+fn error() -> AppError {
+    AppError::BadRequest("Invalid 3MF file".to_string())
+}
+
+fn too_big() -> AppError {
+    AppError::BadRequest("The 3MF file is too big".to_string())
+}
+
+/// Name of a file of the archive from a path of a relationship or a component, like
+/// `/3D/3dmodel.model`
+fn archive_path(path: &str) -> String {
+    path.trim_start_matches('/').to_string()
+}
+
+/// Reads the model files of an archive, and merges the objects of the build into a mesh
+struct Parser<R> {
+    archive: ZipArchive<R>,
+    /// Model files read so far, by path
+    files: HashMap<String, ModelFile>,
+    /// Triangles and vertices of an object with all its components, by path and id
+    counts: HashMap<(String, String), (u64, u64)>,
+    budget: Budget,
+    mesh: Mesh,
+    /// Millimeters in a unit of the root model file
+    scale: f64,
+}
+
+impl<R: Read + Seek> Parser<R> {
+    /// Path of the model file. It is the target of the `3dmodel` relationship of `_rels/.rels`;
+    /// old archives without it have a single `3D/*.model` file
+    fn root_model(&mut self) -> Result<String, AppError> {
+        let mut target = None;
+        if let Ok(rels) = self.archive.by_name("_rels/.rels") {
+            let mut xml = BufReader::new(rels.take(self.budget.xml));
+            read_tags(&mut xml, |name, tag| {
+                let is_model = attribute(tag, "Type").is_some_and(|x| x.ends_with("/3dmodel"));
+                if name == "Relationship" && is_model && target.is_none() {
+                    target = attribute(tag, "Target").map(archive_path);
+                }
+                Ok(())
+            })?;
+            self.budget.xml = xml.get_ref().limit();
+        }
+
+        if let Some(target) = target {
+            return Ok(target);
+        }
+
+        let mut names: Vec<&str> = self
+            .archive
+            .file_names()
+            .filter(|name| name.starts_with("3D/") && name.ends_with(".model"))
+            .collect();
+        names.sort();
+
+        names.first().map(|x| x.to_string()).ok_or_else(error)
+    }
+
+    /// Read the model file at `path`, if it was not read yet
+    fn load(&mut self, path: &str) -> Result<(), AppError> {
+        if self.files.contains_key(path) {
+            return Ok(());
+        }
+
+        let model = read_model(&mut self.archive, path, &mut self.budget)?;
+        self.files.insert(path.to_string(), model);
+
+        Ok(())
+    }
+
+    /// Take a reference from the budget
+    fn follow(&mut self, depth: usize) -> Result<(), AppError> {
+        if depth > MAX_DEPTH {
+            return Err(error());
+        }
+        self.budget.references = self.budget.references.checked_sub(1).ok_or_else(too_big)?;
+
+        Ok(())
+    }
+
+    /// Returns the triangles and the vertices the object of a reference, from the model file at
+    /// `path`, adds to the mesh. They are computed once for every object
+    fn count(
+        &mut self,
+        path: &str,
+        reference: &Reference,
+        depth: usize,
+    ) -> Result<(u64, u64), AppError> {
+        self.follow(depth)?;
+
+        let path = reference.path.clone().unwrap_or_else(|| path.to_string());
+        let key = (path, reference.id.clone());
+        if let Some(count) = self.counts.get(&key) {
+            return Ok(*count);
+        }
+
+        self.load(&key.0)?;
+        let object = self.files[&key.0].objects.get(&key.1).ok_or_else(error)?;
+        let mut count = (object.triangles.len() as u64, object.vertices.len() as u64);
+
+        let components = object.components.clone();
+        for component in &components {
+            let (triangles, vertices) = self.count(&key.0, component, depth + 1)?;
+            count.0 = count.0.saturating_add(triangles);
+            count.1 = count.1.saturating_add(vertices);
+        }
+
+        self.counts.insert(key, count);
+
+        Ok(count)
+    }
+
+    /// Add the object of a reference from the model file at `path`, moved by `parent`
+    fn add(
+        &mut self,
+        path: &str,
+        reference: &Reference,
+        parent: &Transform,
+        depth: usize,
+    ) -> Result<(), AppError> {
+        self.follow(depth)?;
+
+        let path = reference.path.clone().unwrap_or_else(|| path.to_string());
+        // Objects without triangles, even with components, add nothing
+        if self.counts.get(&(path.clone(), reference.id.clone())) == Some(&(0, 0)) {
+            return Ok(());
+        }
+        self.load(&path)?;
+
+        let object = self.files[&path]
+            .objects
+            .get(&reference.id)
+            .ok_or_else(error)?;
+
+        let transform = multiply(&reference.transform, parent);
+        let indexes: Vec<u32> = object
+            .vertices
+            .iter()
+            .map(|vertex| {
+                let vertex = apply(&transform, vertex).map(|x| x * self.scale);
+                self.mesh.add_vertex(vertex)
+            })
+            .collect();
+        // A mirroring transform turns the triangles inside out
+        let mirrored = determinant(&transform) < 0.0;
+        for triangle in &object.triangles {
+            let [a, b, c] = triangle.map(|x| indexes[x]);
+            match mirrored {
+                true => self.mesh.add_triangle([a, c, b]),
+                false => self.mesh.add_triangle([a, b, c]),
+            }
+        }
+
+        let components = object.components.clone();
+        for component in &components {
+            self.add(&path, component, &transform, depth + 1)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Read the objects and the build items of a model file, taking its XML, its triangles and its
+/// vertices from the budget
+fn read_model<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    path: &str,
+    budget: &mut Budget,
+) -> Result<ModelFile, AppError> {
+    let entry = archive.by_name(path).map_err(|_| error())?;
+    let mut xml = BufReader::new(entry.take(budget.xml));
+
+    let mut model = ModelFile {
+        scale: 1.0,
+        ..Default::default()
+    };
+    // Object whose content is being read, with its id
+    let mut object: Option<(String, Object)> = None;
+
+    let result = read_tags(&mut xml, |name, tag| {
+        match name {
+            "model" => {
+                model.scale = match attribute(tag, "unit") {
+                    Some("micron") => 0.001,
+                    Some("centimeter") => 10.0,
+                    Some("inch") => 25.4,
+                    Some("foot") => 304.8,
+                    Some("meter") => 1000.0,
+                    _ => 1.0,
+                };
+            }
+            "object" => {
+                if let Some((id, current)) = object.take() {
+                    model.objects.insert(id, current);
+                }
+                let id = attribute(tag, "id").ok_or_else(error)?;
+                object = Some((id.to_string(), Object::default()));
+            }
+            "vertex" => {
+                let (_, current) = object.as_mut().ok_or_else(error)?;
+                budget.vertices = budget.vertices.checked_sub(1).ok_or_else(too_big)?;
+
+                let mut vertex = [0.0; 3];
+                for (coord, key) in vertex.iter_mut().zip(["x", "y", "z"]) {
+                    *coord = attribute(tag, key)
+                        .and_then(|x| x.parse().ok())
+                        .ok_or_else(error)?;
+                }
+                current.vertices.push(vertex);
+            }
+            "triangle" => {
+                let (_, current) = object.as_mut().ok_or_else(error)?;
+                budget.triangles = budget.triangles.checked_sub(1).ok_or_else(too_big)?;
+
+                let mut triangle = [0; 3];
+                for (index, key) in triangle.iter_mut().zip(["v1", "v2", "v3"]) {
+                    *index = attribute(tag, key)
+                        .and_then(|x| x.parse::<usize>().ok())
+                        .filter(|x| *x < current.vertices.len())
+                        .ok_or_else(error)?;
+                }
+                current.triangles.push(triangle);
+            }
+            "component" => {
+                let (_, current) = object.as_mut().ok_or_else(error)?;
+                current.components.push(reference(tag)?);
+            }
+            "item" => model.build.push(reference(tag)?),
+            _ => {}
+        }
+
+        Ok(())
+    });
+
+    // The whole budget is used: the file was cut
+    budget.xml = xml.get_ref().limit();
+    if budget.xml == 0 {
+        return Err(too_big());
+    }
+    result?;
+
+    if let Some((id, current)) = object {
+        model.objects.insert(id, current);
+    }
+
+    Ok(model)
+}
+
+/// Read a build item or a component
+fn reference(tag: &str) -> Result<Reference, AppError> {
+    let transform = match attribute(tag, "transform") {
+        Some(value) => {
+            let values = value
+                .split_whitespace()
+                .map(|x| x.parse::<f64>().map_err(|_| error()))
+                .collect::<Result<Vec<f64>, AppError>>()?;
+            values.try_into().map_err(|_| error())?
+        }
+        None => IDENTITY,
+    };
+
+    Ok(Reference {
+        // Production extension, like `p:path="/3D/Objects/object_1.model"`
+        path: attribute(tag, "path").map(archive_path),
+        id: attribute(tag, "objectid").ok_or_else(error)?.to_string(),
+        transform,
+    })
+}
+
+/// Transform which applies `a` and then `b`
+fn multiply(a: &Transform, b: &Transform) -> Transform {
+    let mut result = [0.0; 12];
+    for row in 0..4 {
+        for column in 0..3 {
+            result[row * 3 + column] = (0..3)
+                .map(|k| a[row * 3 + k] * b[k * 3 + column])
+                .sum::<f64>();
+        }
+    }
+    // Translation
+    for column in 0..3 {
+        result[9 + column] += b[9 + column];
+    }
+
+    result
+}
+
+fn apply(t: &Transform, [x, y, z]: &[f64; 3]) -> [f64; 3] {
+    [
+        x * t[0] + y * t[3] + z * t[6] + t[9],
+        x * t[1] + y * t[4] + z * t[7] + t[10],
+        x * t[2] + y * t[5] + z * t[8] + t[11],
+    ]
+}
+
+fn determinant(t: &Transform) -> f64 {
+    t[0] * (t[4] * t[8] - t[5] * t[7]) - t[1] * (t[3] * t[8] - t[5] * t[6])
+        + t[2] * (t[3] * t[7] - t[4] * t[6])
+}
+
+/// Call `f` with the name and the content of every opening tag of an XML file. The file is read
+/// one tag at a time, and a tag longer than `MAX_TAG_SIZE` is refused
+fn read_tags<B: BufRead>(
+    mut xml: B,
+    mut f: impl FnMut(&str, &str) -> Result<(), AppError>,
+) -> Result<(), AppError> {
+    let mut buffer = vec![];
+
+    // Skip what comes before the first tag
+    read_tag(&mut xml, &mut buffer)?;
+
+    loop {
+        buffer.clear();
+        if read_tag(&mut xml, &mut buffer)? == 0 {
+            break;
+        }
+        let tag = std::str::from_utf8(&buffer).map_err(|_| error())?;
+        let tag = &tag[..tag.find('>').ok_or_else(error)?];
+        let name = tag
+            .split(|c: char| c.is_ascii_whitespace() || c == '/')
+            .next()
+            .unwrap_or_default();
+        // Elements can use a namespace prefix
+        let name = name.rsplit(':').next().unwrap_or_default();
+
+        f(name, tag)?;
+    }
+
+    Ok(())
+}
+
+/// Read until the next `<`, returning the bytes read
+fn read_tag<B: BufRead>(xml: &mut B, buffer: &mut Vec<u8>) -> Result<usize, AppError> {
+    let read = xml
+        .take(MAX_TAG_SIZE)
+        .read_until(b'<', buffer)
+        .map_err(|_| error())?;
+
+    if read as u64 == MAX_TAG_SIZE && buffer.last() != Some(&b'<') {
+        return Err(too_big());
+    }
+
+    Ok(read)
+}
+
+/// Returns the value of the attribute `key` of a tag. Attributes of other namespaces are found
+/// without their prefix, like `path` for `p:path`
+fn attribute<'a>(tag: &'a str, key: &str) -> Option<&'a str> {
+    let mut rest = tag;
+    while let Some(position) = rest.find(key) {
+        let before = rest[..position].chars().last();
+        rest = &rest[position + key.len()..];
+
+        if !before.is_some_and(|c| c.is_ascii_whitespace() || c == ':') {
+            continue;
+        }
+        let value = match rest.trim_start().strip_prefix('=') {
+            Some(value) => value.trim_start(),
+            None => continue,
+        };
+        let quote = value.chars().next()?;
+        if quote == '"' || quote == '\'' {
+            let value = &value[1..];
+            return value.find(quote).map(|end| &value[..end]);
+        }
+    }
+
+    None
+}
+
+/// Write a mesh as a 3MF file, with a single object. Lengths are in millimeters
+pub fn write(mesh: &Mesh) -> Result<Vec<u8>, AppError> {
+    let mut model = String::from(concat!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+        "\n",
+        r#"<model unit="millimeter" xml:lang="en-US" xmlns="http://schemas.microsoft.com/3dmanufacturing/core/2015/02">"#,
+        "\n<resources>\n",
+        r#"<object id="1" type="model">"#,
+        "\n<mesh>\n<vertices>\n",
+    ));
+    for [x, y, z] in &mesh.vertices {
+        writeln!(model, r#"<vertex x="{}" y="{}" z="{}"/>"#, x, y, z).unwrap();
+    }
+    model.push_str("</vertices>\n<triangles>\n");
+    for [a, b, c] in &mesh.triangles {
+        writeln!(model, r#"<triangle v1="{}" v2="{}" v3="{}"/>"#, a, b, c).unwrap();
+    }
+    model.push_str(concat!(
+        "</triangles>\n</mesh>\n</object>\n</resources>\n",
+        r#"<build><item objectid="1"/></build>"#,
+        "\n</model>\n",
+    ));
+
+    let files = [
+        (
+            "[Content_Types].xml",
+            concat!(
+                r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+                r#"<Types xmlns="http://schemas.openxmlformats.org/package/2006/content-types">"#,
+                r#"<Default Extension="rels" ContentType="application/vnd.openxmlformats-package.relationships+xml"/>"#,
+                r#"<Default Extension="model" ContentType="application/vnd.ms-package.3dmanufacturing-3dmodel+xml"/>"#,
+                r#"</Types>"#,
+            ),
+        ),
+        (
+            "_rels/.rels",
+            concat!(
+                r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+                r#"<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships">"#,
+                r#"<Relationship Target="/3D/3dmodel.model" Id="rel0" Type="http://schemas.microsoft.com/3dmanufacturing/2013/01/3dmodel"/>"#,
+                r#"</Relationships>"#,
+            ),
+        ),
+        ("3D/3dmodel.model", model.as_str()),
+    ];
+
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    for (name, content) in files {
+        zip.start_file(name, FileOptions::default())
+            .map_err(|error| error.to_string())?;
+        zip.write_all(content.as_bytes())?;
+    }
+    let cursor = zip.finish().map_err(|error| error.to_string())?;
+
+    Ok(cursor.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::{cross, dot, tests::cube};
+
+    /// The cube of the tests as a 3MF object
+    fn cube_object(id: &str) -> String {
+        let mesh = cube();
+        let mut object = format!(r#"<object id="{}"><mesh><vertices>"#, id);
+        for [x, y, z] in &mesh.vertices {
+            write!(object, r#"<vertex x="{}" y="{}" z="{}"/>"#, x, y, z).unwrap();
+        }
+        object.push_str("</vertices><triangles>");
+        for [a, b, c] in &mesh.triangles {
+            write!(object, r#"<triangle v1="{}" v2="{}" v3="{}"/>"#, a, b, c).unwrap();
+        }
+        object.push_str("</triangles></mesh></object>");
+
+        object
+    }
+
+    /// Archive with the model files, without relationships
+    fn archive(files: &[(&str, String)]) -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, content) in files {
+            zip.start_file(*name, FileOptions::default()).unwrap();
+            zip.write_all(content.as_bytes()).unwrap();
+        }
+
+        zip.finish().unwrap().into_inner()
+    }
+
+    fn model(resources: &str, build: &str) -> String {
+        format!(
+            r#"<?xml version="1.0"?><model unit="millimeter" xmlns:p="production"><resources>{}</resources><build>{}</build></model>"#,
+            resources, build
+        )
+    }
+
+    fn parse_files(files: &[(&str, String)]) -> Result<Mesh, AppError> {
+        parse(Cursor::new(archive(files)), 1024 * 1024)
+    }
+
+    #[test]
+    fn write_and_parse() {
+        let data = write(&cube()).unwrap();
+        let geometry = parse(Cursor::new(data), 1024 * 1024).unwrap().geometry();
+
+        assert_eq!(geometry.triangles, 12);
+        assert!((geometry.volume - 1000.0).abs() < 1e-9);
+        assert!(geometry.watertight);
+    }
+
+    #[test]
+    fn parse_applies_the_transforms() {
+        let resources = cube_object("1")
+            + r#"<object id="2"><components><component objectid="1" transform="2 0 0 0 2 0 0 0 3 0 0 0"/></components></object>"#;
+        let build = r#"<item objectid="2" transform="1 0 0 0 1 0 0 0 1 100 50 5"/>"#;
+        let geometry = parse_files(&[("3D/3dmodel.model", model(&resources, build))])
+            .unwrap()
+            .geometry();
+
+        assert_eq!(geometry.bounding_box.min, [100.0, 50.0, 5.0]);
+        assert_eq!(geometry.bounding_box.max, [120.0, 70.0, 35.0]);
+        assert!((geometry.volume - 12000.0).abs() < 1e-9);
+        assert!(geometry.watertight);
+    }
+
+    #[test]
+    fn parse_keeps_a_mirrored_object_outwards() {
+        let build = r#"<item objectid="1" transform="1 0 0 0 1 0 0 0 -1 0 0 10"/>"#;
+        let mesh = parse_files(&[("3D/3dmodel.model", model(&cube_object("1"), build))]).unwrap();
+
+        // The signed volume is positive when the triangles face outwards
+        let volume: f64 = mesh
+            .triangles
+            .iter()
+            .map(|triangle| {
+                let [a, b, c] = triangle.map(|x| mesh.vertices[x as usize]);
+                dot(a, cross(b, c)) / 6.0
+            })
+            .sum();
+        assert!((volume - 1000.0).abs() < 1e-9);
+        assert!(mesh.geometry().watertight);
+    }
+
+    #[test]
+    fn parse_follows_the_relationships_and_other_files() {
+        let rels = r#"<Relationships><Relationship Target="/3D/main.model" Id="rel0" Type="http://schemas.microsoft.com/3dmanufacturing/2013/01/3dmodel"/></Relationships>"#;
+        let main = model(
+            r#"<object id="3"><components><p:component p:path="/3D/Objects/cube.model" objectid="7"/></components></object>"#,
+            r#"<item objectid="3"/><item objectid="3" transform="1 0 0 0 1 0 0 0 1 20 0 0"/>"#,
+        );
+        let geometry = parse_files(&[
+            ("_rels/.rels", rels.to_string()),
+            // Found first without the relationships
+            (
+                "3D/a.model",
+                model(&cube_object("1"), r#"<item objectid="1"/>"#),
+            ),
+            ("3D/main.model", main),
+            ("3D/Objects/cube.model", model(&cube_object("7"), "")),
+        ])
+        .unwrap()
+        .geometry();
+
+        assert_eq!(geometry.triangles, 24);
+        assert_eq!(geometry.bounding_box.max, [30.0, 10.0, 10.0]);
+        assert!((geometry.volume - 2000.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn parse_uses_the_unit() {
+        let data = model(&cube_object("1"), r#"<item objectid="1"/>"#)
+            .replace(r#"unit="millimeter""#, r#"unit="centimeter""#);
+        let geometry = parse_files(&[("3D/3dmodel.model", data)])
+            .unwrap()
+            .geometry();
+
+        assert_eq!(geometry.size, [100.0; 3]);
+    }
+
+    #[test]
+    fn parse_refuses_a_cycle() {
+        let resources =
+            r#"<object id="1"><components><component objectid="1"/></components></object>"#;
+        let data = model(resources, r#"<item objectid="1"/>"#);
+
+        assert!(parse_files(&[("3D/3dmodel.model", data)]).is_err());
+    }
+
+    #[test]
+    fn parse_refuses_too_many_instances() {
+        // Every level repeats the one below 100 times: 100^15 triangles in a few KB
+        let mut resources = cube_object("0");
+        for level in 1..16 {
+            resources += &format!(r#"<object id="{}"><components>"#, level);
+            for _ in 0..100 {
+                resources += &format!(r#"<component objectid="{}"/>"#, level - 1);
+            }
+            resources += "</components></object>";
+        }
+        let data = model(&resources, r#"<item objectid="15"/>"#);
+
+        assert!(parse_files(&[("3D/3dmodel.model", data)]).is_err());
+    }
+
+    #[test]
+    fn parse_refuses_too_much_xml() {
+        let data = model(&cube_object("1"), r#"<item objectid="1"/>"#);
+        let data = data.replace(
+            "<resources>",
+            &format!("<resources>{}", " ".repeat(1 << 16)),
+        );
+
+        let zip = archive(&[("3D/3dmodel.model", data)]);
+        assert!(parse(Cursor::new(&zip), 1 << 16).is_ok());
+        assert!(parse(Cursor::new(&zip), 1 << 12).is_err());
+    }
+}
@@ -0,0 +1,451 @@
+use crate::{
+    errors::AppError,
+    files::{storage_key, STREAM_CHUNK_SIZE},
+    model::models::{ModelUpload, ModelUser},
+    state::AppState,
+};
+use axum::body::Body;
+use chrono::{Datelike, Local, NaiveDateTime, Timelike};
+use flate2::{write::DeflateEncoder, Compression};
+use serde::Serialize;
+use sqlx::types::JsonValue;
+use std::{collections::HashSet, io::Write};
+
+/// Biggest archive which can be built: offsets and sizes of a ZIP file, without the ZIP64
+/// extensions, are 32 bits. It leaves room for the headers and the compressed files that grow
+pub const MAX_BUNDLE_SIZE: u64 = 4_000_000_000;
+
+/// Extensions of the files which are already compressed, stored as they are
+const COMPRESSED_EXTENSIONS: [&str; 7] = ["png", "jpg", "jpeg", "gif", "webp", "3mf", "bgcode"];
+
+/// Content of a file of the archive
+pub enum BundleSource {
+    Data(Vec<u8>),
+    /// File of the storage, with its size
+    Stored {
+        key: String,
+        size: u64,
+    },
+}
+
+/// File written into the archive of a model
+pub struct BundleFile {
+    pub name: String,
+    pub modified: NaiveDateTime,
+    pub source: BundleSource,
+}
+
+/// File described by the manifest
+#[derive(Serialize)]
+struct ManifestFile<'a> {
+    name: &'a str,
+    size: u64,
+    sha256: Option<&'a str>,
+    geometry: Option<&'a JsonValue>,
+}
+
+/// `manifest.json` of the archive
+#[derive(Serialize)]
+struct Manifest<'a> {
+    id: i32,
+    name: &'a str,
+    description: Option<&'a str>,
+    duration: f64,
+    height: f64,
+    weight: f64,
+    printer: Option<&'a str>,
+    material: Option<&'a str>,
+    author: Option<&'a JsonValue>,
+    created: NaiveDateTime,
+    updated: NaiveDateTime,
+    files: Vec<ManifestFile<'a>>,
+}
+
+/// List the files of the archive of a model: a `README.md`, a `manifest.json` and all the
+/// uploads. Uploads whose file is missing are left out
+pub async fn files(state: &AppState, model: &ModelUser) -> Result<Vec<BundleFile>, AppError> {
+    let uploads = ModelUpload::find_by_model(state, model.id).await?;
+
+    let mut names = HashSet::from(["README.md".to_string(), "manifest.json".to_string()]);
+    let mut stored = vec![];
+    let mut manifest_files = vec![];
+    let mut total = 0;
+    for upload in &uploads {
+        let key = storage_key(&upload.filepath);
+        let info = match state.storage.stat(key).await {
+            Ok(info) => info,
+            Err(error) => {
+                tracing::warn!("File `{}` is not bundled: {:?}", key, error);
+                continue;
+            }
+        };
+
+        let name = unique_name(&mut names, upload.filename.as_deref().unwrap_or(key));
+        total += info.size;
+        stored.push(BundleFile {
+            name,
+            modified: info.modified,
+            source: BundleSource::Stored {
+                key: key.to_string(),
+                size: info.size,
+            },
+        });
+        manifest_files.push((upload, info.size));
+    }
+
+    if total > MAX_BUNDLE_SIZE {
+        return Err(AppError::BadRequest(
+            "Model is too big to be downloaded as an archive".to_string(),
+        ));
+    }
+
+    let manifest = Manifest {
+        id: model.id,
+        name: &model.name,
+        description: model.description.as_deref(),
+        duration: model.duration,
+        height: model.height,
+        weight: model.weight,
+        printer: model.printer.as_deref(),
+        material: model.material.as_deref(),
+        author: model.author.as_ref(),
+        created: model.created,
+        updated: model.updated,
+        files: manifest_files
+            .iter()
+            .zip(&stored)
+            .map(|((upload, size), file)| ManifestFile {
+                name: &file.name,
+                size: *size,
+                sha256: upload.hash.as_deref(),
+                geometry: upload.geometry.as_ref(),
+            })
+            .collect(),
+    };
+    let manifest = serde_json::to_vec_pretty(&manifest).map_err(|error| error.to_string())?;
+
+    let now = Local::now().naive_utc();
+    let mut files = vec![
+        BundleFile {
+            name: "README.md".to_string(),
+            modified: now,
+            source: BundleSource::Data(readme(model, &stored).into_bytes()),
+        },
+        BundleFile {
+            name: "manifest.json".to_string(),
+            modified: now,
+            source: BundleSource::Data(manifest),
+        },
+    ];
+    files.extend(stored);
+
+    Ok(files)
+}
+
+/// Returns `name`, or `name (2)`, `name (3)`... if it is already in the archive
+fn unique_name(names: &mut HashSet<String>, name: &str) -> String {
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem, format!(".{}", ext)),
+        _ => (name, String::new()),
+    };
+
+    let mut candidate = name.to_string();
+    let mut count = 1;
+    while names.contains(&candidate.to_lowercase()) {
+        count += 1;
+        candidate = format!("{} ({}){}", stem, count, ext);
+    }
+    names.insert(candidate.to_lowercase());
+
+    candidate
+}
+
+/// Text of the `README.md` of the archive
+fn readme(model: &ModelUser, files: &[BundleFile]) -> String {
+    let mut text = format!("# {}\n\n", model.name);
+
+    if let Some(description) = model.description.as_deref().map(str::trim) {
+        if !description.is_empty() {
+            text.push_str(&format!("{}\n\n", description));
+        }
+    }
+
+    if let Some(printer) = &model.printer {
+        text.push_str(&format!("- Printer: {}\n", printer));
+    }
+    if let Some(material) = &model.material {
+        text.push_str(&format!("- Material: {}\n", material));
+    }
+    if model.duration > 0.0 {
+        text.push_str(&format!("- Duration: {}\n", model.duration));
+    }
+    if model.weight > 0.0 {
+        text.push_str(&format!("- Weight: {}\n", model.weight));
+    }
+    if model.height > 0.0 {
+        text.push_str(&format!("- Height: {}\n", model.height));
+    }
+
+    text.push_str("\n## Files\n\n");
+    for file in files {
+        text.push_str(&format!("- {}\n", file.name));
+    }
+
+    text
+}
+
+/// Stream a ZIP archive of `files`. The archive is built while it is sent, reading the stored
+/// files by chunks. A file which can not be read breaks the stream
+pub fn stream(state: AppState, files: Vec<BundleFile>) -> Body {
+    let (mut sender, body) = Body::channel();
+
+    tokio::spawn(async move {
+        let mut zip = ZipStream::default();
+
+        for file in files {
+            let ext = file
+                .name
+                .rsplit_once('.')
+                .map(|(_, ext)| ext.to_lowercase());
+            let compress = !ext.is_some_and(|ext| COMPRESSED_EXTENSIONS.contains(&ext.as_str()));
+            let mut data = zip.start_file(&file.name, file.modified, compress);
+
+            match file.source {
+                BundleSource::Data(content) => data.extend(zip.write(&content)),
+                BundleSource::Stored { key, size } => {
+                    let mut position = 0;
+                    while position < size {
+                        let chunk_end = (size - 1).min(position + STREAM_CHUNK_SIZE - 1);
+                        let chunk = match state.storage.get_range(&key, position, chunk_end).await {
+                            Ok(chunk) => chunk,
+                            Err(error) => {
+                                tracing::warn!("File `{}` is not bundled: {:?}", key, error);
+                                sender.abort();
+                                return;
+                            }
+                        };
+
+                        // Compressing a chunk takes a while: it is done out of the runtime threads,
+                        // moving the writer there and back
+                        let written = tokio::task::spawn_blocking(move || {
+                            let written = zip.write(&chunk);
+                            (zip, written)
+                        })
+                        .await;
+                        match written {
+                            Ok((writer, written)) => {
+                                zip = writer;
+                                data.extend(written);
+                            }
+                            Err(error) => {
+                                tracing::error!("File `{}` is not bundled: {:?}", key, error);
+                                sender.abort();
+                                return;
+                            }
+                        }
+
+                        // The client went away
+                        if sender
+                            .send_data(std::mem::take(&mut data).into())
+                            .await
+                            .is_err()
+                        {
+                            return;
+                        }
+                        position = chunk_end + 1;
+                    }
+                }
+            }
+
+            data.extend(zip.end_file());
+            if sender.send_data(data.into()).await.is_err() {
+                return;
+            }
+        }
+
+        let _ = sender.send_data(zip.finish().into()).await;
+    });
+
+    body
+}
+
+/// Entry of the central directory of a ZIP archive
+struct ZipEntry {
+    name: String,
+    method: u16,
+    time: u16,
+    date: u16,
+    crc: u32,
+    compressed_size: u32,
+    size: u32,
+    offset: u32,
+}
+
+/// File of a ZIP archive while it is written
+struct OpenEntry {
+    entry: ZipEntry,
+    hasher: crc32fast::Hasher,
+    encoder: Option<DeflateEncoder<Vec<u8>>>,
+}
+
+/// Writer of a ZIP archive which is never read back, so it can be sent while it is built. The
+/// `zip` crate seeks back to write the sizes and the checksum of a file: here they are written
+/// after the file, in a data descriptor. Every call returns the bytes to send
+#[derive(Default)]
+struct ZipStream {
+    /// Bytes of the archive returned so far
+    offset: u64,
+    entries: Vec<ZipEntry>,
+    current: Option<OpenEntry>,
+}
+
+impl ZipStream {
+    /// Bit 3: sizes and checksum are in the data descriptor. Bit 11: the name is UTF-8
+    const FLAGS: u16 = 1 << 3 | 1 << 11;
+    /// Version 2.0, needed by the deflate method and the data descriptor
+    const VERSION: u16 = 20;
+    const STORED: u16 = 0;
+    const DEFLATED: u16 = 8;
+
+    /// Keep track of the bytes which are going to be sent
+    fn emit(&mut self, data: Vec<u8>) -> Vec<u8> {
+        self.offset += data.len() as u64;
+        data
+    }
+
+    /// Start a new file, with its local header
+    fn start_file(&mut self, name: &str, modified: NaiveDateTime, compress: bool) -> Vec<u8> {
+        let (time, date) = dos_datetime(modified);
+        let entry = ZipEntry {
+            name: name.to_string(),
+            method: if compress {
+                Self::DEFLATED
+            } else {
+                Self::STORED
+            },
+            time,
+            date,
+            crc: 0,
+            compressed_size: 0,
+            size: 0,
+            offset: self.offset as u32,
+        };
+
+        let mut header = vec![];
+        header.extend(0x04034b50u32.to_le_bytes());
+        header.extend(Self::VERSION.to_le_bytes());
+        header.extend(Self::FLAGS.to_le_bytes());
+        header.extend(entry.method.to_le_bytes());
+        header.extend(entry.time.to_le_bytes());
+        header.extend(entry.date.to_le_bytes());
+        // Checksum, compressed size and size are in the data descriptor
+        header.extend([0; 12]);
+        header.extend((entry.name.len() as u16).to_le_bytes());
+        header.extend(0u16.to_le_bytes());
+        header.extend(entry.name.as_bytes());
+
+        self.current = Some(OpenEntry {
+            entry,
+            hasher: crc32fast::Hasher::new(),
+            encoder: compress.then(|| DeflateEncoder::new(vec![], Compression::default())),
+        });
+
+        self.emit(header)
+    }
+
+    /// Write a chunk of the current file
+    fn write(&mut self, data: &[u8]) -> Vec<u8> {
+        let current = self.current.as_mut().expect("A file is started");
+        current.hasher.update(data);
+        current.entry.size = current.entry.size.wrapping_add(data.len() as u32);
+
+        let output = match &mut current.encoder {
+            Some(encoder) => {
+                encoder
+                    .write_all(data)
+                    .expect("Writes into a vector do not fail");
+                std::mem::take(encoder.get_mut())
+            }
+            None => data.to_vec(),
+        };
+        current.entry.compressed_size = current
+            .entry
+            .compressed_size
+            .wrapping_add(output.len() as u32);
+
+        self.emit(output)
+    }
+
+    /// End the current file, with its data descriptor
+    fn end_file(&mut self) -> Vec<u8> {
+        let mut current = self.current.take().expect("A file is started");
+
+        let mut output = match current.encoder {
+            Some(encoder) => encoder.finish().expect("Writes into a vector do not fail"),
+            None => vec![],
+        };
+        current.entry.compressed_size = current
+            .entry
+            .compressed_size
+            .wrapping_add(output.len() as u32);
+        current.entry.crc = current.hasher.finalize();
+
+        output.extend(0x08074b50u32.to_le_bytes());
+        output.extend(current.entry.crc.to_le_bytes());
+        output.extend(current.entry.compressed_size.to_le_bytes());
+        output.extend(current.entry.size.to_le_bytes());
+
+        self.entries.push(current.entry);
+
+        self.emit(output)
+    }
+
+    /// Write the central directory, which ends the archive
+    fn finish(mut self) -> Vec<u8> {
+        let start = self.offset as u32;
+
+        let mut output = vec![];
+        for entry in &self.entries {
+            output.extend(0x02014b50u32.to_le_bytes());
+            // Made by Unix, so the permissions of the external attributes are read
+            output.extend((3 << 8 | Self::VERSION).to_le_bytes());
+            output.extend(Self::VERSION.to_le_bytes());
+            output.extend(Self::FLAGS.to_le_bytes());
+            output.extend(entry.method.to_le_bytes());
+            output.extend(entry.time.to_le_bytes());
+            output.extend(entry.date.to_le_bytes());
+            output.extend(entry.crc.to_le_bytes());
+            output.extend(entry.compressed_size.to_le_bytes());
+            output.extend(entry.size.to_le_bytes());
+            output.extend((entry.name.len() as u16).to_le_bytes());
+            // Extra field, comment, disk number and internal attributes
+            output.extend([0; 8]);
+            output.extend((0o100644u32 << 16).to_le_bytes());
+            output.extend(entry.offset.to_le_bytes());
+            output.extend(entry.name.as_bytes());
+        }
+        let size = output.len() as u32;
+
+        output.extend(0x06054b50u32.to_le_bytes());
+        // Number of this disk and of the disk with the central directory
+        output.extend([0; 4]);
+        output.extend((self.entries.len() as u16).to_le_bytes());
+        output.extend((self.entries.len() as u16).to_le_bytes());
+        output.extend(size.to_le_bytes());
+        output.extend(start.to_le_bytes());
+        output.extend(0u16.to_le_bytes());
+
+        self.emit(output)
+    }
+}
+
+/// Time and date in the MS-DOS format used by ZIP archives, which starts from 1980
+fn dos_datetime(datetime: NaiveDateTime) -> (u16, u16) {
+    let time = (datetime.hour() << 11 | datetime.minute() << 5 | (datetime.second() / 2)) as u16;
+    let date = match datetime.year() {
+        year if year < 1980 => 1 << 5 | 1,
+        year => ((year - 1980).min(127) as u32) << 9 | datetime.month() << 5 | datetime.day(),
+    } as u16;
+
+    (time, date)
+}
@@ -1,2 +1,3 @@
+pub mod bundle;
 pub mod models;
 pub mod routes;
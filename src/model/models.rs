@@ -1,9 +1,13 @@
-use crate::{config::CONFIG, db::get_client, errors::AppError, json::number_from_string};
-use serde_json::json;
+use crate::{
+    config::Configuration, errors::AppError, files::SavedUpload, json::number_from_string,
+    state::AppState,
+};
+use rand::{distributions::Alphanumeric, Rng};
 use sqlx::types::JsonValue;
 use sqlx::Row;
+use std::path::PathBuf;
 
-use chrono::{Local, NaiveDateTime};
+use chrono::{Duration, Local, NaiveDateTime};
 use serde::{Deserialize, Serialize};
 use validator::Validate;
 
@@ -14,14 +18,19 @@ pub struct Model {
     #[validate(length(min = 2, message = "Can not be empty"))]
     name: String,
     description: Option<String>,
+    /// Print time, in hours
     duration: f64,
+    /// In millimeters
     height: f64,
+    /// Filament used, in grams
     weight: f64,
     printer: Option<String>,
     material: Option<String>,
     author_id: i32,
     created: NaiveDateTime,
     updated: NaiveDateTime,
+    /// Times the model has been downloaded as a ZIP archive
+    downloads: i32,
 }
 
 /// Payload used for model creation
@@ -46,20 +55,38 @@ pub struct ModelFilter {
     pub q: String,
 }
 
+/// Query used to convert a 3D file
+#[derive(Deserialize)]
+pub struct UploadFormat {
+    /// Extension of the format: `stl`, `obj` or `3mf`
+    pub format: String,
+}
+
+/// Payload used to attach an already stored file to a model
+#[derive(Deserialize)]
+pub struct ModelUploadHash {
+    /// SHA-256 digest of the file content, in hex. Images can not be attached by digest
+    pub hash: String,
+    /// Name of the file on the computer of the user
+    pub filename: Option<String>,
+}
+
 #[derive(Serialize, sqlx::FromRow)]
 pub struct ModelUser {
     pub id: i32,
-    name: String,
-    description: Option<String>,
-    duration: f64,
-    height: f64,
-    weight: f64,
-    printer: Option<String>,
-    material: Option<String>,
+    pub name: String,
+    pub description: Option<String>,
+    pub duration: f64,
+    pub height: f64,
+    pub weight: f64,
+    pub printer: Option<String>,
+    pub material: Option<String>,
     author_id: i32,
-    created: NaiveDateTime,
-    updated: NaiveDateTime,
-    author: Option<JsonValue>,
+    pub created: NaiveDateTime,
+    pub updated: NaiveDateTime,
+    /// Times the model has been downloaded as a ZIP archive
+    pub downloads: i32,
+    pub author: Option<JsonValue>,
     uploads: Option<JsonValue>,
     likes: Option<JsonValue>,
 }
@@ -70,6 +97,34 @@ pub struct ModelUpload {
     pub model_id: i32,
     pub filepath: String,
     created: NaiveDateTime,
+    /// Values computed from a 3D file, `None` for the other files
+    pub geometry: Option<JsonValue>,
+    /// SHA-256 digest of the file content
+    pub hash: Option<String>,
+    /// Name of the file on the computer of the user
+    pub filename: Option<String>,
+    /// Size of the file, in bytes. It is counted in the storage used by the model author
+    pub size: i64,
+    /// Values read from a G-code file, `None` for the other files
+    pub print_info: Option<JsonValue>,
+}
+
+/// A file sent in chunks with the tus protocol. It becomes a `ModelUpload` when all its bytes
+/// are received
+#[derive(sqlx::FromRow)]
+pub struct PendingUpload {
+    id: i32,
+    pub token: String,
+    pub user_id: i32,
+    /// Extension declared by the client
+    pub ext: String,
+    /// Name of the file on the computer of the user
+    pub filename: Option<String>,
+    /// Size of the whole file, in bytes
+    pub length: i64,
+    /// Bytes received and written, where the next chunk starts
+    pub received: i64,
+    updated: NaiveDateTime,
 }
 
 impl Model {
@@ -97,12 +152,13 @@ impl Model {
             author_id,
             created: now,
             updated: now,
+            downloads: 0,
         }
     }
 
     /// Create a new model
-    pub async fn create(model: Model) -> Result<Model, AppError> {
-        let pool = unsafe { get_client() };
+    pub async fn create(state: &AppState, model: Model) -> Result<Model, AppError> {
+        let pool = &state.pool;
 
         model
             .validate()
@@ -131,8 +187,8 @@ impl Model {
     }
 
     /// Edit a model
-    pub async fn edit(id: i32, model: Model) -> Result<Model, AppError> {
-        let pool = unsafe { get_client() };
+    pub async fn edit(state: &AppState, id: i32, model: Model) -> Result<Model, AppError> {
+        let pool = &state.pool;
 
         model
             .validate()
@@ -160,13 +216,13 @@ impl Model {
     }
 
     /// Returns the model with id = `model_id`
-    pub async fn find_by_id(model_id: i32) -> Result<ModelUser, AppError> {
-        let pool = unsafe { get_client() };
+    pub async fn find_by_id(state: &AppState, model_id: i32) -> Result<ModelUser, AppError> {
+        let pool = &state.pool;
 
         let rec: ModelUser = sqlx::query_as(
             r#"
                 WITH model_uploads AS (
-                    SELECT models.id, json_agg(uploads.*) filter(WHERE uploads.* IS NOT NULL) AS uploads
+                    SELECT models.id, json_agg(uploads.*) filter(WHERE uploads.id IS NOT NULL) AS uploads
                     FROM models
                     LEFT JOIN uploads ON uploads.model_id = models.id
                     GROUP BY models.id
@@ -178,7 +234,7 @@ impl Model {
                     GROUP BY models.id
                 ),
                 model_author AS (
-                    SELECT models.id, json_build_object('id', users.id, 'name', users.name, 'email', users.email, 'username', users.username, 'is_staff', users.is_staff, 'avatar', users.avatar) as author
+                    SELECT models.id, json_build_object('id', users.id, 'name', users.name, 'email', users.email, 'username', users.username, 'role', users.role, 'avatar', users.avatar) as author
                     FROM models
                     JOIN users ON users.id = models.author_id
                 )
@@ -196,13 +252,13 @@ impl Model {
         Ok(rec)
     }
 
-    /// List all models
-    pub async fn list(page: i64) -> Result<Vec<ModelUser>, AppError> {
-        let pool = unsafe { get_client() };
+    /// List all models. Models of banned users are hidden
+    pub async fn list(state: &AppState, page: i64) -> Result<Vec<ModelUser>, AppError> {
+        let pool = &state.pool;
         let rows: Vec<ModelUser> = sqlx::query_as(
             r#"
             WITH model_uploads AS (
-                SELECT models.id, json_agg(uploads.*) filter(WHERE uploads.* IS NOT NULL) AS uploads
+                SELECT models.id, json_agg(uploads.*) filter(WHERE uploads.id IS NOT NULL) AS uploads
                 FROM models
                 LEFT JOIN uploads ON uploads.model_id = models.id
                 GROUP BY models.id
@@ -214,7 +270,7 @@ impl Model {
                 GROUP BY models.id
             ),
             model_author AS (
-                SELECT models.id, json_build_object('id', users.id, 'name', users.name, 'email', users.email, 'username', users.username, 'is_staff', users.is_staff, 'avatar', users.avatar) as author
+                SELECT models.id, json_build_object('id', users.id, 'name', users.name, 'email', users.email, 'username', users.username, 'role', users.role, 'avatar', users.avatar) as author
                 FROM models
                 JOIN users ON users.id = models.author_id
             )
@@ -223,24 +279,34 @@ impl Model {
             INNER JOIN model_author using (id)
             INNER JOIN model_uploads using (id)
             INNER JOIN model_likes using (id)
+            WHERE NOT EXISTS (
+                SELECT 1 FROM user_bans
+                WHERE user_bans.user_id = models.author_id AND user_bans.kind = 'banned'
+                    AND user_bans.lifted IS NULL
+                    AND (user_bans.expires IS NULL OR user_bans.expires > (now() AT TIME ZONE 'utc'))
+            )
             ORDER BY id DESC
             LIMIT $1 OFFSET $2
             "#)
-        .bind(CONFIG.page_limit)
-        .bind(CONFIG.page_limit * page)
+        .bind(state.config.page_limit)
+        .bind(state.config.page_limit * page)
         .fetch_all(pool)
         .await?;
 
         Ok(rows)
     }
 
-    /// Filter models by some cols
-    pub async fn filter(page: i64, query: String) -> Result<Vec<ModelUser>, AppError> {
-        let pool = unsafe { get_client() };
+    /// Filter models by some cols. Models of banned users are hidden
+    pub async fn filter(
+        state: &AppState,
+        page: i64,
+        query: String,
+    ) -> Result<Vec<ModelUser>, AppError> {
+        let pool = &state.pool;
         let rows: Vec<ModelUser> = sqlx::query_as(
             r#"
             WITH model_uploads AS (
-                SELECT models.id, json_agg(uploads.*) filter(WHERE uploads.* IS NOT NULL) AS uploads
+                SELECT models.id, json_agg(uploads.*) filter(WHERE uploads.id IS NOT NULL) AS uploads
                 FROM models
                 LEFT JOIN uploads ON uploads.model_id = models.id
                 GROUP BY models.id
@@ -252,7 +318,7 @@ impl Model {
                 GROUP BY models.id
             ),
             model_author AS (
-                SELECT models.id, json_build_object('id', users.id, 'name', users.name, 'email', users.email, 'username', users.username, 'is_staff', users.is_staff, 'avatar', users.avatar) as author
+                SELECT models.id, json_build_object('id', users.id, 'name', users.name, 'email', users.email, 'username', users.username, 'role', users.role, 'avatar', users.avatar) as author
                 FROM models
                 JOIN users ON users.id = models.author_id
             )
@@ -261,13 +327,19 @@ impl Model {
             INNER JOIN model_author using (id)
             INNER JOIN model_uploads using (id)
             INNER JOIN model_likes using (id)
-            WHERE models.name ILIKE $1 OR description ILIKE $1 OR printer ILIKE $1 OR material ILIKE $1
+            WHERE (models.name ILIKE $1 OR description ILIKE $1 OR printer ILIKE $1 OR material ILIKE $1)
+            AND NOT EXISTS (
+                SELECT 1 FROM user_bans
+                WHERE user_bans.user_id = models.author_id AND user_bans.kind = 'banned'
+                    AND user_bans.lifted IS NULL
+                    AND (user_bans.expires IS NULL OR user_bans.expires > (now() AT TIME ZONE 'utc'))
+            )
             ORDER BY id DESC
             LIMIT $2 OFFSET $3
             "#)
         .bind(format!("%{}%", query))
-        .bind(CONFIG.page_limit)
-        .bind(CONFIG.page_limit * page)
+        .bind(state.config.page_limit)
+        .bind(state.config.page_limit * page)
         .fetch_all(pool)
         .await?;
 
@@ -275,12 +347,16 @@ impl Model {
     }
 
     /// List author's models
-    pub async fn list_from_author(page: i64, author: i32) -> Result<Vec<ModelUser>, AppError> {
-        let pool = unsafe { get_client() };
+    pub async fn list_from_author(
+        state: &AppState,
+        page: i64,
+        author: i32,
+    ) -> Result<Vec<ModelUser>, AppError> {
+        let pool = &state.pool;
         let rows: Vec<ModelUser> = sqlx::query_as(
             r#"
             WITH model_uploads AS (
-                SELECT models.id, json_agg(uploads.*) filter(WHERE uploads.* IS NOT NULL) AS uploads
+                SELECT models.id, json_agg(uploads.*) filter(WHERE uploads.id IS NOT NULL) AS uploads
                 FROM models
                 LEFT JOIN uploads ON uploads.model_id = models.id
                 GROUP BY models.id
@@ -292,7 +368,7 @@ impl Model {
                 GROUP BY models.id
             ),
             model_author AS (
-                SELECT models.id, json_build_object('id', users.id, 'name', users.name, 'email', users.email, 'username', users.username, 'is_staff', users.is_staff, 'avatar', users.avatar) as author
+                SELECT models.id, json_build_object('id', users.id, 'name', users.name, 'email', users.email, 'username', users.username, 'role', users.role, 'avatar', users.avatar) as author
                 FROM models
                 JOIN users ON users.id = models.author_id
             )
@@ -306,17 +382,108 @@ impl Model {
             LIMIT $2 OFFSET $3
             "#)
         .bind(author)
-        .bind(CONFIG.page_limit)
-        .bind(CONFIG.page_limit * page)
+        .bind(state.config.page_limit)
+        .bind(state.config.page_limit * page)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows)
+    }
+
+    /// Set the height of a model to the tallest of its 3D files, if it has any
+    pub async fn update_height_from_uploads(
+        state: &AppState,
+        model_id: i32,
+    ) -> Result<(), AppError> {
+        let pool = &state.pool;
+
+        sqlx::query(
+            r#"
+            UPDATE models SET height = uploads.height
+            FROM (
+                SELECT MAX((geometry->'size'->>2)::float8) AS height
+                FROM uploads WHERE model_id = $1
+            ) uploads
+            WHERE models.id = $1 AND uploads.height IS NOT NULL
+            "#,
+        )
+        .bind(model_id)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// List all the models of an author, without pagination
+    pub async fn list_all_from_author(
+        state: &AppState,
+        author: i32,
+    ) -> Result<Vec<Model>, AppError> {
+        let pool = &state.pool;
+
+        let rows: Vec<Model> = sqlx::query_as(
+            r#"
+                SELECT * FROM models WHERE author_id = $1 ORDER BY id
+            "#,
+        )
+        .bind(author)
         .fetch_all(pool)
         .await?;
 
         Ok(rows)
     }
 
+    /// Fill the print time, weight, printer and material of a model from the values read from a
+    /// G-code file. Only the empty ones are filled: the others are left to the author, who sees
+    /// the values on the upload. Negative numbers, read before they were checked, count as zero
+    pub async fn fill_from_print_info(
+        state: &AppState,
+        model_id: i32,
+        print_info: &JsonValue,
+    ) -> Result<(), AppError> {
+        let pool = &state.pool;
+
+        sqlx::query(
+            r#"
+            UPDATE models SET
+                duration = CASE WHEN duration = 0
+                    THEN COALESCE(round(GREATEST(($2->>'duration')::numeric, 0) / 3600, 2)::float8, duration)
+                    ELSE duration END,
+                weight = CASE WHEN weight = 0
+                    THEN COALESCE(GREATEST(($2->>'filament_weight')::float8, 0), weight)
+                    ELSE weight END,
+                printer = COALESCE(NULLIF(printer, ''), $2->>'printer'),
+                material = COALESCE(NULLIF(material, ''), $2->>'material')
+            WHERE id = $1
+            "#,
+        )
+        .bind(model_id)
+        .bind(print_info)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Count a download of the model with id = `model_id`
+    pub async fn add_download(state: &AppState, model_id: i32) -> Result<(), AppError> {
+        let pool = &state.pool;
+
+        sqlx::query(
+            r#"
+            UPDATE models SET downloads = downloads + 1 WHERE id = $1
+            "#,
+        )
+        .bind(model_id)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
     /// Delete a model
-    pub async fn delete(model_id: i32) -> Result<(), AppError> {
-        let pool = unsafe { get_client() };
+    pub async fn delete(state: &AppState, model_id: i32) -> Result<(), AppError> {
+        let pool = &state.pool;
 
         sqlx::query(
             r#"
@@ -330,20 +497,30 @@ impl Model {
         Ok(())
     }
 
-    /// Return the number of models.
-    pub async fn count() -> Result<i64, AppError> {
-        let pool = unsafe { get_client() };
-        let cursor = sqlx::query(r#"SELECT COUNT(id) as count FROM models"#)
-            .fetch_one(pool)
-            .await?;
+    /// Return the number of models. Models of banned users are not counted
+    pub async fn count(state: &AppState) -> Result<i64, AppError> {
+        let pool = &state.pool;
+        let cursor = sqlx::query(
+            r#"
+            SELECT COUNT(id) as count FROM models
+            WHERE NOT EXISTS (
+                SELECT 1 FROM user_bans
+                WHERE user_bans.user_id = models.author_id AND user_bans.kind = 'banned'
+                    AND user_bans.lifted IS NULL
+                    AND (user_bans.expires IS NULL OR user_bans.expires > (now() AT TIME ZONE 'utc'))
+            )
+            "#,
+        )
+        .fetch_one(pool)
+        .await?;
 
         let count: i64 = cursor.try_get(0).unwrap();
         Ok(count)
     }
 
     /// Return the number of author models
-    pub async fn count_filter_by_author(author: i32) -> Result<i64, AppError> {
-        let pool = unsafe { get_client() };
+    pub async fn count_filter_by_author(state: &AppState, author: i32) -> Result<i64, AppError> {
+        let pool = &state.pool;
         let cursor = sqlx::query(r#"SELECT COUNT(id) as count FROM models WHERE author_id = $1"#)
             .bind(author)
             .fetch_one(pool)
@@ -353,13 +530,19 @@ impl Model {
         Ok(count)
     }
 
-    /// Return the number of models filtered by query
-    pub async fn count_filter(query: String) -> Result<i64, AppError> {
-        let pool = unsafe { get_client() };
+    /// Return the number of models filtered by query. Models of banned users are not counted
+    pub async fn count_filter(state: &AppState, query: String) -> Result<i64, AppError> {
+        let pool = &state.pool;
         let cursor = sqlx::query(
                 r#"
                 SELECT COUNT(id) as count FROM models
-                WHERE models.name ILIKE $1 OR description ILIKE $1 OR printer ILIKE $1 OR material ILIKE $1
+                WHERE (models.name ILIKE $1 OR description ILIKE $1 OR printer ILIKE $1 OR material ILIKE $1)
+                AND NOT EXISTS (
+                    SELECT 1 FROM user_bans
+                    WHERE user_bans.user_id = models.author_id AND user_bans.kind = 'banned'
+                        AND user_bans.lifted IS NULL
+                        AND (user_bans.expires IS NULL OR user_bans.expires > (now() AT TIME ZONE 'utc'))
+                )
                 "#
             )
             .bind(format!("%{}%", query))
@@ -372,66 +555,97 @@ impl Model {
 }
 
 impl ModelUser {
-    /// Returns the author id from the `JsonValue`
-    pub fn author_id(&self) -> JsonValue {
-        match &self.author {
-            Some(json) => json.get("id").unwrap().clone(),
-            None => json!(0),
-        }
+    /// Returns the author id
+    pub fn author_id(&self) -> i32 {
+        self.author_id
     }
 
     /// Returns a vec of string made by all the filepaths from the model
-    pub async fn list_upload_filepaths(&self) -> Option<Vec<String>> {
-        // Raise a `None` if `self.uploads` is `None`
-        self.uploads.as_ref()?;
+    pub async fn list_upload_filepaths(&self, state: &AppState) -> Result<Vec<String>, AppError> {
+        if self.uploads.is_none() {
+            return Ok(vec![]);
+        }
 
-        let uploads = ModelUpload::find_by_model(self.id)
-            .await
-            .unwrap_or_default();
+        let uploads = ModelUpload::find_by_model(state, self.id).await?;
 
         let paths = uploads
             .iter()
             .map(|x| x.filepath.clone())
             .collect::<Vec<String>>();
 
-        Some(paths)
+        Ok(paths)
     }
 }
 
 impl ModelUpload {
-    pub fn new(filepath: String, model_id: i32) -> Self {
+    pub fn new(model_id: i32, file: SavedUpload) -> Self {
         let now = Local::now().naive_utc();
         Self {
             id: 0,
-            filepath,
+            filepath: file.filepath,
             model_id,
             created: now,
+            geometry: file.geometry,
+            hash: Some(file.hash),
+            filename: file.filename,
+            size: file.size,
+            print_info: file.print_info,
         }
     }
 
     /// Create a new upload for model
-    pub async fn create(file: ModelUpload) -> Result<ModelUpload, AppError> {
-        let pool = unsafe { get_client() };
+    pub async fn create(state: &AppState, file: ModelUpload) -> Result<ModelUpload, AppError> {
+        let pool = &state.pool;
 
         let rec: ModelUpload = sqlx::query_as(
             r#"
-                INSERT INTO uploads (filepath, model_id, created)
-                VALUES ( $1, $2, $3)
+                INSERT INTO uploads (filepath, model_id, created, geometry, hash, filename, size, print_info)
+                VALUES ( $1, $2, $3, $4, $5, $6, $7, $8)
                 RETURNING *
             "#,
         )
         .bind(file.filepath)
         .bind(file.model_id)
         .bind(file.created)
+        .bind(file.geometry)
+        .bind(file.hash)
+        .bind(file.filename)
+        .bind(file.size)
+        .bind(file.print_info)
         .fetch_one(pool)
         .await?;
 
         Ok(rec)
     }
 
+    /// Find the geometry and the print info computed for a file with the digest `hash`, if it
+    /// was uploaded before
+    pub async fn find_analysis_by_hash(
+        state: &AppState,
+        hash: &str,
+    ) -> Result<(Option<JsonValue>, Option<JsonValue>), AppError> {
+        let pool = &state.pool;
+
+        let rec: Option<(Option<JsonValue>, Option<JsonValue>)> = sqlx::query_as(
+            r#"
+                SELECT geometry, print_info FROM uploads
+                WHERE hash = $1 AND (geometry IS NOT NULL OR print_info IS NOT NULL)
+                LIMIT 1
+            "#,
+        )
+        .bind(hash)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(rec.unwrap_or_default())
+    }
+
     /// Find all paths of a model
-    pub async fn find_by_model(model_id: i32) -> Result<Vec<ModelUpload>, AppError> {
-        let pool = unsafe { get_client() };
+    pub async fn find_by_model(
+        state: &AppState,
+        model_id: i32,
+    ) -> Result<Vec<ModelUpload>, AppError> {
+        let pool = &state.pool;
 
         let rec: Vec<ModelUpload> = sqlx::query_as(
             r#"
@@ -445,9 +659,31 @@ impl ModelUpload {
         Ok(rec)
     }
 
+    /// Find all the uploads of the models of an author
+    pub async fn find_by_author(
+        state: &AppState,
+        author: i32,
+    ) -> Result<Vec<ModelUpload>, AppError> {
+        let pool = &state.pool;
+
+        let rec: Vec<ModelUpload> = sqlx::query_as(
+            r#"
+                SELECT uploads.* FROM uploads
+                JOIN models ON models.id = uploads.model_id
+                WHERE models.author_id = $1
+                ORDER BY uploads.id
+            "#,
+        )
+        .bind(author)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rec)
+    }
+
     /// Returns the model upload with id = `upload_id`
-    pub async fn find_by_id(id: i32) -> Result<ModelUpload, AppError> {
-        let pool = unsafe { get_client() };
+    pub async fn find_by_id(state: &AppState, id: i32) -> Result<ModelUpload, AppError> {
+        let pool = &state.pool;
 
         let rec: ModelUpload = sqlx::query_as(
             r#"
@@ -462,8 +698,8 @@ impl ModelUpload {
     }
 
     /// Delete a model upload
-    pub async fn delete(upload_id: i32) -> Result<(), AppError> {
-        let pool = unsafe { get_client() };
+    pub async fn delete(state: &AppState, upload_id: i32) -> Result<(), AppError> {
+        let pool = &state.pool;
 
         sqlx::query(
             r#"
@@ -477,3 +713,214 @@ impl ModelUpload {
         Ok(())
     }
 }
+
+impl PendingUpload {
+    /// Hours of inactivity after which a pending upload is dropped
+    pub const EXPIRATION_HOURS: i64 = 24;
+
+    /// Seconds a request writing the upload keeps it locked. The lock is renewed while the bytes
+    /// are received, and it is released when the received bytes are saved
+    pub const LOCK_SECONDS: i64 = 60;
+
+    /// Create a new pending upload of `length` bytes, with a random token
+    pub async fn create(
+        state: &AppState,
+        model_id: i32,
+        user_id: i32,
+        ext: &str,
+        filename: Option<String>,
+        length: i64,
+    ) -> Result<PendingUpload, AppError> {
+        let pool = &state.pool;
+        let now = Local::now().naive_utc();
+        let token: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect();
+
+        let rec: PendingUpload = sqlx::query_as(
+            r#"
+                INSERT INTO pending_uploads (token, model_id, user_id, ext, filename, length, received, created, updated)
+                VALUES ( $1, $2, $3, $4, $5, $6, 0, $7, $7)
+                RETURNING *
+            "#,
+        )
+        .bind(token)
+        .bind(model_id)
+        .bind(user_id)
+        .bind(ext)
+        .bind(filename)
+        .bind(length)
+        .bind(now)
+        .fetch_one(pool)
+        .await?;
+
+        Ok(rec)
+    }
+
+    /// Find a pending upload of a model by its token. Expired uploads are not found, even if
+    /// they are not deleted yet
+    pub async fn find(
+        state: &AppState,
+        model_id: i32,
+        token: &str,
+    ) -> Result<PendingUpload, AppError> {
+        let pool = &state.pool;
+        let limit = Local::now().naive_utc() - Duration::hours(Self::EXPIRATION_HOURS);
+
+        let rec: Option<PendingUpload> = sqlx::query_as(
+            r#"
+                SELECT * FROM pending_uploads WHERE model_id = $1 AND token = $2 AND updated >= $3
+            "#,
+        )
+        .bind(model_id)
+        .bind(token)
+        .bind(limit)
+        .fetch_optional(pool)
+        .await?;
+
+        rec.ok_or_else(|| AppError::NotFound("Upload not found".to_string()))
+    }
+
+    /// Path of the file which keeps the received bytes
+    pub fn path(&self, config: &Configuration) -> PathBuf {
+        PathBuf::from(&config.upload_temp_path).join(format!("verden-tus-{}.part", self.token))
+    }
+
+    /// Lock the upload to write the bytes after the received ones. Returns the token of the
+    /// lock, or `None` if other bytes were received or another request is writing them
+    pub async fn lock(&mut self, state: &AppState) -> Result<Option<String>, AppError> {
+        let pool = &state.pool;
+        let now = Local::now().naive_utc();
+        let lock_token: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect();
+
+        let rec = sqlx::query(
+            r#"
+                UPDATE pending_uploads SET lock_token = $1, locked_until = $2, updated = $3
+                WHERE id = $4 AND received = $5 AND (locked_until IS NULL OR locked_until < $3)
+            "#,
+        )
+        .bind(&lock_token)
+        .bind(now + Duration::seconds(Self::LOCK_SECONDS))
+        .bind(now)
+        .bind(self.id)
+        .bind(self.received)
+        .execute(pool)
+        .await?;
+
+        if rec.rows_affected() == 0 {
+            return Ok(None);
+        }
+        self.updated = now;
+
+        Ok(Some(lock_token))
+    }
+
+    /// Extend the lock `lock_token`. Returns `false` if it was lost, because it expired and
+    /// another request took the upload
+    pub async fn renew_lock(
+        &mut self,
+        state: &AppState,
+        lock_token: &str,
+    ) -> Result<bool, AppError> {
+        let pool = &state.pool;
+        let now = Local::now().naive_utc();
+
+        let rec = sqlx::query(
+            r#"
+                UPDATE pending_uploads SET locked_until = $1, updated = $2
+                WHERE id = $3 AND lock_token = $4
+            "#,
+        )
+        .bind(now + Duration::seconds(Self::LOCK_SECONDS))
+        .bind(now)
+        .bind(self.id)
+        .bind(lock_token)
+        .execute(pool)
+        .await?;
+        self.updated = now;
+
+        Ok(rec.rows_affected() == 1)
+    }
+
+    /// Save how many bytes are received, releasing the lock `lock_token`. The received bytes are
+    /// not saved if the lock was lost
+    pub async fn set_received(
+        &mut self,
+        state: &AppState,
+        lock_token: &str,
+        received: i64,
+    ) -> Result<(), AppError> {
+        let pool = &state.pool;
+        let now = Local::now().naive_utc();
+
+        let rec = sqlx::query(
+            r#"
+                UPDATE pending_uploads
+                SET received = $1, updated = $2, lock_token = NULL, locked_until = NULL
+                WHERE id = $3 AND received = $4 AND lock_token = $5
+            "#,
+        )
+        .bind(received)
+        .bind(now)
+        .bind(self.id)
+        .bind(self.received)
+        .bind(lock_token)
+        .execute(pool)
+        .await?;
+
+        if rec.rows_affected() == 0 {
+            return Err(AppError::Conflict(
+                "Upload is written by another request".to_string(),
+            ));
+        }
+        self.received = received;
+        self.updated = now;
+
+        Ok(())
+    }
+
+    /// Returns when the upload expires, if no more bytes are received
+    pub fn expires(&self) -> NaiveDateTime {
+        self.updated + Duration::hours(Self::EXPIRATION_HOURS)
+    }
+
+    /// Delete a pending upload. Its file is not deleted
+    pub async fn delete(&self, state: &AppState) -> Result<(), AppError> {
+        let pool = &state.pool;
+
+        sqlx::query(r#"DELETE FROM pending_uploads WHERE id = $1"#)
+            .bind(self.id)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Delete the pending uploads not updated for `EXPIRATION_HOURS`, with their files
+    pub async fn delete_expired(state: &AppState) -> Result<(), AppError> {
+        let pool = &state.pool;
+        let limit = Local::now().naive_utc() - Duration::hours(Self::EXPIRATION_HOURS);
+
+        let expired: Vec<PendingUpload> = sqlx::query_as(
+            r#"
+                DELETE FROM pending_uploads WHERE updated < $1
+                RETURNING *
+            "#,
+        )
+        .bind(limit)
+        .fetch_all(pool)
+        .await?;
+
+        for upload in expired {
+            let _ = tokio::fs::remove_file(upload.path(&state.config)).await;
+        }
+
+        Ok(())
+    }
+}
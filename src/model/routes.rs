@@ -1,19 +1,45 @@
 use crate::{
-    auth::models::Claims,
+    auth::{
+        models::{Claims, Scope},
+        permissions::Permission,
+    },
     errors::AppError,
-    files::{delete_upload, upload},
+    files::{
+        content_disposition, convert_upload, delete_upload, discard_upload,
+        extension_from_content_type, save_upload, serve_file, storage_key, upload, Blob,
+        SavedUpload, TempUpload,
+    },
+    filetype,
     likes::models::Like,
-    model::models::{Model, ModelCreate, ModelFilter, ModelUpload, ModelUser},
+    mesh::MeshFormat,
+    model::{
+        bundle,
+        models::{
+            Model, ModelCreate, ModelFilter, ModelUpload, ModelUploadHash, ModelUser,
+            PendingUpload, UploadFormat,
+        },
+    },
     pagination::{ModelPagination, Pagination},
-    routes::JsonCreate,
-    user::models::User,
+    routes::{http_date, JsonCreate},
+    state::AppState,
+    storage::storage_error,
+    user::models::{StorageUsage, User},
 };
 use axum::{
-    extract::{ContentLengthLimit, Multipart, Path, Query},
-    http::StatusCode,
-    routing::{delete, get, post},
+    body::{boxed, Body},
+    extract::{Extension, Multipart, Path, Query, RawBody},
+    http::{
+        header::{self, HeaderMap, HeaderValue},
+        Method, StatusCode,
+    },
+    response::{IntoResponse, Response},
+    routing::{get, head, post},
     Json, Router,
 };
+use data_encoding::BASE64;
+use http_body::Body as _;
+use std::{collections::HashMap, io::SeekFrom, time::Instant};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
 
 /// Create routes for `/v1/models/` namespace
 pub fn create_route() -> Router {
@@ -21,25 +47,43 @@ pub fn create_route() -> Router {
         .route("/", get(list_models).post(create_model))
         .route("/filter", post(filter_models))
         .route("/:id", get(get_model).delete(delete_model).put(edit_model))
+        .route("/:id/download", get(download_model))
         .route("/:id/like", post(add_like).delete(delete_like))
         .route("/:id/upload", post(upload_model_file))
-        .route("/:id/upload/:uid", delete(delete_model_file))
+        .route("/:id/upload-by-hash", post(upload_model_file_by_hash))
+        .route(
+            "/:id/upload/:uid",
+            get(convert_model_file).delete(delete_model_file),
+        )
+        .route("/:id/resumable", post(create_resumable_upload))
+        .route(
+            "/:id/resumable/:token",
+            head(get_resumable_upload)
+                .patch(patch_resumable_upload)
+                .delete(delete_resumable_upload),
+        )
 }
 
 /// List models.
-async fn list_models(pagination: Query<Pagination>) -> Result<Json<ModelPagination>, AppError> {
+async fn list_models(
+    Extension(state): Extension<AppState>,
+    pagination: Query<Pagination>,
+) -> Result<Json<ModelPagination>, AppError> {
     let page = pagination.0.page.unwrap_or_default();
-    let results = Model::list(page).await?;
-    let count = Model::count().await?;
+    let results = Model::list(&state, page).await?;
+    let count = Model::count(&state).await?;
 
     Ok(Json(ModelPagination { count, results }))
 }
 
 /// Create a model. Checks Authorization token
 async fn create_model(
+    Extension(state): Extension<AppState>,
     Json(payload): Json<ModelCreate>,
     claims: Claims,
 ) -> Result<JsonCreate<Model>, AppError> {
+    claims.require(Scope::Write)?;
+
     let model = Model::new(
         payload.name,
         payload.description,
@@ -51,41 +95,101 @@ async fn create_model(
         claims.user_id,
     );
 
-    let model_new = Model::create(model).await?;
+    let model_new = Model::create(&state, model).await?;
 
     Ok(JsonCreate(model_new))
 }
 
 /// Get a model with id = `model_id`
-async fn get_model(Path(model_id): Path<i32>) -> Result<Json<ModelUser>, AppError> {
-    match Model::find_by_id(model_id).await {
+async fn get_model(
+    Extension(state): Extension<AppState>,
+    Path(model_id): Path<i32>,
+) -> Result<Json<ModelUser>, AppError> {
+    match Model::find_by_id(&state, model_id).await {
         Ok(model) => Ok(Json(model)),
         Err(_) => Err(AppError::NotFound("Model not found".to_string())),
     }
 }
 
-/// The owner or a staffer can delete a model
-async fn delete_model(claims: Claims, Path(model_id): Path<i32>) -> Result<StatusCode, AppError> {
-    let model = match Model::find_by_id(model_id).await {
+/// Download a model with id = `model_id` as a ZIP archive, with all its uploads, a README and a
+/// JSON manifest. The archive is streamed while it is built. Every download is counted
+async fn download_model(
+    Extension(state): Extension<AppState>,
+    Path(model_id): Path<i32>,
+    method: Method,
+) -> Result<Response, AppError> {
+    let model = match Model::find_by_id(&state, model_id).await {
         Ok(model) => model,
         Err(_) => {
             return Err(AppError::NotFound("Model not found".to_string()));
         }
     };
 
-    let user = User::find_by_id(claims.user_id).await?;
+    let files = bundle::files(&state, &model).await?;
+
+    let slug = model
+        .name
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '-' })
+        .collect::<String>();
+    let slug = slug
+        .split('-')
+        .filter(|x| !x.is_empty())
+        .collect::<Vec<&str>>()
+        .join("-");
+    let filename = match slug.is_empty() {
+        true => format!("model-{}.zip", model_id),
+        false => format!("{}.zip", slug),
+    };
 
-    let uploads: Vec<String> = model.list_upload_filepaths().await.unwrap_or_default();
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/zip"),
+    );
+    headers.insert(
+        header::CONTENT_DISPOSITION,
+        HeaderValue::from_str(&content_disposition("attachment", &filename)).unwrap(),
+    );
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
 
-    if !(model.author_id() == user.id || user.is_staff.unwrap()) {
-        return Err(AppError::Unauthorized);
+    if method == Method::HEAD {
+        return Ok((headers, boxed(Body::empty())).into_response());
     }
 
-    // If the model has been deleted, remove all old uploads from the file system
-    if Model::delete(model_id).await.is_ok() {
-        uploads
-            .iter()
-            .for_each(|path: &String| delete_upload(path).unwrap_or_default());
+    Model::add_download(&state, model_id).await?;
+
+    Ok((headers, boxed(bundle::stream(state, files))).into_response())
+}
+
+/// The owner or a staffer can delete a model
+async fn delete_model(
+    Extension(state): Extension<AppState>,
+    claims: Claims,
+    Path(model_id): Path<i32>,
+) -> Result<StatusCode, AppError> {
+    claims.require(Scope::Write)?;
+
+    let model = match Model::find_by_id(&state, model_id).await {
+        Ok(model) => model,
+        Err(_) => {
+            return Err(AppError::NotFound("Model not found".to_string()));
+        }
+    };
+
+    let user = User::find_by_id(&state, claims.user_id).await?;
+
+    user.require_owner_or(model.author_id(), Permission::ModelDeleteAny)?;
+
+    let uploads: Vec<String> = model.list_upload_filepaths(&state).await?;
+
+    Model::delete(&state, model_id).await?;
+
+    // Rows are gone: a file which can not be deleted now is left to the storage check
+    for path in &uploads {
+        if let Err(error) = delete_upload(&state, path).await {
+            tracing::warn!("File `{}` has not been deleted: {:?}", path, error);
+        }
     }
 
     Ok(StatusCode::NO_CONTENT)
@@ -93,22 +197,23 @@ async fn delete_model(claims: Claims, Path(model_id): Path<i32>) -> Result<Statu
 
 /// The owner or a staffer can edit a model
 async fn edit_model(
+    Extension(state): Extension<AppState>,
     Json(payload): Json<ModelCreate>,
     claims: Claims,
     Path(model_id): Path<i32>,
 ) -> Result<Json<ModelUser>, AppError> {
-    let model = match Model::find_by_id(model_id).await {
+    claims.require(Scope::Write)?;
+
+    let model = match Model::find_by_id(&state, model_id).await {
         Ok(model) => model,
         Err(_) => {
             return Err(AppError::NotFound("Model not found".to_string()));
         }
     };
 
-    let user = User::find_by_id(claims.user_id).await?;
+    let user = User::find_by_id(&state, claims.user_id).await?;
 
-    if !(model.author_id() == user.id || user.is_staff.unwrap()) {
-        return Err(AppError::Unauthorized);
-    }
+    user.require_owner_or(model.author_id(), Permission::ModelEditAny)?;
 
     let model_body = Model::new(
         payload.name,
@@ -121,72 +226,435 @@ async fn edit_model(
         claims.user_id,
     );
 
-    // NOTE: can we edit this as same as `user.edit_avatar()`?
-    Model::edit(model.id, model_body).await?;
+    // NOTE: can we edit this as same as `user.edit_avatar(&state, )`?
+    Model::edit(&state, model.id, model_body).await?;
     Ok(Json(model))
 }
 
-/// Upload a file for a model
+/// Extensions of the files which can be uploaded for a model
+const UPLOAD_EXTENSIONS: [&str; 10] = [
+    "stl", "obj", "3mf", "png", "jpg", "gif", "webp", "blend", "gcode", "bgcode",
+];
+
+/// Version of the tus protocol used by the resumable uploads
+const TUS_VERSION: &str = "1.0.0";
+
+/// Checks if the user of the claims can upload files for the model `model_id`: the owner or a
+/// staffer, with a verified email address. Returns the storage used by the model author, whose
+/// quota the uploads count against
+async fn check_upload_allowed(
+    state: &AppState,
+    claims: &Claims,
+    model_id: i32,
+) -> Result<StorageUsage, AppError> {
+    claims.require(Scope::Upload)?;
+
+    let model = match Model::find_by_id(state, model_id).await {
+        Ok(model) => model,
+        Err(_) => {
+            return Err(AppError::NotFound("Model not found".to_string()));
+        }
+    };
+
+    let user = User::find_by_id(state, claims.user_id).await?;
+
+    user.require_owner_or(model.author_id(), Permission::ModelEditAny)?;
+
+    if !user.email_verified {
+        return Err(AppError::Forbidden(
+            "Verify your email address before uploading files".to_string(),
+        ));
+    }
+
+    StorageUsage::find(state, model.author_id()).await
+}
+
+/// Create the upload of a saved file for a model. The model height is updated by 3D files
+async fn attach_upload(
+    state: &AppState,
+    model_id: i32,
+    saved_file: SavedUpload,
+) -> Result<ModelUpload, AppError> {
+    let has_geometry = saved_file.geometry.is_some();
+    let filepath = saved_file.filepath.clone();
+
+    // The file is referenced by the row: without it, the reference is released
+    let model_file = match ModelUpload::create(state, ModelUpload::new(model_id, saved_file)).await
+    {
+        Ok(model_file) => model_file,
+        Err(error) => {
+            discard_upload(state, &filepath).await;
+            return Err(error);
+        }
+    };
+
+    if has_geometry {
+        Model::update_height_from_uploads(state, model_id).await?;
+    }
+    if let Some(print_info) = &model_file.print_info {
+        Model::fill_from_print_info(state, model_id, print_info).await?;
+    }
+
+    Ok(model_file)
+}
+
+/// Upload a file for a model. 3D files are parsed: their geometry is saved on the upload, the
+/// model height follows the tallest of them and thumbnails are rendered, shown by the uploads
+/// endpoint with `?size=`. Images are cleaned of their metadata and resized
 async fn upload_model_file(
+    Extension(state): Extension<AppState>,
     claims: Claims,
     Path(model_id): Path<i32>,
-    ContentLengthLimit(multipart): ContentLengthLimit<Multipart, { 1024 * 1024 * 40 }>,
+    multipart: Multipart,
 ) -> Result<Json<ModelUpload>, AppError> {
-    let model = match Model::find_by_id(model_id).await {
-        Ok(model) => model,
-        Err(_) => {
-            return Err(AppError::NotFound("Model not found".to_string()));
+    let usage = check_upload_allowed(&state, &claims, model_id).await?;
+    usage.require(1)?;
+
+    // The file is refused while it is received, as soon as it does not fit in the quota
+    let max_size = state.config.max_upload_size.min(usage.available as u64);
+
+    // Images which can not be decoded and 3D files which can not be parsed are refused
+    let saved_file = upload(&state, multipart, UPLOAD_EXTENSIONS.to_vec(), max_size)
+        .await
+        .map_err(|error| match error {
+            AppError::PayloadTooLarge(_) if max_size < state.config.max_upload_size => {
+                AppError::QuotaExceeded(usage.available)
+            }
+            error => error,
+        })?;
+
+    Ok(Json(attach_upload(&state, model_id, saved_file).await?))
+}
+
+/// Attach to a model a file already stored, found by the digest of its content, so a file is not
+/// sent again. The geometry computed for the file is copied. Only 3D files and G-code are found:
+/// images are cleaned when they are uploaded, so the digest of the file sent is not stored
+async fn upload_model_file_by_hash(
+    Extension(state): Extension<AppState>,
+    claims: Claims,
+    Path(model_id): Path<i32>,
+    Json(payload): Json<ModelUploadHash>,
+) -> Result<Json<ModelUpload>, AppError> {
+    let usage = check_upload_allowed(&state, &claims, model_id).await?;
+
+    let hash = payload.hash.to_lowercase();
+    let size = match Blob::size_by_hash(&state, &hash).await? {
+        Some(size) => size,
+        None => {
+            return Err(AppError::NotFound("File not found".to_string()));
+        }
+    };
+    usage.require(size)?;
+
+    let (geometry, print_info) = ModelUpload::find_analysis_by_hash(&state, &hash).await?;
+
+    // From here the reference is released by `attach_upload` if the upload is not created
+    let filepath = match Blob::retain_by_hash(&state, &hash).await? {
+        Some(filepath) => filepath,
+        None => {
+            return Err(AppError::NotFound("File not found".to_string()));
         }
     };
 
-    let user = User::find_by_id(claims.user_id).await?;
+    let saved_file = SavedUpload {
+        filepath,
+        hash,
+        geometry,
+        filename: payload
+            .filename
+            .as_deref()
+            .and_then(filetype::clean_filename),
+        size,
+        print_info,
+    };
+
+    Ok(Json(attach_upload(&state, model_id, saved_file).await?))
+}
 
-    if !(model.author_id() == user.id || user.is_staff.unwrap()) {
-        return Err(AppError::Unauthorized);
+/// Headers sent by every response of the resumable uploads
+fn tus_headers() -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert("tus-resumable", HeaderValue::from_static(TUS_VERSION));
+
+    headers
+}
+
+/// Checks the tus version of a request and returns the value of its header `name`
+fn tus_header(headers: &HeaderMap, name: &str) -> Result<i64, AppError> {
+    if headers.get("tus-resumable").and_then(|x| x.to_str().ok()) != Some(TUS_VERSION) {
+        return Err(AppError::PreconditionFailed(format!(
+            "Only the tus version {} is supported",
+            TUS_VERSION
+        )));
     }
 
-    let allowed_extensions = vec![
-        "stl",
-        "obj",
-        "png",
-        "jpg",
-        "jpeg",
-        "gif",
-        "webp",
-        "blend",
-        "octet-stream",
-        "sla",
-    ];
-
-    match upload(multipart, allowed_extensions, None).await {
-        Ok(saved_file) => {
-            let model_file = ModelUpload::create(ModelUpload::new(saved_file, model_id)).await?;
-
-            Ok(Json(model_file))
+    headers
+        .get(name)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.parse::<i64>().ok())
+        .filter(|x| *x >= 0)
+        .ok_or_else(|| AppError::BadRequest(format!("Header `{}` is missing or invalid", name)))
+}
+
+/// Returns the extension declared for a resumable upload by the `filetype` of its
+/// `Upload-Metadata` header, and its `filename`
+fn tus_metadata(headers: &HeaderMap) -> (String, Option<String>) {
+    let metadata: HashMap<&str, String> = headers
+        .get("upload-metadata")
+        .and_then(|x| x.to_str().ok())
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|pair| {
+            let mut pair = pair.trim().splitn(2, ' ');
+            let key = pair.next()?;
+            let value = BASE64
+                .decode(pair.next().unwrap_or_default().as_bytes())
+                .ok()?;
+
+            Some((key, String::from_utf8(value).ok()?))
+        })
+        .collect();
+
+    (
+        extension_from_content_type(metadata.get("filetype").map_or("", |x| x.as_str())),
+        metadata
+            .get("filename")
+            .and_then(|x| filetype::clean_filename(x)),
+    )
+}
+
+/// Start a resumable upload for a model, with the tus protocol. The size of the file is sent with
+/// `Upload-Length`, its type and its name with the `filetype` and the `filename` of
+/// `Upload-Metadata`. The
+/// response `Location` is the URL where the file is sent, in one or more chunks
+async fn create_resumable_upload(
+    Extension(state): Extension<AppState>,
+    claims: Claims,
+    Path(model_id): Path<i32>,
+    headers: HeaderMap,
+) -> Result<(StatusCode, HeaderMap), AppError> {
+    let length = tus_header(&headers, "upload-length")?;
+
+    let usage = check_upload_allowed(&state, &claims, model_id).await?;
+
+    if length as u64 > state.config.max_upload_size {
+        return Err(AppError::PayloadTooLarge(state.config.max_upload_size));
+    }
+    // The whole length is reserved until the upload is completed or it expires
+    usage.require(length)?;
+
+    let (ext, filename) = tus_metadata(&headers);
+    if !filetype::accepts(&ext, &UPLOAD_EXTENSIONS) {
+        return Err(AppError::BadRequest(
+            "File extension not supported".to_string(),
+        ));
+    }
+
+    PendingUpload::delete_expired(&state).await?;
+
+    let pending =
+        PendingUpload::create(&state, model_id, claims.user_id, &ext, filename, length).await?;
+    tokio::fs::File::create(pending.path(&state.config))
+        .await
+        .map_err(storage_error)?;
+
+    let mut headers = tus_headers();
+    headers.insert(
+        header::LOCATION,
+        HeaderValue::from_str(&format!(
+            "/v1/models/{}/resumable/{}",
+            model_id, pending.token
+        ))
+        .unwrap(),
+    );
+    headers.insert(
+        "upload-expires",
+        HeaderValue::from_str(&http_date(pending.expires())).unwrap(),
+    );
+
+    Ok((StatusCode::CREATED, headers))
+}
+
+/// Find a pending upload of the user of the claims
+async fn find_pending_upload(
+    state: &AppState,
+    claims: &Claims,
+    model_id: i32,
+    token: &str,
+) -> Result<PendingUpload, AppError> {
+    claims.require(Scope::Upload)?;
+
+    let pending = PendingUpload::find(state, model_id, token).await?;
+    if pending.user_id != claims.user_id {
+        return Err(AppError::NotFound("Upload not found".to_string()));
+    }
+
+    Ok(pending)
+}
+
+/// Returns how many bytes of a resumable upload are received, so the client knows where to
+/// resume it
+async fn get_resumable_upload(
+    Extension(state): Extension<AppState>,
+    claims: Claims,
+    Path((model_id, token)): Path<(i32, String)>,
+) -> Result<(StatusCode, HeaderMap), AppError> {
+    let pending = find_pending_upload(&state, &claims, model_id, &token).await?;
+
+    let mut headers = tus_headers();
+    headers.insert("upload-offset", HeaderValue::from(pending.received));
+    headers.insert("upload-length", HeaderValue::from(pending.length));
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+
+    Ok((StatusCode::OK, headers))
+}
+
+/// Send a chunk of a resumable upload, starting from the `Upload-Offset` of the request, which
+/// must be where the previous chunks ended. If the connection drops, what is received is kept.
+/// When all the bytes are received the file is saved as the multipart uploads are, and the
+/// response is the created model upload
+async fn patch_resumable_upload(
+    Extension(state): Extension<AppState>,
+    claims: Claims,
+    Path((model_id, token)): Path<(i32, String)>,
+    headers: HeaderMap,
+    RawBody(mut body): RawBody,
+) -> Result<Response, AppError> {
+    let offset = tus_header(&headers, "upload-offset")?;
+
+    if headers.get(header::CONTENT_TYPE)
+        != Some(&HeaderValue::from_static("application/offset+octet-stream"))
+    {
+        return Err(AppError::BadRequest(
+            "Content type must be `application/offset+octet-stream`".to_string(),
+        ));
+    }
+
+    let mut pending = find_pending_upload(&state, &claims, model_id, &token).await?;
+    if offset != pending.received {
+        return Err(AppError::Conflict(format!(
+            "Upload offset is {}",
+            pending.received
+        )));
+    }
+    // Only one request at a time writes the file
+    let lock_token = match pending.lock(&state).await? {
+        Some(lock_token) => lock_token,
+        None => {
+            return Err(AppError::Conflict(
+                "Upload is written by another request".to_string(),
+            ))
         }
-        Err(e) => Err(e),
+    };
+
+    let path = pending.path(&state.config);
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .await
+        .map_err(storage_error)?;
+    // Bytes written after the last saved offset, by an interrupted request, are dropped
+    file.set_len(offset as u64).await.map_err(storage_error)?;
+    file.seek(SeekFrom::End(0)).await.map_err(storage_error)?;
+
+    let mut received = offset;
+    let mut result = Ok(());
+    let mut locked = Instant::now();
+    while let Some(chunk) = body.data().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(error) => {
+                result = Err(AppError::BadRequest(error.to_string()));
+                break;
+            }
+        };
+
+        if received + chunk.len() as i64 > pending.length {
+            result = Err(AppError::BadRequest(
+                "Upload is longer than its `Upload-Length`".to_string(),
+            ));
+            break;
+        }
+
+        // The lock is renewed well before it expires, and nothing is written once it is lost
+        if locked.elapsed().as_secs() as i64 > PendingUpload::LOCK_SECONDS / 3 {
+            if !pending.renew_lock(&state, &lock_token).await? {
+                return Err(AppError::Conflict(
+                    "Upload is written by another request".to_string(),
+                ));
+            }
+            locked = Instant::now();
+        }
+
+        file.write_all(&chunk).await.map_err(storage_error)?;
+        received += chunk.len() as i64;
+    }
+
+    file.flush().await.map_err(storage_error)?;
+    pending.set_received(&state, &lock_token, received).await?;
+    result?;
+
+    let mut headers = tus_headers();
+    headers.insert("upload-offset", HeaderValue::from(pending.received));
+
+    if pending.received < pending.length {
+        headers.insert(
+            "upload-expires",
+            HeaderValue::from_str(&http_date(pending.expires())).unwrap(),
+        );
+        return Ok((StatusCode::NO_CONTENT, headers).into_response());
     }
+
+    // The file is complete: it is not pending anymore, even if it is refused
+    pending.delete(&state).await?;
+    let temp = TempUpload::from_path(path).await?;
+    let saved_file = save_upload(
+        &state,
+        temp,
+        &pending.ext,
+        pending.filename,
+        &UPLOAD_EXTENSIONS,
+    )
+    .await?;
+    let model_file = attach_upload(&state, model_id, saved_file).await?;
+
+    Ok((StatusCode::OK, headers, Json(model_file)).into_response())
+}
+
+/// Cancel a resumable upload, deleting what is received
+async fn delete_resumable_upload(
+    Extension(state): Extension<AppState>,
+    claims: Claims,
+    Path((model_id, token)): Path<(i32, String)>,
+) -> Result<(StatusCode, HeaderMap), AppError> {
+    let pending = find_pending_upload(&state, &claims, model_id, &token).await?;
+
+    pending.delete(&state).await?;
+    let _ = tokio::fs::remove_file(pending.path(&state.config)).await;
+
+    Ok((StatusCode::NO_CONTENT, tus_headers()))
 }
 
 /// The owner or a staffer can delete a model upload
 async fn delete_model_file(
+    Extension(state): Extension<AppState>,
     claims: Claims,
     Path((model_id, upload_id)): Path<(i32, i32)>,
 ) -> Result<StatusCode, AppError> {
-    let model = match Model::find_by_id(model_id).await {
+    claims.require(Scope::Write)?;
+
+    let model = match Model::find_by_id(&state, model_id).await {
         Ok(model) => model,
         Err(_) => {
             return Err(AppError::NotFound("Model not found".to_string()));
         }
     };
 
-    let user = User::find_by_id(claims.user_id).await?;
+    let user = User::find_by_id(&state, claims.user_id).await?;
 
-    if !(model.author_id() == user.id || user.is_staff.unwrap()) {
-        return Err(AppError::Unauthorized);
-    }
+    user.require_owner_or(model.author_id(), Permission::ModelEditAny)?;
 
-    let upload = match ModelUpload::find_by_id(upload_id).await {
+    let upload = match ModelUpload::find_by_id(&state, upload_id).await {
         Ok(upload) => upload,
         Err(_) => {
             return Err(AppError::NotFound("Upload not found".to_string()));
@@ -199,9 +667,15 @@ async fn delete_model_file(
 
     let filepath = upload.filepath.clone();
 
-    match ModelUpload::delete(upload_id).await {
+    match ModelUpload::delete(&state, upload_id).await {
         Ok(_) => {
-            delete_upload(&filepath)?;
+            if let Err(error) = delete_upload(&state, &filepath).await {
+                tracing::warn!("File `{}` has not been deleted: {:?}", filepath, error);
+            }
+
+            if upload.geometry.is_some() {
+                Model::update_height_from_uploads(&state, model_id).await?;
+            }
 
             Ok(StatusCode::NO_CONTENT)
         }
@@ -209,57 +683,119 @@ async fn delete_model_file(
     }
 }
 
+/// Download the 3D file `upload_id` of a model converted to the `?format=` one: `stl`, `obj` or
+/// `3mf`. The conversion is made once and kept in the storage with the uploaded file. Only logged
+/// users can ask for it, and files bigger than `MAX_CONVERT_SIZE` are refused
+async fn convert_model_file(
+    Extension(state): Extension<AppState>,
+    claims: Claims,
+    Path((model_id, upload_id)): Path<(i32, i32)>,
+    Query(query): Query<UploadFormat>,
+    method: Method,
+    request_headers: HeaderMap,
+) -> Result<Response, AppError> {
+    claims.require(Scope::Read)?;
+
+    let upload = match ModelUpload::find_by_id(&state, upload_id).await {
+        Ok(upload) if upload.model_id == model_id => upload,
+        _ => {
+            return Err(AppError::NotFound("Upload not found".to_string()));
+        }
+    };
+
+    let format = match MeshFormat::from_extension(&query.format) {
+        Some(format) => format,
+        None => {
+            return Err(AppError::BadRequest(
+                "Format must be `stl`, `obj` or `3mf`".to_string(),
+            ));
+        }
+    };
+
+    let filepath = convert_upload(&state, &upload.filepath, format).await?;
+
+    // The name of the uploaded file, with the extension of the format
+    let name = upload
+        .filename
+        .as_deref()
+        .unwrap_or_else(|| storage_key(&upload.filepath));
+    let stem = match name.rsplit_once('.') {
+        Some((stem, _)) if !stem.is_empty() => stem,
+        _ => name,
+    };
+    let disposition =
+        content_disposition("attachment", &format!("{}.{}", stem, format.extension()));
+
+    serve_file(
+        &state,
+        storage_key(&filepath).to_string(),
+        Some(disposition),
+        &method,
+        &request_headers,
+    )
+    .await
+}
+
 /// Assign a like to a model from the Authorization user
-async fn add_like(claims: Claims, Path(model_id): Path<i32>) -> Result<StatusCode, AppError> {
-    let model = match Model::find_by_id(model_id).await {
+async fn add_like(
+    Extension(state): Extension<AppState>,
+    claims: Claims,
+    Path(model_id): Path<i32>,
+) -> Result<StatusCode, AppError> {
+    claims.require(Scope::Write)?;
+
+    let model = match Model::find_by_id(&state, model_id).await {
         Ok(model) => model,
         Err(_) => {
             return Err(AppError::NotFound("Model not found".to_string()));
         }
     };
 
-    let user = User::find_by_id(claims.user_id).await?;
+    let user = User::find_by_id(&state, claims.user_id).await?;
 
     let like = Like::new(user.id, model.id);
 
-    match like.save().await {
+    match like.save(&state).await {
         Ok(_) => Ok(StatusCode::CREATED),
-        Err(e) => {
-            return Err(e);
-        }
+        Err(e) => Err(e),
     }
 }
 
 /// Remove a like from a model and an Authorization user
-async fn delete_like(claims: Claims, Path(model_id): Path<i32>) -> Result<StatusCode, AppError> {
-    let model = match Model::find_by_id(model_id).await {
+async fn delete_like(
+    Extension(state): Extension<AppState>,
+    claims: Claims,
+    Path(model_id): Path<i32>,
+) -> Result<StatusCode, AppError> {
+    claims.require(Scope::Write)?;
+
+    let model = match Model::find_by_id(&state, model_id).await {
         Ok(model) => model,
         Err(_) => {
             return Err(AppError::NotFound("Model not found".to_string()));
         }
     };
 
-    let user = User::find_by_id(claims.user_id).await?;
+    let user = User::find_by_id(&state, claims.user_id).await?;
 
     let like = Like::new(user.id, model.id);
 
-    match like.remove().await {
+    match like.remove(&state).await {
         Ok(_) => Ok(StatusCode::NO_CONTENT),
-        Err(e) => {
-            return Err(e);
-        }
+        Err(e) => Err(e),
     }
 }
 
 /// Filter models
 async fn filter_models(
+    Extension(state): Extension<AppState>,
     pagination: Query<Pagination>,
     Json(payload): Json<ModelFilter>,
 ) -> Result<Json<ModelPagination>, AppError> {
     let page = pagination.0.page.unwrap_or_default();
 
-    let results = Model::filter(page, payload.q.clone()).await?;
-    let count = Model::count_filter(payload.q).await?;
+    let results = Model::filter(&state, page, payload.q.clone()).await?;
+    let count = Model::count_filter(&state, payload.q).await?;
 
     Ok(Json(ModelPagination { count, results }))
 }
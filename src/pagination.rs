@@ -1,3 +1,4 @@
+use crate::auth::models::LoginAttempt;
 use crate::model::models::ModelUser;
 use crate::user::models::UserList;
 use crate::warning::models::WarningUser;
@@ -26,3 +27,9 @@ pub struct WarningPagination {
     pub count: i64,
     pub results: Vec<WarningUser>,
 }
+
+#[derive(Serialize)]
+pub struct LoginAttemptPagination {
+    pub count: i64,
+    pub results: Vec<LoginAttempt>,
+}
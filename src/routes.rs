@@ -1,15 +1,32 @@
-use crate::errors::AppError;
+use crate::{errors::AppError, state::AppState};
 use axum::{
+    async_trait,
+    extract::{ConnectInfo, Extension, FromRequest, RequestParts},
     http::StatusCode,
     response::{IntoResponse, Response},
     Json,
 };
+use chrono::NaiveDateTime;
 use serde::Serialize;
+use std::net::SocketAddr;
 
 pub async fn page_404() -> impl IntoResponse {
     AppError::NotFound("Route not found".to_string())
 }
 
+/// Format of the dates of the HTTP headers
+const HTTP_DATE_FORMAT: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+/// Format a date, in UTC, as the HTTP headers do
+pub fn http_date(date: NaiveDateTime) -> String {
+    date.format(HTTP_DATE_FORMAT).to_string()
+}
+
+/// Parse a date of an HTTP header
+pub fn parse_http_date(value: &str) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(value.trim(), HTTP_DATE_FORMAT).ok()
+}
+
 /// Extension of `Json` which returns the CREATED status code
 pub struct JsonCreate<T>(pub T);
 
@@ -21,3 +38,45 @@ where
         (StatusCode::CREATED, Json(self.0)).into_response()
     }
 }
+
+/// IP address of the client. If `TRUST_PROXY_HEADERS` is set, it is read from the last value of
+/// the `X-Forwarded-For` header: the one appended by the proxy. The values before it are sent by
+/// the client, so they can be forged
+pub struct ClientIp(pub String);
+
+#[async_trait]
+impl<B> FromRequest<B> for ClientIp
+where
+    B: Send,
+{
+    type Rejection = AppError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let Extension(state) = Extension::<AppState>::from_request(req)
+            .await
+            .expect("`AppState` extension is missing");
+
+        if state.config.trust_proxy_headers {
+            let forwarded = req
+                .headers()
+                .get_all("x-forwarded-for")
+                .iter()
+                .next_back()
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.rsplit(',').next())
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty());
+
+            if let Some(ip) = forwarded {
+                return Ok(ClientIp(ip));
+            }
+        }
+
+        let ip = match req.extensions().get::<ConnectInfo<SocketAddr>>() {
+            Some(ConnectInfo(addr)) => addr.ip().to_string(),
+            None => "unknown".to_string(),
+        };
+
+        Ok(ClientIp(ip))
+    }
+}
@@ -0,0 +1,61 @@
+use crate::{
+    auth::{models::Keys, oidc::OidcClient},
+    config::Configuration,
+    db,
+    errors::AppError,
+    mailer::{self, Mailer},
+    storage::{self, Storage},
+    user::models::DeletionPolicy,
+};
+use sqlx::postgres::PgPool;
+use std::sync::Arc;
+
+/// State of an app instance. It is injected into the handlers as an `Extension` and then passed
+/// down to the model layer. Cloning it is cheap: every field is reference counted.
+#[derive(Clone)]
+pub struct AppState {
+    /// Database connection pool
+    pub pool: PgPool,
+    /// Configuration used to build this instance
+    pub config: Arc<Configuration>,
+    /// Storage where uploaded files are saved
+    pub storage: Arc<dyn Storage>,
+    /// Keys used to encode and decode JWTs
+    pub keys: Arc<Keys>,
+    /// Mailer used to send emails to the users
+    pub mailer: Arc<dyn Mailer>,
+    /// Client of the OpenID Connect provider, if configured
+    pub oidc: Option<Arc<OidcClient>>,
+}
+
+impl AppState {
+    /// Build a new state from a configuration, opening a new database pool
+    pub async fn new(config: Configuration) -> Result<Self, AppError> {
+        let pool = db::setup(&config.database_url).await?;
+
+        Self::with_pool(config, pool)
+    }
+
+    /// Build a new state from a configuration and an already opened pool
+    pub fn with_pool(config: Configuration, pool: PgPool) -> Result<Self, AppError> {
+        let storage = storage::from_config(&config)?;
+        let keys = Keys::new(config.jwt_secret.as_bytes());
+        let mailer = mailer::from_config(&config)?;
+        let oidc = OidcClient::from_config(&config)?.map(Arc::new);
+
+        if DeletionPolicy::from_name(&config.account_deletion_policy).is_none() {
+            return Err(AppError::BadRequest(
+                "`ACCOUNT_DELETION_POLICY` must be `anonymize` or `cascade`".to_string(),
+            ));
+        }
+
+        Ok(Self {
+            pool,
+            config: Arc::new(config),
+            storage,
+            keys: Arc::new(keys),
+            mailer,
+            oidc,
+        })
+    }
+}
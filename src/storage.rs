@@ -0,0 +1,322 @@
+use crate::{config::Configuration, errors::AppError};
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDateTime};
+use s3::{creds::Credentials, Bucket, Region};
+use std::{
+    io::{ErrorKind, SeekFrom},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+/// Something which can keep the uploaded files. Files are identified by a key, their name
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Save a file, replacing the one with the same key
+    async fn put(&self, key: &str, data: &[u8]) -> Result<(), AppError>;
+    /// Move a local file into the storage, replacing the one with the same key
+    async fn put_file(&self, key: &str, path: &Path) -> Result<(), AppError>;
+    /// Delete a file. Deleting a missing file is not an error
+    async fn delete(&self, key: &str) -> Result<(), AppError>;
+    /// Read the bytes from `start` to `end`, included, of a file. Raises an `AppError::NotFound`
+    /// if it does not exist
+    async fn get_range(&self, key: &str, start: u64, end: u64) -> Result<Vec<u8>, AppError>;
+    /// Returns the size and the last change of a file. Raises an `AppError::NotFound` if it does
+    /// not exist
+    async fn stat(&self, key: &str) -> Result<FileInfo, AppError>;
+    /// Returns `true` if a file exists
+    async fn exists(&self, key: &str) -> Result<bool, AppError>;
+    /// List the keys of all the files
+    async fn list(&self) -> Result<Vec<String>, AppError>;
+}
+
+/// Metadata of a stored file
+pub struct FileInfo {
+    /// Size, in bytes
+    pub size: u64,
+    pub modified: NaiveDateTime,
+}
+
+/// Storage which saves files into a directory of the local filesystem
+pub struct LocalStorage {
+    base_path: PathBuf,
+}
+
+/// Storage which saves files into a bucket of a S3-compatible object storage, like MinIO
+pub struct S3Storage {
+    bucket: Bucket,
+}
+
+/// Build the storage chosen by the `STORAGE` variable: "local" or "s3"
+pub fn from_config(config: &Configuration) -> Result<Arc<dyn Storage>, AppError> {
+    build(&config.storage, config)
+}
+
+/// Build a storage by its name, with the settings of a configuration
+pub fn build(name: &str, config: &Configuration) -> Result<Arc<dyn Storage>, AppError> {
+    match name {
+        "local" => Ok(Arc::new(LocalStorage::new(config))),
+        "s3" => Ok(Arc::new(S3Storage::new(config)?)),
+        other => Err(AppError::BadRequest(format!("Unknown storage `{}`", other))),
+    }
+}
+
+/// Log the error of a storage, hiding it to the clients
+pub fn storage_error<E: std::fmt::Debug>(error: E) -> AppError {
+    tracing::error!("Storage error: {:?}", error);
+    AppError::Storage
+}
+
+impl LocalStorage {
+    pub fn new(config: &Configuration) -> Self {
+        Self {
+            base_path: PathBuf::from(&config.save_file_base_path),
+        }
+    }
+
+    /// Files are written with a hidden name, then renamed: a file is never seen half written
+    fn partial_path(&self, key: &str) -> Result<PathBuf, AppError> {
+        self.path(key)?;
+
+        Ok(self.base_path.join(format!(".{}.part", key)))
+    }
+
+    /// Keys are file names: a key with a path separator could escape the base path
+    fn path(&self, key: &str) -> Result<PathBuf, AppError> {
+        if key.is_empty() || key.contains('/') || key.contains('\\') || key.starts_with('.') {
+            return Err(AppError::NotFound("File not found".to_string()));
+        }
+
+        Ok(self.base_path.join(key))
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn put(&self, key: &str, data: &[u8]) -> Result<(), AppError> {
+        let partial = self.partial_path(key)?;
+        tokio::fs::write(&partial, data)
+            .await
+            .map_err(storage_error)?;
+
+        tokio::fs::rename(&partial, self.path(key)?)
+            .await
+            .map_err(storage_error)
+    }
+
+    async fn put_file(&self, key: &str, path: &Path) -> Result<(), AppError> {
+        // A rename between two filesystems fails: the file is copied near the storage first
+        if tokio::fs::rename(path, self.path(key)?).await.is_ok() {
+            return Ok(());
+        }
+
+        let partial = self.partial_path(key)?;
+        tokio::fs::copy(path, &partial)
+            .await
+            .map_err(storage_error)?;
+        tokio::fs::rename(&partial, self.path(key)?)
+            .await
+            .map_err(storage_error)?;
+
+        tokio::fs::remove_file(path).await.map_err(storage_error)
+    }
+
+    async fn get_range(&self, key: &str, start: u64, end: u64) -> Result<Vec<u8>, AppError> {
+        let mut file = match tokio::fs::File::open(self.path(key)?).await {
+            Ok(file) => file,
+            Err(error) if error.kind() == ErrorKind::NotFound => {
+                return Err(AppError::NotFound("File not found".to_string()))
+            }
+            Err(error) => return Err(storage_error(error)),
+        };
+
+        file.seek(SeekFrom::Start(start))
+            .await
+            .map_err(storage_error)?;
+        let mut data = vec![];
+        file.take(end + 1 - start)
+            .read_to_end(&mut data)
+            .await
+            .map_err(storage_error)?;
+
+        Ok(data)
+    }
+
+    async fn stat(&self, key: &str) -> Result<FileInfo, AppError> {
+        let metadata = match tokio::fs::metadata(self.path(key)?).await {
+            Ok(metadata) => metadata,
+            Err(error) if error.kind() == ErrorKind::NotFound => {
+                return Err(AppError::NotFound("File not found".to_string()))
+            }
+            Err(error) => return Err(storage_error(error)),
+        };
+
+        let modified = metadata.modified().map_err(storage_error)?;
+
+        Ok(FileInfo {
+            size: metadata.len(),
+            modified: DateTime::<chrono::Utc>::from(modified).naive_utc(),
+        })
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), AppError> {
+        match tokio::fs::remove_file(self.path(key)?).await {
+            Err(error) if error.kind() != ErrorKind::NotFound => Err(storage_error(error)),
+            _ => Ok(()),
+        }
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, AppError> {
+        Ok(tokio::fs::metadata(self.path(key)?).await.is_ok())
+    }
+
+    async fn list(&self) -> Result<Vec<String>, AppError> {
+        let mut keys = vec![];
+        let mut entries = tokio::fs::read_dir(&self.base_path)
+            .await
+            .map_err(storage_error)?;
+
+        while let Some(entry) = entries.next_entry().await.map_err(storage_error)? {
+            let is_file = entry.file_type().await.map_err(storage_error)?.is_file();
+            match entry.file_name().to_str() {
+                Some(name) if is_file && !name.starts_with('.') => keys.push(name.to_string()),
+                _ => {}
+            }
+        }
+
+        Ok(keys)
+    }
+}
+
+impl S3Storage {
+    pub fn new(config: &Configuration) -> Result<Self, AppError> {
+        let name = config
+            .s3_bucket
+            .as_ref()
+            .ok_or_else(|| AppError::BadRequest("`S3_BUCKET` is not set".to_string()))?;
+
+        let region = match &config.s3_endpoint {
+            Some(endpoint) => Region::Custom {
+                region: config.s3_region.clone(),
+                endpoint: endpoint.clone(),
+            },
+            None => config.s3_region.parse().map_err(storage_error)?,
+        };
+
+        let credentials = Credentials::new(
+            config.s3_access_key.as_deref(),
+            config.s3_secret_key.as_deref(),
+            None,
+            None,
+            None,
+        )
+        .map_err(storage_error)?;
+
+        let mut bucket = Bucket::new(name, region, credentials).map_err(storage_error)?;
+        // MinIO and most of the self-hosted services do not support virtual-hosted buckets
+        if config.s3_path_style {
+            bucket = bucket.with_path_style();
+        }
+
+        Ok(Self { bucket })
+    }
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    async fn put(&self, key: &str, data: &[u8]) -> Result<(), AppError> {
+        let response = self
+            .bucket
+            .put_object(key, data)
+            .await
+            .map_err(storage_error)?;
+
+        match response.status_code() {
+            200..=299 => Ok(()),
+            status => Err(storage_error(format!("PUT {} returned {}", key, status))),
+        }
+    }
+
+    async fn put_file(&self, key: &str, path: &Path) -> Result<(), AppError> {
+        // Big files are sent as a multipart upload, never read all at once
+        let mut file = tokio::fs::File::open(path).await.map_err(storage_error)?;
+        let status = self
+            .bucket
+            .put_object_stream(&mut file, key)
+            .await
+            .map_err(storage_error)?;
+
+        match status {
+            200..=299 => tokio::fs::remove_file(path).await.map_err(storage_error),
+            status => Err(storage_error(format!("PUT {} returned {}", key, status))),
+        }
+    }
+
+    async fn get_range(&self, key: &str, start: u64, end: u64) -> Result<Vec<u8>, AppError> {
+        let response = self
+            .bucket
+            .get_object_range(key, start, Some(end))
+            .await
+            .map_err(storage_error)?;
+
+        match response.status_code() {
+            200..=299 => Ok(response.bytes().to_vec()),
+            404 => Err(AppError::NotFound("File not found".to_string())),
+            status => Err(storage_error(format!("GET {} returned {}", key, status))),
+        }
+    }
+
+    async fn stat(&self, key: &str) -> Result<FileInfo, AppError> {
+        let (head, status) = self.bucket.head_object(key).await.map_err(storage_error)?;
+
+        match status {
+            200..=299 => Ok(FileInfo {
+                size: head.content_length.unwrap_or_default() as u64,
+                modified: head
+                    .last_modified
+                    .and_then(|x| DateTime::parse_from_rfc2822(&x).ok())
+                    .map(|x| x.naive_utc())
+                    .unwrap_or_default(),
+            }),
+            404 => Err(AppError::NotFound("File not found".to_string())),
+            status => Err(storage_error(format!("HEAD {} returned {}", key, status))),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), AppError> {
+        let response = self
+            .bucket
+            .delete_object(key)
+            .await
+            .map_err(storage_error)?;
+
+        match response.status_code() {
+            200..=299 | 404 => Ok(()),
+            status => Err(storage_error(format!("DELETE {} returned {}", key, status))),
+        }
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, AppError> {
+        let (_, status) = self.bucket.head_object(key).await.map_err(storage_error)?;
+
+        match status {
+            200..=299 => Ok(true),
+            404 => Ok(false),
+            status => Err(storage_error(format!("HEAD {} returned {}", key, status))),
+        }
+    }
+
+    async fn list(&self) -> Result<Vec<String>, AppError> {
+        let pages = self
+            .bucket
+            .list(String::new(), None)
+            .await
+            .map_err(storage_error)?;
+
+        Ok(pages
+            .into_iter()
+            .flat_map(|page| page.contents)
+            .map(|object| object.key)
+            .collect())
+    }
+}
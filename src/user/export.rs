@@ -0,0 +1,86 @@
+use crate::{
+    auth::models::ApiToken,
+    errors::AppError,
+    files::storage_key,
+    likes::models::Like,
+    model::{
+        bundle::{BundleFile, BundleSource, MAX_BUNDLE_SIZE},
+        models::{Model, ModelUpload},
+    },
+    state::AppState,
+    user::models::UserList,
+    warning::models::Warning,
+};
+use chrono::Local;
+use serde::Serialize;
+use std::collections::HashSet;
+
+/// A JSON file of the archive
+fn json_file<T: Serialize>(name: &str, value: &T) -> Result<BundleFile, AppError> {
+    let data = serde_json::to_vec_pretty(value).map_err(|error| error.to_string())?;
+
+    Ok(BundleFile {
+        name: name.to_string(),
+        modified: Local::now().naive_utc(),
+        source: BundleSource::Data(data),
+    })
+}
+
+/// List the files of the archive with all the data of an user: the profile, the models and their
+/// uploaded files, the likes and the warnings filed. Uploads which share the same stored file are
+/// written once, as `files/<key>`. The archive is streamed by `bundle::stream`
+pub async fn files(state: &AppState, user: &UserList) -> Result<Vec<BundleFile>, AppError> {
+    let models = Model::list_all_from_author(state, user.id).await?;
+    let uploads = ModelUpload::find_by_author(state, user.id).await?;
+    let likes = Like::find_by_user(state, user.id).await?;
+    let warnings = Warning::find_by_user(state, user.id).await?;
+    let tokens = ApiToken::list(state, user.id).await?;
+
+    let mut files = vec![
+        json_file("profile.json", user)?,
+        json_file("tokens.json", &tokens)?,
+        json_file("models.json", &models)?,
+        json_file("uploads.json", &uploads)?,
+        json_file("likes.json", &likes)?,
+        json_file("warnings.json", &warnings)?,
+    ];
+
+    let filepaths = uploads
+        .iter()
+        .map(|upload| &upload.filepath)
+        .chain(user.avatar.iter());
+    let mut keys = HashSet::new();
+    let mut total = 0;
+    for filepath in filepaths {
+        let key = storage_key(filepath);
+        if !keys.insert(key) {
+            continue;
+        }
+
+        let info = match state.storage.stat(key).await {
+            Ok(info) => info,
+            Err(error) => {
+                tracing::warn!("File `{}` is not exported: {:?}", key, error);
+                continue;
+            }
+        };
+
+        total += info.size;
+        files.push(BundleFile {
+            name: format!("files/{}", key),
+            modified: info.modified,
+            source: BundleSource::Stored {
+                key: key.to_string(),
+                size: info.size,
+            },
+        });
+    }
+
+    if total > MAX_BUNDLE_SIZE {
+        return Err(AppError::BadRequest(
+            "Data is too big to be exported as an archive".to_string(),
+        ));
+    }
+
+    Ok(files)
+}
@@ -1,10 +1,15 @@
 use crate::{
-    config::CONFIG,
-    db::get_client,
+    auth::{
+        password,
+        permissions::{Permission, Role},
+    },
     errors::AppError,
-    model::models::{Model, ModelUser},
+    files::delete_upload,
+    model::models::{Model, ModelUpload, ModelUser, PendingUpload},
+    state::AppState,
 };
 
+use chrono::{Duration, Local, NaiveDateTime};
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, NoneAsEmptyString};
 use sqlx::Row;
@@ -15,13 +20,13 @@ use validator::Validate;
 pub struct User {
     id: i32,
     name: String,
-    #[validate(length(min = 4, message = "Can not be empty"))]
+    #[validate(email(message = "Invalid email address"))]
     email: String,
     #[validate(length(min = 2, message = "Can not be empty"))]
     username: String,
     #[validate(length(min = 8, message = "Must be min 8 chars length"))]
     password: String,
-    is_staff: Option<bool>,
+    role: Role,
     avatar: Option<String>,
 }
 
@@ -31,7 +36,8 @@ pub struct UserEdit {
     pub name: String,
     pub email: String,
     pub username: String,
-    pub is_staff: Option<bool>,
+    /// New role of the user. It is ignored if the editor can't promote users
+    pub role: Option<Role>,
 }
 
 /// Response used to print a user (or a users list)
@@ -40,13 +46,90 @@ pub struct UserEdit {
 pub struct UserList {
     pub id: i32,
     pub name: String,
-    #[validate(length(min = 4, message = "Can not be empty"))]
+    #[validate(email(message = "Invalid email address"))]
     pub email: String,
     #[validate(length(min = 2, message = "Can not be empty"))]
     pub username: String,
-    pub is_staff: Option<bool>,
+    pub role: Role,
     #[serde_as(as = "NoneAsEmptyString")]
     pub avatar: Option<String>,
+    pub email_verified: bool,
+}
+
+/// Restriction applied to an account by the staff
+#[derive(Serialize, Deserialize, sqlx::Type, Clone, Copy, PartialEq, Eq, Debug)]
+#[sqlx(type_name = "user_ban_kind", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum BanKind {
+    /// The user can log in and read, but can not write anything
+    Suspended,
+    /// The user can not log in, and their models are hidden from the lists
+    Banned,
+}
+
+/// A suspension or a ban. Lifted and expired ones are kept as a record of the staff actions
+#[derive(Serialize, sqlx::FromRow)]
+pub struct UserBan {
+    pub id: i32,
+    pub user_id: i32,
+    pub kind: BanKind,
+    pub reason: String,
+    /// It is `None` for a ban without an end
+    pub expires: Option<NaiveDateTime>,
+    pub created_by: Option<i32>,
+    pub created: NaiveDateTime,
+    pub lifted_by: Option<i32>,
+    pub lifted: Option<NaiveDateTime>,
+}
+
+/// Payload used to suspend or ban an user
+#[derive(Deserialize)]
+pub struct UserBanCreate {
+    pub kind: BanKind,
+    pub reason: String,
+    /// The ban has no end if it is `None`
+    pub expires_in_hours: Option<i64>,
+}
+
+/// Storage used by an user with the uploads of their models, and how much they can use
+#[derive(Serialize)]
+pub struct StorageUsage {
+    /// Bytes of the uploads of the models of the user
+    pub used: i64,
+    /// Bytes of the resumable uploads in progress on the models of the user
+    pub reserved: i64,
+    pub quota: i64,
+    /// `false` if the quota is the default one
+    pub custom_quota: bool,
+    /// Bytes which can still be uploaded
+    pub available: i64,
+}
+
+/// Payload used by a staffer to change the storage quota of an user
+#[derive(Deserialize)]
+pub struct StorageQuotaEdit {
+    /// Quota in bytes. With `None` the user gets the default quota
+    pub quota: Option<i64>,
+}
+
+/// What happens to the data of a deleted account
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum DeletionPolicy {
+    /// The user becomes anonymous and its models are kept
+    Anonymize,
+    /// The user is deleted with its models and their files
+    Cascade,
+}
+
+impl DeletionPolicy {
+    /// Returns the policy with the name used by the configuration
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "anonymize" => Some(DeletionPolicy::Anonymize),
+            "cascade" => Some(DeletionPolicy::Cascade),
+            _ => None,
+        }
+    }
 }
 
 impl User {
@@ -58,25 +141,25 @@ impl User {
             email,
             username,
             password,
-            is_staff: Some(false),
+            role: Role::User,
             avatar: None,
         }
     }
 
-    /// Create a new user from the model using a SHA256 crypted password
-    pub async fn create(user: User) -> Result<UserList, AppError> {
-        let pool = unsafe { get_client() };
+    /// Create a new user from the model using an Argon2 hashed password
+    pub async fn create(state: &AppState, user: User) -> Result<UserList, AppError> {
+        let pool = &state.pool;
 
         user.validate()
             .map_err(|error| AppError::BadRequest(error.to_string()))?;
 
-        let crypted_password = sha256::digest(user.password);
+        let crypted_password = password::hash(&user.password).await?;
 
         let rec: UserList = sqlx::query_as(
             r#"
                 INSERT INTO users (name, email, username, password)
                 VALUES ( $1, $2, $3, $4)
-                RETURNING id, name, email, username, is_staff, avatar
+                RETURNING id, name, email, username, role, avatar, email_verified
             "#,
         )
         .bind(user.name)
@@ -89,33 +172,56 @@ impl User {
         Ok(rec)
     }
 
-    /// Find a user using the model. It used for login
-    pub async fn find(user: User) -> Result<UserList, AppError> {
-        let pool = unsafe { get_client() };
+    /// Find a user using the model. It used for login: the password is checked against the stored
+    /// hash and, if that hash uses an outdated format, it is replaced by an Argon2 one
+    pub async fn find(state: &AppState, user: User) -> Result<UserList, AppError> {
+        let pool = &state.pool;
 
-        let crypted_password = sha256::digest(user.password);
-
-        let rec: UserList = sqlx::query_as(
+        let rec: Option<(i32, String)> = sqlx::query_as(
             r#"
-                SELECT id, name, email, username, is_staff, avatar FROM "users"
-                WHERE username = $1 AND password = $2
+                SELECT id, password FROM "users"
+                WHERE username = $1
             "#,
         )
-        .bind(user.username)
-        .bind(crypted_password)
-        .fetch_one(pool)
+        .bind(&user.username)
+        .fetch_optional(pool)
         .await?;
 
-        Ok(rec)
+        let (user_id, stored) = match rec {
+            Some(rec) => rec,
+            None => {
+                // A missing user is answered in the same time of a wrong password
+                password::verify_dummy(&user.password).await;
+                return Err(AppError::NotFound("User not found".to_string()));
+            }
+        };
+
+        if !password::verify(&user.password, &stored).await {
+            return Err(AppError::NotFound("User not found".to_string()));
+        }
+
+        if password::needs_rehash(&stored) {
+            sqlx::query(
+                r#"
+                UPDATE users SET password = $1 WHERE id = $2
+                "#,
+            )
+            .bind(password::hash(&user.password).await?)
+            .bind(user_id)
+            .execute(pool)
+            .await?;
+        }
+
+        User::find_by_id(state, user_id).await
     }
 
     /// Returns the user with id = `user_id`
-    pub async fn find_by_id(user_id: i32) -> Result<UserList, AppError> {
-        let pool = unsafe { get_client() };
+    pub async fn find_by_id(state: &AppState, user_id: i32) -> Result<UserList, AppError> {
+        let pool = &state.pool;
 
         let rec: UserList = sqlx::query_as(
             r#"
-                SELECT id, name, email, username, is_staff, avatar FROM "users"
+                SELECT id, name, email, username, role, avatar, email_verified FROM "users"
                 WHERE id = $1
             "#,
         )
@@ -126,17 +232,76 @@ impl User {
         Ok(rec)
     }
 
+    /// Returns the user with email = `email`, if it exists
+    pub async fn find_by_email(
+        state: &AppState,
+        email: &str,
+    ) -> Result<Option<UserList>, AppError> {
+        let pool = &state.pool;
+
+        let rec: Option<UserList> = sqlx::query_as(
+            r#"
+                SELECT id, name, email, username, role, avatar, email_verified FROM "users"
+                WHERE email = $1
+            "#,
+        )
+        .bind(email)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(rec)
+    }
+
+    /// Replace the password of the user with id = `user_id`
+    pub async fn set_password(
+        state: &AppState,
+        user_id: i32,
+        new_password: &str,
+    ) -> Result<(), AppError> {
+        let pool = &state.pool;
+
+        password::validate(new_password)?;
+
+        sqlx::query(
+            r#"
+            UPDATE users SET password = $1 WHERE id = $2
+            "#,
+        )
+        .bind(password::hash(new_password).await?)
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Mark the email of the user with id = `user_id` as verified
+    pub async fn verify_email(state: &AppState, user_id: i32) -> Result<(), AppError> {
+        let pool = &state.pool;
+
+        sqlx::query(
+            r#"
+            UPDATE users SET email_verified = true WHERE id = $1
+            "#,
+        )
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
     /// List all users
-    pub async fn list(page: i64) -> Result<Vec<UserList>, AppError> {
-        let pool = unsafe { get_client() };
+    pub async fn list(state: &AppState, page: i64) -> Result<Vec<UserList>, AppError> {
+        let pool = &state.pool;
         let rows: Vec<UserList> = sqlx::query_as(
-            r#"SELECT id, name, email, username, is_staff, avatar FROM users
+            r#"SELECT id, name, email, username, role, avatar, email_verified FROM users
             ORDER BY id DESC
             LIMIT $1 OFFSET $2
             "#,
         )
-        .bind(CONFIG.page_limit)
-        .bind(CONFIG.page_limit * page)
+        .bind(state.config.page_limit)
+        .bind(state.config.page_limit * page)
         .fetch_all(pool)
         .await?;
 
@@ -144,8 +309,8 @@ impl User {
     }
 
     /// Return the number of users.
-    pub async fn count() -> Result<i64, AppError> {
-        let pool = unsafe { get_client() };
+    pub async fn count(state: &AppState) -> Result<i64, AppError> {
+        let pool = &state.pool;
         let cursor = sqlx::query(r#"SELECT COUNT(id) as count FROM users"#)
             .fetch_one(pool)
             .await?;
@@ -155,8 +320,8 @@ impl User {
     }
 
     /// Prevent the "uniquess" Postgres fields check. Check if username has been taken
-    pub async fn username_has_taken(username: &String) -> Result<bool, AppError> {
-        let pool = unsafe { get_client() };
+    pub async fn username_has_taken(state: &AppState, username: &String) -> Result<bool, AppError> {
+        let pool = &state.pool;
         let cursor = sqlx::query(
             r#"
                 SELECT COUNT(id) as count FROM users WHERE username = $1
@@ -172,8 +337,8 @@ impl User {
     }
 
     /// Prevent the "uniquess" Postgres fields check. Check if email has been taken
-    pub async fn email_has_taken(email: &String) -> Result<bool, AppError> {
-        let pool = unsafe { get_client() };
+    pub async fn email_has_taken(state: &AppState, email: &String) -> Result<bool, AppError> {
+        let pool = &state.pool;
         let cursor = sqlx::query(
             r#"
                 SELECT COUNT(id) as count FROM users WHERE email = $1
@@ -189,10 +354,318 @@ impl User {
     }
 }
 
+impl UserBan {
+    /// Returns the active restriction of an user, if any. A ban wins over a suspension
+    pub async fn active(state: &AppState, user_id: i32) -> Result<Option<UserBan>, AppError> {
+        let pool = &state.pool;
+
+        let rec: Option<UserBan> = sqlx::query_as(
+            r#"
+                SELECT * FROM user_bans
+                WHERE user_id = $1 AND lifted IS NULL AND (expires IS NULL OR expires > $2)
+                ORDER BY kind DESC, id DESC
+                LIMIT 1
+            "#,
+        )
+        .bind(user_id)
+        .bind(Local::now().naive_utc())
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(rec)
+    }
+
+    /// Raises an `AppError::Forbidden` if the user is banned
+    pub async fn check_banned(state: &AppState, user_id: i32) -> Result<(), AppError> {
+        match Self::active(state, user_id).await? {
+            Some(ban) if ban.kind == BanKind::Banned => Err(ban.error()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the error shown to the restricted user
+    pub fn error(&self) -> AppError {
+        let kind = match self.kind {
+            BanKind::Suspended => "suspended",
+            BanKind::Banned => "banned",
+        };
+
+        AppError::Forbidden(match self.expires {
+            Some(expires) => format!(
+                "This account is {} until {}: {}",
+                kind, expires, self.reason
+            ),
+            None => format!("This account is {}: {}", kind, self.reason),
+        })
+    }
+
+    /// Suspend or ban an user. A previous active restriction is lifted by the new one
+    pub async fn create(
+        state: &AppState,
+        user_id: i32,
+        payload: UserBanCreate,
+        created_by: i32,
+    ) -> Result<UserBan, AppError> {
+        if payload.reason.trim().is_empty() {
+            return Err(AppError::BadRequest("Reason can not be empty".to_string()));
+        }
+
+        let now = Local::now().naive_utc();
+        let expires = match payload.expires_in_hours {
+            Some(hours) if hours <= 0 => {
+                return Err(AppError::BadRequest(
+                    "Expiration must be in the future".to_string(),
+                ))
+            }
+            Some(hours) => Some(now + Duration::hours(hours)),
+            None => None,
+        };
+
+        Self::lift(state, user_id, created_by).await?;
+
+        let pool = &state.pool;
+        let rec: UserBan = sqlx::query_as(
+            r#"
+                INSERT INTO user_bans (user_id, kind, reason, expires, created_by, created)
+                VALUES ( $1, $2, $3, $4, $5, $6)
+                RETURNING *
+            "#,
+        )
+        .bind(user_id)
+        .bind(payload.kind)
+        .bind(payload.reason)
+        .bind(expires)
+        .bind(created_by)
+        .bind(now)
+        .fetch_one(pool)
+        .await?;
+
+        Ok(rec)
+    }
+
+    /// Lift the active restrictions of an user. Returns how many they were
+    pub async fn lift(state: &AppState, user_id: i32, lifted_by: i32) -> Result<u64, AppError> {
+        let pool = &state.pool;
+        let now = Local::now().naive_utc();
+
+        let result = sqlx::query(
+            r#"
+                UPDATE user_bans SET lifted = $1, lifted_by = $2
+                WHERE user_id = $3 AND lifted IS NULL AND (expires IS NULL OR expires > $1)
+            "#,
+        )
+        .bind(now)
+        .bind(lifted_by)
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// List all the restrictions of an user, also the lifted and expired ones
+    pub async fn list(state: &AppState, user_id: i32) -> Result<Vec<UserBan>, AppError> {
+        let pool = &state.pool;
+
+        let rows: Vec<UserBan> = sqlx::query_as(
+            r#"
+                SELECT * FROM user_bans WHERE user_id = $1 ORDER BY id DESC
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows)
+    }
+}
+
+impl StorageUsage {
+    /// Returns the storage used by the user `user_id`
+    pub async fn find(state: &AppState, user_id: i32) -> Result<StorageUsage, AppError> {
+        let pool = &state.pool;
+        // Expired resumable uploads do not reserve space, even if they are not deleted yet
+        let limit = Local::now().naive_utc() - Duration::hours(PendingUpload::EXPIRATION_HOURS);
+
+        let (used, reserved, quota): (i64, i64, Option<i64>) = sqlx::query_as(
+            r#"
+                SELECT
+                    (SELECT COALESCE(SUM(uploads.size), 0)::BIGINT FROM uploads
+                     JOIN models ON models.id = uploads.model_id
+                     WHERE models.author_id = $1),
+                    (SELECT COALESCE(SUM(pending_uploads.length), 0)::BIGINT FROM pending_uploads
+                     JOIN models ON models.id = pending_uploads.model_id
+                     WHERE models.author_id = $1 AND pending_uploads.updated >= $2),
+                    (SELECT storage_quota FROM users WHERE id = $1)
+            "#,
+        )
+        .bind(user_id)
+        .bind(limit)
+        .fetch_one(pool)
+        .await?;
+
+        let custom_quota = quota.is_some();
+        let quota = quota.unwrap_or(state.config.default_storage_quota);
+
+        Ok(StorageUsage {
+            used,
+            reserved,
+            quota,
+            custom_quota,
+            available: (quota - used - reserved).max(0),
+        })
+    }
+
+    /// Checks that `size` bytes fit in the available storage
+    pub fn require(&self, size: i64) -> Result<(), AppError> {
+        if size > self.available {
+            return Err(AppError::QuotaExceeded(self.available));
+        }
+
+        Ok(())
+    }
+
+    /// Set the quota of the user `user_id`. With `None` the user gets the default quota
+    pub async fn set_quota(
+        state: &AppState,
+        user_id: i32,
+        quota: Option<i64>,
+    ) -> Result<(), AppError> {
+        let pool = &state.pool;
+
+        if quota.is_some_and(|x| x < 0) {
+            return Err(AppError::BadRequest(
+                "Quota can not be negative".to_string(),
+            ));
+        }
+
+        sqlx::query(r#"UPDATE users SET storage_quota = $1 WHERE id = $2"#)
+            .bind(quota)
+            .bind(user_id)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+}
+
 impl UserList {
+    /// Returns `true` if the role of the user grants `permission`
+    pub fn can(&self, permission: Permission) -> bool {
+        self.role.can(permission)
+    }
+
+    /// Raises an `AppError::Unauthorized` if the user has not `permission`
+    pub fn require(&self, permission: Permission) -> Result<(), AppError> {
+        if self.can(permission) {
+            Ok(())
+        } else {
+            Err(AppError::Unauthorized)
+        }
+    }
+
+    /// Raises an `AppError::Unauthorized` if the user is not `owner_id` and has not `permission`
+    pub fn require_owner_or(&self, owner_id: i32, permission: Permission) -> Result<(), AppError> {
+        if self.id == owner_id {
+            Ok(())
+        } else {
+            self.require(permission)
+        }
+    }
+
+    /// Delete the account following the configured policy. Sessions, tokens and linked identities
+    /// are always removed, as the login attempts of the user. Likes and filed warnings are
+    /// kept without an user if the account is deleted
+    pub async fn delete(self, state: &AppState) -> Result<(), AppError> {
+        let policy = DeletionPolicy::from_name(&state.config.account_deletion_policy)
+            .unwrap_or(DeletionPolicy::Anonymize);
+
+        let mut files: Vec<String> = self.avatar.iter().cloned().collect();
+        if policy == DeletionPolicy::Cascade {
+            files.extend(
+                ModelUpload::find_by_author(state, self.id)
+                    .await?
+                    .into_iter()
+                    .map(|upload| upload.filepath),
+            );
+        }
+
+        let mut tx = state.pool.begin().await?;
+
+        sqlx::query(r#"DELETE FROM login_attempts WHERE username = $1"#)
+            .bind(&self.username)
+            .execute(&mut tx)
+            .await?;
+
+        match policy {
+            DeletionPolicy::Anonymize => {
+                for table in [
+                    "refresh_tokens",
+                    "api_tokens",
+                    "user_tokens",
+                    "recovery_codes",
+                    "user_identities",
+                ] {
+                    sqlx::query(&format!(r#"DELETE FROM {} WHERE user_id = $1"#, table))
+                        .bind(self.id)
+                        .execute(&mut tx)
+                        .await?;
+                }
+
+                // The password can not match any hash, so nobody can log in again
+                sqlx::query(
+                    r#"
+                    UPDATE users SET
+                        name = 'Deleted user',
+                        email = 'deleted-' || id || '@invalid',
+                        username = 'deleted-' || id,
+                        password = '!',
+                        avatar = NULL,
+                        role = 'user',
+                        email_verified = false,
+                        totp_secret = NULL,
+                        totp_enabled = false,
+                        totp_last_step = NULL
+                    WHERE id = $1
+                    "#,
+                )
+                .bind(self.id)
+                .execute(&mut tx)
+                .await?;
+            }
+            DeletionPolicy::Cascade => {
+                // Uploads are deleted by the `ON DELETE CASCADE` of their model
+                sqlx::query(r#"DELETE FROM models WHERE author_id = $1"#)
+                    .bind(self.id)
+                    .execute(&mut tx)
+                    .await?;
+
+                sqlx::query(r#"DELETE FROM users WHERE id = $1"#)
+                    .bind(self.id)
+                    .execute(&mut tx)
+                    .await?;
+            }
+        }
+
+        tx.commit().await?;
+
+        // Rows are gone: a missing file can not stop the deletion anymore
+        for file in files {
+            if let Err(error) = delete_upload(state, &file).await {
+                tracing::warn!("File `{}` has not been deleted: {:?}", file, error);
+            }
+        }
+
+        Ok(())
+    }
+
     /// Edit an user avatar
-    pub async fn edit_avatar(&mut self, avatar: Option<String>) -> Result<(), AppError> {
-        let pool = unsafe { get_client() };
+    pub async fn edit_avatar(
+        &mut self,
+        state: &AppState,
+        avatar: Option<String>,
+    ) -> Result<(), AppError> {
+        let pool = &state.pool;
         sqlx::query(
             r#"
             UPDATE users SET avatar = $1 WHERE id = $2
@@ -209,28 +682,36 @@ impl UserList {
     }
 
     /// Edit an user
-    pub async fn edit(&mut self, payload: UserEdit) -> Result<(), AppError> {
-        let pool = unsafe { get_client() };
+    pub async fn edit(&mut self, state: &AppState, payload: UserEdit) -> Result<(), AppError> {
+        let pool = &state.pool;
 
         // Make assignments before the `sqlx::query()` so to perform validation.
         // If the `AppError::BadRequest` is raised, the query (and then the update) will be skipped
+        // A new email address has to be verified again
+        if self.email != payload.email {
+            self.email_verified = false;
+        }
         self.name = payload.name.clone();
         self.username = payload.username.clone();
         self.email = payload.email.clone();
-        self.is_staff = payload.is_staff;
+        if let Some(role) = payload.role {
+            self.role = role;
+        }
 
         self.validate()
             .map_err(|error| AppError::BadRequest(error.to_string()))?;
 
         sqlx::query(
             r#"
-            UPDATE users SET name = $1, username = $2, email = $3, is_staff = $4 WHERE id = $5
+            UPDATE users SET name = $1, username = $2, email = $3, role = $4, email_verified = $5
+            WHERE id = $6
             "#,
         )
         .bind(&payload.name)
         .bind(&payload.username)
         .bind(&payload.email)
-        .bind(payload.is_staff.unwrap_or_default())
+        .bind(self.role)
+        .bind(self.email_verified)
         .bind(self.id)
         .execute(pool)
         .await?;
@@ -239,12 +720,16 @@ impl UserList {
     }
 
     /// Get all models created by an user
-    pub async fn get_models(&self, page: i64) -> Result<Vec<ModelUser>, AppError> {
-        Model::list_from_author(page, self.id).await
+    pub async fn get_models(
+        &self,
+        state: &AppState,
+        page: i64,
+    ) -> Result<Vec<ModelUser>, AppError> {
+        Model::list_from_author(state, page, self.id).await
     }
 
     /// Returns the number of models for an user
-    pub async fn count_models(&self) -> Result<i64, AppError> {
-        Model::count_filter_by_author(self.id).await
+    pub async fn count_models(&self, state: &AppState) -> Result<i64, AppError> {
+        Model::count_filter_by_author(state, self.id).await
     }
 }
@@ -1,13 +1,31 @@
 use crate::{
-    auth::models::Claims,
+    auth::{
+        models::{ApiToken, ApiTokenCreate, ApiTokenCreated, Claims, RefreshToken, Scope},
+        permissions::{Permission, RolePermissions},
+    },
     errors::AppError,
-    files::{delete_upload, upload},
+    files::{content_disposition, delete_upload, discard_upload, upload},
+    model::bundle,
     pagination::{ModelPagination, Pagination, UserPagination},
-    user::models::{User, UserEdit, UserList},
+    routes::JsonCreate,
+    state::AppState,
+    user::{
+        export,
+        models::{
+            BanKind, StorageQuotaEdit, StorageUsage, User, UserBan, UserBanCreate, UserEdit,
+            UserList,
+        },
+    },
 };
 use axum::{
-    extract::{ContentLengthLimit, Multipart, Path, Query},
-    routing::{delete, get, put},
+    body::boxed,
+    extract::{Extension, Multipart, Path, Query},
+    http::{
+        header::{self, HeaderMap, HeaderValue},
+        StatusCode,
+    },
+    response::{IntoResponse, Response},
+    routing::{delete, get, post, put},
     Json, Router,
 };
 
@@ -15,72 +33,170 @@ use axum::{
 pub fn create_route() -> Router {
     Router::new()
         .route("/", get(list_users))
-        .route("/me", get(get_me))
+        .route("/me", get(get_me).delete(delete_me))
+        .route("/me/export", get(export_me))
+        .route("/me/permissions", get(get_my_permissions))
+        .route("/me/storage", get(get_my_storage))
         .route("/me/avatar", put(edit_my_avatar).delete(delete_my_avatar))
+        .route("/me/tokens", get(list_my_tokens).post(create_my_token))
+        .route("/me/tokens/:id", delete(delete_my_token))
         .route("/:id", get(get_user).put(edit_user))
         .route("/:id/avatar", delete(delete_avatar))
+        .route("/:id/ban", post(ban_user).delete(unban_user))
+        .route("/:id/bans", get(list_user_bans))
+        .route("/:id/storage", get(get_user_storage).put(edit_user_storage))
         .route("/:id/models", get(get_user_models))
 }
 
 /// List users. Checks Authorization token
 async fn list_users(
-    _: Claims,
+    Extension(state): Extension<AppState>,
+    claims: Claims,
     pagination: Query<Pagination>,
 ) -> Result<Json<UserPagination>, AppError> {
+    claims.require(Scope::Read)?;
+
     let page = pagination.0.page.unwrap_or_default();
-    let results = User::list(page).await?;
-    let count = User::count().await?;
+    let results = User::list(&state, page).await?;
+    let count = User::count(&state).await?;
 
     Ok(Json(UserPagination { count, results }))
 }
 
 /// Get info about me
-async fn get_me(claims: Claims) -> Result<Json<UserList>, AppError> {
-    match User::find_by_id(claims.user_id).await {
+async fn get_me(
+    Extension(state): Extension<AppState>,
+    claims: Claims,
+) -> Result<Json<UserList>, AppError> {
+    claims.require(Scope::Read)?;
+
+    match User::find_by_id(&state, claims.user_id).await {
         Ok(user) => Ok(Json(user)),
         Err(_) => Err(AppError::NotFound("User not found".to_string())),
     }
 }
 
+/// Delete the account of the user linked to the claims, following the configured policy. It can
+/// not be done by a personal access token
+async fn delete_me(
+    Extension(state): Extension<AppState>,
+    claims: Claims,
+) -> Result<StatusCode, AppError> {
+    claims.require(Scope::Write)?;
+    claims.require_session()?;
+
+    let user = match User::find_by_id(&state, claims.user_id).await {
+        Ok(user) => user,
+        Err(_) => return Err(AppError::NotFound("User not found".to_string())),
+    };
+
+    user.delete(&state).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Download a ZIP archive with all the data of the user linked to the claims. It can not be done
+/// by a personal access token. The archive is streamed while it is built
+async fn export_me(
+    Extension(state): Extension<AppState>,
+    claims: Claims,
+) -> Result<Response, AppError> {
+    claims.require(Scope::Read)?;
+    claims.require_session()?;
+
+    let user = match User::find_by_id(&state, claims.user_id).await {
+        Ok(user) => user,
+        Err(_) => return Err(AppError::NotFound("User not found".to_string())),
+    };
+
+    let files = export::files(&state, &user).await?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/zip"),
+    );
+    headers.insert(
+        header::CONTENT_DISPOSITION,
+        HeaderValue::from_str(&content_disposition(
+            "attachment",
+            &format!("verden-{}.zip", user.username),
+        ))
+        .unwrap(),
+    );
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+
+    Ok((headers, boxed(bundle::stream(state, files))).into_response())
+}
+
+/// Get the role and the permissions of the user linked to the claims
+async fn get_my_permissions(
+    Extension(state): Extension<AppState>,
+    claims: Claims,
+) -> Result<Json<RolePermissions>, AppError> {
+    claims.require(Scope::Read)?;
+
+    let user = User::find_by_id(&state, claims.user_id).await?;
+
+    Ok(Json(user.role.describe()))
+}
+
 /// Edit the avatar of the user linked to the claims
 async fn edit_my_avatar(
+    Extension(state): Extension<AppState>,
     claims: Claims,
-    ContentLengthLimit(multipart): ContentLengthLimit<Multipart, { 1024 * 1024 * 5 }>,
+    multipart: Multipart,
 ) -> Result<Json<UserList>, AppError> {
-    let mut user = match User::find_by_id(claims.user_id).await {
+    claims.require(Scope::Write)?;
+
+    let mut user = match User::find_by_id(&state, claims.user_id).await {
         Ok(user) => user,
         Err(_) => {
             return Err(AppError::NotFound("User not found".to_string()));
         }
     };
 
-    if user.avatar.is_some() {
-        let avatar_url = user.avatar.as_ref().unwrap();
-        delete_upload(avatar_url)?;
+    if !user.email_verified {
+        return Err(AppError::Forbidden(
+            "Verify your email address before uploading files".to_string(),
+        ));
     }
 
-    match upload(
+    // The stored avatar is cleaned of its metadata, and resized copies are made
+    let saved_file = upload(
+        &state,
         multipart,
-        vec!["jpg", "jpeg", "png", "webp"],
-        Some(format!("avatar-{}", user.id)),
+        vec!["jpg", "png", "webp"],
+        state.config.max_avatar_size,
     )
-    .await
+    .await?;
+
+    // The old avatar is released only when the user points to the new one
+    let old_avatar = user.avatar.clone();
+    if let Err(error) = user
+        .edit_avatar(&state, Some(saved_file.filepath.clone()))
+        .await
     {
-        Ok(saved_file) => {
-            user.edit_avatar(Some(saved_file)).await?;
+        discard_upload(&state, &saved_file.filepath).await;
+        return Err(error);
+    }
 
-            Ok(Json(user))
-        }
-        Err(e) => Err(e),
+    if let Some(avatar_url) = old_avatar {
+        remove_avatar_file(&state, &avatar_url).await;
     }
+
+    Ok(Json(user))
 }
 
 /// A staffer can delete an user `id`'s avatar
 async fn delete_avatar(
+    Extension(state): Extension<AppState>,
     Path(user_id): Path<i32>,
     claims: Claims,
 ) -> Result<Json<UserList>, AppError> {
-    let mut user = match User::find_by_id(user_id).await {
+    claims.require(Scope::Write)?;
+
+    let mut user = match User::find_by_id(&state, user_id).await {
         Ok(user) => user,
         Err(_) => {
             return Err(AppError::NotFound("User not found".to_string()));
@@ -90,110 +206,124 @@ async fn delete_avatar(
     // If the user of the access token is different than the user they want to edit, checks if the
     // first user is an admin
     if claims.user_id != user.id {
-        match User::find_by_id(claims.user_id).await {
-            Ok(user) => {
-                if !(user.is_staff.unwrap()) {
-                    return Err(AppError::Unauthorized);
-                }
-            }
+        match User::find_by_id(&state, claims.user_id).await {
+            Ok(user) => user.require(Permission::UserAvatarDeleteAny)?,
             Err(_) => {
                 return Err(AppError::NotFound("User not found".to_string()));
             }
         };
     }
 
-    if user.avatar.is_some() {
-        let avatar_url = user.avatar.as_ref().unwrap();
-        delete_upload(avatar_url)?;
-    }
+    let old_avatar = user.avatar.clone();
+    user.edit_avatar(&state, None).await?;
 
-    user.edit_avatar(None).await?;
+    if let Some(avatar_url) = old_avatar {
+        remove_avatar_file(&state, &avatar_url).await;
+    }
 
     Ok(Json(user))
 }
 
 /// Delete the avatar of the user linked to the claims
-async fn delete_my_avatar(claims: Claims) -> Result<Json<UserList>, AppError> {
-    let mut user = match User::find_by_id(claims.user_id).await {
+async fn delete_my_avatar(
+    Extension(state): Extension<AppState>,
+    claims: Claims,
+) -> Result<Json<UserList>, AppError> {
+    claims.require(Scope::Write)?;
+
+    let mut user = match User::find_by_id(&state, claims.user_id).await {
         Ok(user) => user,
         Err(_) => {
             return Err(AppError::NotFound("User not found".to_string()));
         }
     };
 
-    if user.avatar.is_some() {
-        let avatar_url = user.avatar.as_ref().unwrap();
-        delete_upload(avatar_url)?;
-    }
+    let old_avatar = user.avatar.clone();
+    user.edit_avatar(&state, None).await?;
 
-    user.edit_avatar(None).await?;
+    if let Some(avatar_url) = old_avatar {
+        remove_avatar_file(&state, &avatar_url).await;
+    }
 
     Ok(Json(user))
 }
 
+/// Release an old avatar after the user stopped pointing to it. A file which can not be deleted
+/// now is left to the storage check
+async fn remove_avatar_file(state: &AppState, avatar_url: &str) {
+    if let Err(error) = delete_upload(state, avatar_url).await {
+        tracing::warn!("File `{}` has not been deleted: {:?}", avatar_url, error);
+    }
+}
+
 /// Get an user with id = `user_id`
-async fn get_user(Path(user_id): Path<i32>) -> Result<Json<UserList>, AppError> {
-    match User::find_by_id(user_id).await {
+async fn get_user(
+    Extension(state): Extension<AppState>,
+    Path(user_id): Path<i32>,
+) -> Result<Json<UserList>, AppError> {
+    match User::find_by_id(&state, user_id).await {
         Ok(user) => Ok(Json(user)),
         Err(_) => Err(AppError::NotFound("User not found".to_string())),
     }
 }
 
-/// Edit an user with id = `user_id`. Only staffers and owner of that account can perform this
-/// action.
-/// Only staffers can update the user `is_staff` value
+/// Edit an user with id = `user_id`. Only the owner of that account and who can edit any user can
+/// perform this action.
+/// Only who can promote users can update the user `role` value
 async fn edit_user(
+    Extension(state): Extension<AppState>,
     Path(user_id): Path<i32>,
     Json(mut payload): Json<UserEdit>,
     claims: Claims,
 ) -> Result<Json<UserList>, AppError> {
-    let mut user = match User::find_by_id(user_id).await {
+    claims.require(Scope::Write)?;
+
+    let mut user = match User::find_by_id(&state, user_id).await {
         Ok(user) => user,
         Err(_) => {
             return Err(AppError::NotFound("User not found".to_string()));
         }
     };
 
-    let claimed = match User::find_by_id(claims.user_id).await {
+    let claimed = match User::find_by_id(&state, claims.user_id).await {
         Ok(user) => user,
         Err(_) => {
             return Err(AppError::NotFound("User not found".to_string()));
         }
     };
 
-    if user.id != claimed.id {
-        if !(claimed.is_staff.unwrap()) {
-            return Err(AppError::Unauthorized);
-        }
-    }
+    claimed.require_owner_or(user.id, Permission::UserEditAny)?;
 
-    if !claimed.is_staff.unwrap() && user.is_staff != payload.is_staff {
-        payload.is_staff = user.is_staff;
+    if !claimed.can(Permission::UserPromote) {
+        payload.role = None;
     }
 
-    if user.email != payload.email && User::email_has_taken(&payload.email).await? {
+    if user.email != payload.email && User::email_has_taken(&state, &payload.email).await? {
         return Err(AppError::BadRequest(
             "An user with this email already exists".to_string(),
         ));
     }
 
-    if user.username != payload.username && User::username_has_taken(&payload.username).await? {
+    if user.username != payload.username
+        && User::username_has_taken(&state, &payload.username).await?
+    {
         return Err(AppError::BadRequest(
             "An user with this username already exists".to_string(),
         ));
     }
 
-    user.edit(payload).await?;
+    user.edit(&state, payload).await?;
 
     Ok(Json(user))
 }
 
 /// Get user models list
 async fn get_user_models(
+    Extension(state): Extension<AppState>,
     Path(user_id): Path<i32>,
     pagination: Query<Pagination>,
 ) -> Result<Json<ModelPagination>, AppError> {
-    let user = match User::find_by_id(user_id).await {
+    let user = match User::find_by_id(&state, user_id).await {
         Ok(user) => user,
         Err(_) => {
             return Err(AppError::NotFound("User not found".to_string()));
@@ -201,8 +331,175 @@ async fn get_user_models(
     };
 
     let page = pagination.0.page.unwrap_or_default();
-    let results = user.get_models(page).await?;
-    let count = user.count_models().await?;
+    let results = user.get_models(&state, page).await?;
+    let count = user.count_models(&state).await?;
 
     Ok(Json(ModelPagination { count, results }))
 }
+
+/// List the personal access tokens of the user linked to the claims
+async fn list_my_tokens(
+    Extension(state): Extension<AppState>,
+    claims: Claims,
+) -> Result<Json<Vec<ApiToken>>, AppError> {
+    claims.require_session()?;
+
+    let tokens = ApiToken::list(&state, claims.user_id).await?;
+
+    Ok(Json(tokens))
+}
+
+/// Create a new personal access token. The raw token is returned only by this response
+async fn create_my_token(
+    Extension(state): Extension<AppState>,
+    claims: Claims,
+    Json(payload): Json<ApiTokenCreate>,
+) -> Result<JsonCreate<ApiTokenCreated>, AppError> {
+    claims.require_session()?;
+
+    let token = ApiToken::create(&state, claims.user_id, payload).await?;
+
+    Ok(JsonCreate(token))
+}
+
+/// Revoke a personal access token of the user linked to the claims
+async fn delete_my_token(
+    Extension(state): Extension<AppState>,
+    claims: Claims,
+    Path(token_id): Path<i32>,
+) -> Result<StatusCode, AppError> {
+    claims.require_session()?;
+
+    if !ApiToken::delete(&state, claims.user_id, token_id).await? {
+        return Err(AppError::NotFound("Token not found".to_string()));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Find the user `user_id` and the staffer linked to the claims, checking that the staffer can
+/// restrict that user. Only who can promote users can restrict other staffers
+async fn find_ban_target(
+    state: &AppState,
+    claims: &Claims,
+    user_id: i32,
+) -> Result<UserList, AppError> {
+    let staffer = match User::find_by_id(state, claims.user_id).await {
+        Ok(user) => user,
+        Err(_) => return Err(AppError::NotFound("User not found".to_string())),
+    };
+    staffer.require(Permission::UserBan)?;
+
+    let user = match User::find_by_id(state, user_id).await {
+        Ok(user) => user,
+        Err(_) => return Err(AppError::NotFound("User not found".to_string())),
+    };
+
+    if user.id == staffer.id {
+        return Err(AppError::BadRequest("You can not ban yourself".to_string()));
+    }
+    if user.can(Permission::UserBan) {
+        staffer.require(Permission::UserPromote)?;
+    }
+
+    Ok(user)
+}
+
+/// Suspend or ban the user `user_id`. A banned user is logged out of every session
+async fn ban_user(
+    Extension(state): Extension<AppState>,
+    Path(user_id): Path<i32>,
+    claims: Claims,
+    Json(payload): Json<UserBanCreate>,
+) -> Result<JsonCreate<UserBan>, AppError> {
+    claims.require(Scope::Write)?;
+
+    let user = find_ban_target(&state, &claims, user_id).await?;
+
+    let ban = UserBan::create(&state, user.id, payload, claims.user_id).await?;
+    if ban.kind == BanKind::Banned {
+        RefreshToken::revoke_all(&state, user.id).await?;
+    }
+
+    Ok(JsonCreate(ban))
+}
+
+/// Lift the active suspension or ban of the user `user_id`
+async fn unban_user(
+    Extension(state): Extension<AppState>,
+    Path(user_id): Path<i32>,
+    claims: Claims,
+) -> Result<StatusCode, AppError> {
+    claims.require(Scope::Write)?;
+
+    let user = find_ban_target(&state, &claims, user_id).await?;
+
+    if UserBan::lift(&state, user.id, claims.user_id).await? == 0 {
+        return Err(AppError::NotFound("User is not banned".to_string()));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// List the suspensions and the bans of the user `user_id`, also the lifted ones
+async fn list_user_bans(
+    Extension(state): Extension<AppState>,
+    Path(user_id): Path<i32>,
+    claims: Claims,
+) -> Result<Json<Vec<UserBan>>, AppError> {
+    claims.require(Scope::Read)?;
+
+    let staffer = User::find_by_id(&state, claims.user_id).await?;
+    staffer.require(Permission::UserBan)?;
+
+    Ok(Json(UserBan::list(&state, user_id).await?))
+}
+
+/// Get the storage used by the uploads of my models, and my quota
+async fn get_my_storage(
+    Extension(state): Extension<AppState>,
+    claims: Claims,
+) -> Result<Json<StorageUsage>, AppError> {
+    claims.require(Scope::Read)?;
+
+    Ok(Json(StorageUsage::find(&state, claims.user_id).await?))
+}
+
+/// A staffer can see the storage used by an user `id`
+async fn get_user_storage(
+    Extension(state): Extension<AppState>,
+    Path(user_id): Path<i32>,
+    claims: Claims,
+) -> Result<Json<StorageUsage>, AppError> {
+    claims.require(Scope::Read)?;
+
+    let staffer = User::find_by_id(&state, claims.user_id).await?;
+    staffer.require(Permission::UserQuota)?;
+
+    if User::find_by_id(&state, user_id).await.is_err() {
+        return Err(AppError::NotFound("User not found".to_string()));
+    }
+
+    Ok(Json(StorageUsage::find(&state, user_id).await?))
+}
+
+/// A staffer can change the storage quota of an user `id`. A `null` quota restores the default
+async fn edit_user_storage(
+    Extension(state): Extension<AppState>,
+    Path(user_id): Path<i32>,
+    claims: Claims,
+    Json(payload): Json<StorageQuotaEdit>,
+) -> Result<Json<StorageUsage>, AppError> {
+    claims.require(Scope::Write)?;
+
+    let staffer = User::find_by_id(&state, claims.user_id).await?;
+    staffer.require(Permission::UserQuota)?;
+
+    if User::find_by_id(&state, user_id).await.is_err() {
+        return Err(AppError::NotFound("User not found".to_string()));
+    }
+
+    StorageUsage::set_quota(&state, user_id, payload.quota).await?;
+
+    Ok(Json(StorageUsage::find(&state, user_id).await?))
+}
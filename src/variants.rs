@@ -0,0 +1,115 @@
+use crate::errors::AppError;
+use image::{
+    codecs::{jpeg::JpegEncoder, png::PngEncoder, webp::WebPEncoder},
+    imageops::FilterType,
+    DynamicImage, ImageDecoder, ImageReader, Limits,
+};
+use std::io::Cursor;
+
+/// Sizes, in pixels of the longest side, of the variants generated for an image
+pub const VARIANT_SIZES: [u32; 3] = [64, 256, 1024];
+
+/// Extensions of the images which are decoded and resized
+pub const IMAGE_EXTENSIONS: [&str; 5] = ["jpg", "jpeg", "png", "gif", "webp"];
+
+/// Images bigger than this, on any side, are refused
+const MAX_DIMENSION: u32 = 10000;
+
+/// JPEG quality used to encode again the images
+const JPEG_QUALITY: u8 = 85;
+
+/// Encode an image with the format of an extension. PNG is used for the formats which can not be
+/// encoded
+fn encode(image: &DynamicImage, ext: &str) -> Result<Vec<u8>, AppError> {
+    let mut data = Cursor::new(Vec::new());
+
+    let result = match ext {
+        "jpg" | "jpeg" => image
+            .to_rgb8()
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut data, JPEG_QUALITY)),
+        // The WebP encoder is lossless only
+        "webp" => image
+            .to_rgba8()
+            .write_with_encoder(WebPEncoder::new_lossless(&mut data)),
+        _ => image.write_with_encoder(PngEncoder::new(&mut data)),
+    };
+    result.map_err(|error| AppError::BadRequest(format!("Image can not be encoded: {}", error)))?;
+
+    Ok(data.into_inner())
+}
+
+/// Decode an image, applying its EXIF orientation
+fn decode(data: &[u8]) -> Result<DynamicImage, AppError> {
+    let error = |_| AppError::BadRequest("Invalid image".to_string());
+
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+
+    let mut reader = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .map_err(|_| AppError::BadRequest("Invalid image".to_string()))?;
+    reader.limits(limits);
+
+    let mut decoder = reader.into_decoder().map_err(error)?;
+    let orientation = decoder.orientation().map_err(error)?;
+    let mut image = DynamicImage::from_decoder(decoder).map_err(error)?;
+    image.apply_orientation(orientation);
+
+    Ok(image)
+}
+
+/// An image ready to be saved, with its variants
+pub struct ProcessedImage {
+    /// The image encoded again, without metadata. GIFs are kept as they are
+    pub data: Vec<u8>,
+    /// Resized images, with their size and extension
+    pub variants: Vec<(u32, &'static str, Vec<u8>)>,
+}
+
+fn process(data: Vec<u8>, ext: &str) -> Result<ProcessedImage, AppError> {
+    let image = decode(&data)?;
+
+    // GIFs can be animated and carry no EXIF data, so they are kept as they are
+    let data = if ext == "gif" {
+        data
+    } else {
+        encode(&image, ext)?
+    };
+
+    let variant_ext = match ext {
+        "jpg" | "jpeg" => "jpg",
+        _ => "png",
+    };
+    let longest = image.width().max(image.height());
+
+    let mut variants = vec![];
+    for size in VARIANT_SIZES {
+        // Images are never enlarged
+        let resized = if longest > size {
+            image.resize(size, size, FilterType::Lanczos3)
+        } else {
+            image.clone()
+        };
+
+        variants.push((size, variant_ext, encode(&resized, variant_ext)?));
+        variants.push((size, "webp", encode(&resized, "webp")?));
+    }
+
+    Ok(ProcessedImage { data, variants })
+}
+
+/// Validate an uploaded image, making a copy without metadata and with the right orientation,
+/// and generate its resized variants. Returns `None` if the extension is not of an image
+pub async fn process_image(ext: &str, data: Vec<u8>) -> Result<Option<ProcessedImage>, AppError> {
+    if !IMAGE_EXTENSIONS.contains(&ext) {
+        return Ok(None);
+    }
+
+    let ext = ext.to_string();
+    let processed = tokio::task::spawn_blocking(move || process(data, &ext))
+        .await
+        .map_err(|error| error.to_string())??;
+
+    Ok(Some(processed))
+}
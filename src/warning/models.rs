@@ -1,4 +1,4 @@
-use crate::{config::CONFIG, db::get_client, errors::AppError};
+use crate::{errors::AppError, state::AppState};
 use chrono::{Local, NaiveDateTime};
 use serde::{Deserialize, Serialize};
 use sqlx::types::JsonValue;
@@ -95,8 +95,8 @@ impl Warning {
     }
 
     /// Delete a report
-    pub async fn delete(warning_id: i32) -> Result<(), AppError> {
-        let pool = unsafe { get_client() };
+    pub async fn delete(state: &AppState, warning_id: i32) -> Result<(), AppError> {
+        let pool = &state.pool;
 
         sqlx::query(
             r#"
@@ -111,17 +111,21 @@ impl Warning {
     }
 
     /// List all warnings. A staffer can see all the warnings, a user cannot
-    pub async fn list(page: i64, user_id: Option<i32>) -> Result<Vec<WarningUser>, AppError> {
-        let pool = unsafe { get_client() };
+    pub async fn list(
+        state: &AppState,
+        page: i64,
+        user_id: Option<i32>,
+    ) -> Result<Vec<WarningUser>, AppError> {
+        let pool = &state.pool;
         let query = r#"
                     SELECT
                         warnings.*,
-                        json_build_object('id', users.id, 'name', users.name, 'email', users.email, 'username', users.username, 'is_staff', users.is_staff, 'avatar', users.avatar) as user,
+                        json_build_object('id', users.id, 'name', users.name, 'email', users.email, 'username', users.username, 'role', users.role, 'avatar', users.avatar) as user,
                         coalesce(r.data, '{}'::json) as resolved
                     FROM warnings
                     JOIN users ON users.id = warnings.user_id
                     LEFT JOIN (
-                        SELECT id, json_build_object('id', r.id, 'name', r.name, 'email', r.email, 'username', r.username, 'is_staff', r.is_staff, 'avatar', r.avatar) as data
+                        SELECT id, json_build_object('id', r.id, 'name', r.name, 'email', r.email, 'username', r.username, 'role', r.role, 'avatar', r.avatar) as data
                         FROM users r
                     ) r ON r.id = warnings.resolved_by
                     "#;
@@ -133,15 +137,15 @@ impl Warning {
                     query
                 ))
                 .bind(id)
-                .bind(CONFIG.page_limit)
-                .bind(CONFIG.page_limit * page)
+                .bind(state.config.page_limit)
+                .bind(state.config.page_limit * page)
                 .fetch_all(pool)
                 .await?
             }
             None => {
                 sqlx::query_as(&format!(r#"{} ORDER BY id DESC LIMIT $1 OFFSET $2"#, query))
-                    .bind(CONFIG.page_limit)
-                    .bind(CONFIG.page_limit * page)
+                    .bind(state.config.page_limit)
+                    .bind(state.config.page_limit * page)
                     .fetch_all(pool)
                     .await?
             }
@@ -151,19 +155,19 @@ impl Warning {
     }
 
     /// Returns the warning with id = `warning_id`
-    pub async fn find_by_id(warning_id: i32) -> Result<WarningUser, AppError> {
-        let pool = unsafe { get_client() };
+    pub async fn find_by_id(state: &AppState, warning_id: i32) -> Result<WarningUser, AppError> {
+        let pool = &state.pool;
 
         let rec: WarningUser = sqlx::query_as(
             r#"
                 SELECT
                     warnings.*,
-                    json_build_object('id', users.id, 'name', users.name, 'email', users.email, 'username', users.username, 'is_staff', users.is_staff, 'avatar', users.avatar) as user,
+                    json_build_object('id', users.id, 'name', users.name, 'email', users.email, 'username', users.username, 'role', users.role, 'avatar', users.avatar) as user,
                     coalesce(r.data, '{}'::json) as resolved
                 FROM warnings
                 JOIN users ON users.id = warnings.user_id
                 LEFT JOIN (
-                    SELECT id, json_build_object('id', r.id, 'name', r.name, 'email', r.email, 'username', r.username, 'is_staff', r.is_staff, 'avatar', r.avatar) as data
+                    SELECT id, json_build_object('id', r.id, 'name', r.name, 'email', r.email, 'username', r.username, 'role', r.role, 'avatar', r.avatar) as data
                     FROM users r
                 ) r ON r.id = warnings.resolved_by
                 WHERE warnings.id = $1
@@ -176,8 +180,8 @@ impl Warning {
     }
 
     /// Return the number of warnings.
-    pub async fn count(user_id: Option<i32>) -> Result<i64, AppError> {
-        let pool = unsafe { get_client() };
+    pub async fn count(state: &AppState, user_id: Option<i32>) -> Result<i64, AppError> {
+        let pool = &state.pool;
 
         let cursor = match user_id {
             Some(id) => {
@@ -197,9 +201,28 @@ impl Warning {
         Ok(count)
     }
 
+    /// List all the warnings filed by an user, without pagination
+    pub async fn find_by_user(state: &AppState, user_id: i32) -> Result<Vec<Warning>, AppError> {
+        let pool = &state.pool;
+
+        let rows: Vec<Warning> = sqlx::query_as(
+            r#"
+                SELECT id, user_id, model_id, resolved_by,
+                    coalesce(note, '') AS note, coalesce(admin_note, '') AS admin_note,
+                    created, updated
+                FROM warnings WHERE user_id = $1 ORDER BY id
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows)
+    }
+
     /// Create a new upload for model
-    pub async fn create(warning: Warning) -> Result<Warning, AppError> {
-        let pool = unsafe { get_client() };
+    pub async fn create(state: &AppState, warning: Warning) -> Result<Warning, AppError> {
+        let pool = &state.pool;
 
         let rec: Warning = sqlx::query_as(
             r#"
@@ -223,18 +246,22 @@ impl Warning {
 
     /// Filter warnings. Pass a `WarningFilter` argument. You can filter only by model_id or (not
     /// both) resolved by
-    pub async fn filter(page: i64, args: WarningFilter) -> Result<Vec<WarningUser>, AppError> {
-        let pool = unsafe { get_client() };
+    pub async fn filter(
+        state: &AppState,
+        page: i64,
+        args: WarningFilter,
+    ) -> Result<Vec<WarningUser>, AppError> {
+        let pool = &state.pool;
 
         let mut query = r#"
                     SELECT
                         warnings.*,
-                        json_build_object('id', users.id, 'name', users.name, 'email', users.email, 'username', users.username, 'is_staff', users.is_staff, 'avatar', users.avatar) as user,
+                        json_build_object('id', users.id, 'name', users.name, 'email', users.email, 'username', users.username, 'role', users.role, 'avatar', users.avatar) as user,
                         coalesce(r.data, '{}'::json) as resolved
                     FROM warnings
                     JOIN users ON users.id = warnings.user_id
                     LEFT JOIN (
-                        SELECT id, json_build_object('id', r.id, 'name', r.name, 'email', r.email, 'username', r.username, 'is_staff', r.is_staff, 'avatar', r.avatar) as data
+                        SELECT id, json_build_object('id', r.id, 'name', r.name, 'email', r.email, 'username', r.username, 'role', r.role, 'avatar', r.avatar) as data
                         FROM users r
                     ) r ON r.id = warnings.resolved_by
                     "#.to_string();
@@ -254,18 +281,18 @@ impl Warning {
 
         let rows: Vec<WarningUser> = match args.user_id {
             Some(id) => {
-                let q = if args.model_id.is_some() {
+                let q = if let Some(model_id) = args.model_id {
                     query = format!(
                         r#"{} AND user_id = $2 ORDER BY id DESC LIMIT $3 OFFSET $4"#,
                         query
                     );
-                    sqlx::query_as(&query).bind(args.model_id.unwrap())
-                } else if args.resolved_by.is_some() {
+                    sqlx::query_as(&query).bind(model_id)
+                } else if let Some(resolved_by) = args.resolved_by {
                     query = format!(
                         r#"{} AND user_id = $2 ORDER BY id DESC LIMIT $3 OFFSET $4"#,
                         query
                     );
-                    sqlx::query_as(&query).bind(args.resolved_by.unwrap())
+                    sqlx::query_as(&query).bind(resolved_by)
                 } else {
                     query = format!(
                         r#"{} AND user_id = $1 ORDER BY id DESC LIMIT $2 OFFSET $3"#,
@@ -275,25 +302,25 @@ impl Warning {
                 };
 
                 q.bind(id)
-                    .bind(CONFIG.page_limit)
-                    .bind(CONFIG.page_limit * page)
+                    .bind(state.config.page_limit)
+                    .bind(state.config.page_limit * page)
                     .fetch_all(pool)
                     .await?
             }
             None => {
-                let q = if args.model_id.is_some() {
+                let q = if let Some(model_id) = args.model_id {
                     query = format!(r#"{} ORDER BY id DESC LIMIT $2 OFFSET $3"#, query);
-                    sqlx::query_as(&query).bind(args.model_id.unwrap())
-                } else if args.resolved_by.is_some() {
+                    sqlx::query_as(&query).bind(model_id)
+                } else if let Some(resolved_by) = args.resolved_by {
                     query = format!(r#"{} ORDER BY id DESC LIMIT $2 OFFSET $3"#, query);
-                    sqlx::query_as(&query).bind(args.resolved_by.unwrap())
+                    sqlx::query_as(&query).bind(resolved_by)
                 } else {
                     query = format!(r#"{} ORDER BY id DESC LIMIT $1 OFFSET $2"#, query);
                     sqlx::query_as(&query)
                 };
 
-                q.bind(CONFIG.page_limit)
-                    .bind(CONFIG.page_limit * page)
+                q.bind(state.config.page_limit)
+                    .bind(state.config.page_limit * page)
                     .fetch_all(pool)
                     .await?
             }
@@ -303,8 +330,8 @@ impl Warning {
     }
 
     /// Return the number of filtered warnings.
-    pub async fn count_by_model_id(args: WarningFilter) -> Result<i64, AppError> {
-        let pool = unsafe { get_client() };
+    pub async fn count_by_model_id(state: &AppState, args: WarningFilter) -> Result<i64, AppError> {
+        let pool = &state.pool;
 
         let mut query = r#"
             SELECT COUNT(id) as count FROM warnings
@@ -326,12 +353,12 @@ impl Warning {
 
         let cursor = match args.user_id {
             Some(id) => {
-                let q = if args.model_id.is_some() {
+                let q = if let Some(model_id) = args.model_id {
                     query = format!(r#"{} AND user_id = $2"#, query);
-                    sqlx::query(&query).bind(args.model_id.unwrap())
-                } else if args.resolved_by.is_some() {
+                    sqlx::query(&query).bind(model_id)
+                } else if let Some(resolved_by) = args.resolved_by {
                     query = format!(r#"{} AND user_id = $2"#, query);
-                    sqlx::query(&query).bind(args.resolved_by.unwrap())
+                    sqlx::query(&query).bind(resolved_by)
                 } else {
                     query = format!(r#"{} AND user_id = $1"#, query);
                     sqlx::query(&query)
@@ -340,10 +367,10 @@ impl Warning {
                 q.bind(id).fetch_one(pool).await?
             }
             None => {
-                let q = if args.model_id.is_some() {
-                    sqlx::query(&query).bind(args.model_id.unwrap())
-                } else if args.resolved_by.is_some() {
-                    sqlx::query(&query).bind(args.resolved_by.unwrap())
+                let q = if let Some(model_id) = args.model_id {
+                    sqlx::query(&query).bind(model_id)
+                } else if let Some(resolved_by) = args.resolved_by {
+                    sqlx::query(&query).bind(resolved_by)
                 } else {
                     sqlx::query(&query)
                 };
@@ -359,10 +386,11 @@ impl Warning {
     /// Edit a warning. When `resolver` is None, it means a warning is no more resolved
     pub async fn edit(
         &mut self,
+        state: &AppState,
         resolver: Option<i32>,
         payload: WarningEdit,
     ) -> Result<(), AppError> {
-        let pool = unsafe { get_client() };
+        let pool = &state.pool;
 
         let now = Local::now().naive_utc();
 
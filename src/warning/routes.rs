@@ -1,14 +1,18 @@
 use crate::{
-    auth::models::Claims,
+    auth::{
+        models::{Claims, Scope},
+        permissions::Permission,
+    },
     errors::AppError,
     model::models::Model,
     pagination::{Pagination, WarningPagination},
     routes::JsonCreate,
+    state::AppState,
     user::models::User,
     warning::models::*,
 };
 use axum::{
-    extract::{Path, Query},
+    extract::{Extension, Path, Query},
     http::StatusCode,
     routing::{get, post},
     Json, Router,
@@ -25,23 +29,26 @@ pub fn create_route() -> Router {
         .route("/filter", post(filter_warnings))
 }
 
-/// List warnings. A staffer can see everything.
+/// List warnings. Who can read any warning sees everything.
 async fn list_warnings(
+    Extension(state): Extension<AppState>,
     pagination: Query<Pagination>,
     claims: Claims,
 ) -> Result<Json<WarningPagination>, AppError> {
+    claims.require(Scope::Read)?;
+
     let page = pagination.0.page.unwrap_or_default();
 
-    let user = User::find_by_id(claims.user_id).await?;
+    let user = User::find_by_id(&state, claims.user_id).await?;
 
-    let (results, count) = match user.is_staff.unwrap() {
+    let (results, count) = match user.can(Permission::WarningReadAny) {
         true => (
-            Warning::list(page, None).await?,
-            Warning::count(None).await?,
+            Warning::list(&state, page, None).await?,
+            Warning::count(&state, None).await?,
         ),
         false => (
-            Warning::list(page, Some(user.id)).await?,
-            Warning::count(Some(user.id)).await?,
+            Warning::list(&state, page, Some(user.id)).await?,
+            Warning::count(&state, Some(user.id)).await?,
         ),
     };
 
@@ -50,16 +57,17 @@ async fn list_warnings(
 
 /// Get a warning with id = `model_id`
 async fn get_warning(
+    Extension(state): Extension<AppState>,
     Path(warning_id): Path<i32>,
     claims: Claims,
 ) -> Result<Json<Warning>, AppError> {
-    let user = User::find_by_id(claims.user_id).await?;
+    claims.require(Scope::Read)?;
 
-    if !(user.is_staff.unwrap()) {
-        return Err(AppError::Unauthorized);
-    }
+    let user = User::find_by_id(&state, claims.user_id).await?;
+
+    user.require(Permission::WarningReadAny)?;
 
-    match Warning::find_by_id(warning_id).await {
+    match Warning::find_by_id(&state, warning_id).await {
         Ok(warning) => Ok(Json(warning.into())),
         Err(_) => Err(AppError::NotFound("Warning not found".to_string())),
     }
@@ -67,61 +75,66 @@ async fn get_warning(
 
 /// Create a warning. Checks Authorization token
 async fn create_warning(
+    Extension(state): Extension<AppState>,
     Json(payload): Json<WarningCreate>,
     claims: Claims,
 ) -> Result<JsonCreate<Warning>, AppError> {
-    let model = match Model::find_by_id(payload.model_id).await {
+    claims.require(Scope::Write)?;
+
+    let model = match Model::find_by_id(&state, payload.model_id).await {
         Ok(model) => model,
         Err(_) => return Err(AppError::NotFound("Report not found".to_string())),
     };
 
     let warning = Warning::new(claims.user_id, model.id, payload.note);
 
-    let warning_new = Warning::create(warning).await?;
+    let warning_new = Warning::create(&state, warning).await?;
 
     Ok(JsonCreate(warning_new))
 }
 
-/// Staffers can edit a warning
+/// Who can resolve warnings can edit a warning
 async fn edit_warning(
+    Extension(state): Extension<AppState>,
     Json(payload): Json<WarningEdit>,
     claims: Claims,
     Path(warning_id): Path<i32>,
 ) -> Result<Json<Warning>, AppError> {
-    let mut warning: Warning = match Warning::find_by_id(warning_id).await {
+    claims.require(Scope::Write)?;
+
+    let mut warning: Warning = match Warning::find_by_id(&state, warning_id).await {
         Ok(warning) => warning.into(),
         Err(_) => {
             return Err(AppError::NotFound("Report not found".to_string()));
         }
     };
 
-    let user = User::find_by_id(claims.user_id).await?;
+    let user = User::find_by_id(&state, claims.user_id).await?;
 
-    if !(user.is_staff.unwrap()) {
-        return Err(AppError::Unauthorized);
-    }
+    user.require(Permission::WarningResolve)?;
 
     if payload.resolved_by.is_none() || payload.resolved_by.unwrap() > 0 {
-        warning.edit(Some(user.id), payload).await?;
+        warning.edit(&state, Some(user.id), payload).await?;
     } else {
-        warning.edit(None, payload).await?;
+        warning.edit(&state, None, payload).await?;
     }
 
     Ok(Json(warning))
 }
 
-/// A staffer can delete a warning
+/// Who can delete warnings can delete a warning
 async fn delete_warning(
+    Extension(state): Extension<AppState>,
     claims: Claims,
     Path(warning_id): Path<i32>,
 ) -> Result<StatusCode, AppError> {
-    let user = User::find_by_id(claims.user_id).await?;
+    claims.require(Scope::Write)?;
 
-    if !user.is_staff.unwrap() {
-        return Err(AppError::Unauthorized);
-    }
+    let user = User::find_by_id(&state, claims.user_id).await?;
+
+    user.require(Permission::WarningDelete)?;
 
-    if Warning::delete(warning_id).await.is_ok() {
+    if Warning::delete(&state, warning_id).await.is_ok() {
         Ok(StatusCode::NO_CONTENT)
     } else {
         Ok(StatusCode::BAD_REQUEST)
@@ -130,17 +143,21 @@ async fn delete_warning(
 
 /// Apply a filter to warnings list
 async fn filter_warnings(
+    Extension(state): Extension<AppState>,
     Json(payload): Json<WarningFilterPayload>,
     pagination: Query<Pagination>,
     claims: Claims,
 ) -> Result<Json<WarningPagination>, AppError> {
+    claims.require(Scope::Read)?;
+
     let page = pagination.0.page.unwrap_or_default();
 
-    let user = User::find_by_id(claims.user_id).await?;
+    let user = User::find_by_id(&state, claims.user_id).await?;
 
-    let (results, count) = match user.is_staff.unwrap() {
+    let (results, count) = match user.can(Permission::WarningReadAny) {
         true => (
             Warning::filter(
+                &state,
                 page,
                 WarningFilter {
                     model_id: payload.model_id,
@@ -149,15 +166,19 @@ async fn filter_warnings(
                 },
             )
             .await?,
-            Warning::count_by_model_id(WarningFilter {
-                model_id: payload.model_id,
-                resolved_by: payload.resolved_by,
-                user_id: None,
-            })
+            Warning::count_by_model_id(
+                &state,
+                WarningFilter {
+                    model_id: payload.model_id,
+                    resolved_by: payload.resolved_by,
+                    user_id: None,
+                },
+            )
             .await?,
         ),
         false => (
             Warning::filter(
+                &state,
                 page,
                 WarningFilter {
                     model_id: payload.model_id,
@@ -166,11 +187,14 @@ async fn filter_warnings(
                 },
             )
             .await?,
-            Warning::count_by_model_id(WarningFilter {
-                model_id: payload.model_id,
-                resolved_by: payload.resolved_by,
-                user_id: Some(user.id),
-            })
+            Warning::count_by_model_id(
+                &state,
+                WarningFilter {
+                    model_id: payload.model_id,
+                    resolved_by: payload.resolved_by,
+                    user_id: Some(user.id),
+                },
+            )
             .await?,
         ),
     };